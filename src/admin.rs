@@ -0,0 +1,473 @@
+use crate::config::constant_time_eq;
+use crate::{AppState, Game, GameId, GameInfo, GameStatus, Occupancy, SocketId};
+use axum::extract::ws::{Message, WebSocket};
+use axum::extract::{Path, State, WebSocketUpgrade};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::UNIX_EPOCH;
+use tokio::select;
+use tokio::sync::broadcast;
+use tracing::{error, warn};
+
+use crate::events::{LobbyEvent, RecordedEvent};
+
+// Admin routes without authentication, for serving behind client certificate
+// verification
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/events", get(events))
+        .route("/admin/socket/:socket_id", get(socket_details))
+        .route("/admin/games", get(games))
+        .route("/admin/games/:game_id/events", get(game_events))
+        .route("/admin/games/:game_id/close", post(close_game))
+        .route("/admin/stats", get(stats))
+}
+
+// Admin routes requiring the admin token as a bearer token
+pub fn token_routes(app_state: &AppState) -> Router<AppState> {
+    routes().route_layer(middleware::from_fn_with_state(
+        app_state.clone(),
+        require_token,
+    ))
+}
+
+async fn require_token<B>(
+    State(app_state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if is_authorized(&app_state, request.headers()) {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+fn is_authorized(app_state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(admin_token) = &app_state.config.admin_token else {
+        return false;
+    };
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token, admin_token))
+}
+
+async fn events(ws: WebSocketUpgrade, State(app_state): State<AppState>) -> Response {
+    let rx = app_state.events.subscribe();
+    ws.on_upgrade(|socket| stream_events(socket, rx))
+}
+
+async fn stream_events(mut socket: WebSocket, mut rx: broadcast::Receiver<LobbyEvent>) {
+    loop {
+        select! {
+            event = rx.recv() => match event {
+                Ok(event) => {
+                    let data = match serde_json::to_string(&event) {
+                        Ok(data) => data,
+                        Err(err) => {
+                            error!(%err, "Failed to serialize admin event");
+                            continue;
+                        }
+                    };
+                    if socket.send(data.into()).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Admin event subscriber lagged");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum SocketRole {
+    Host,
+    Client,
+    Pending,
+    Spectator,
+    None,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SocketDetails {
+    socket_id: SocketId,
+    role: SocketRole,
+    game_id: Option<GameId>,
+    // Of the socket's game
+    occupancy: Option<Occupancy>,
+    remote_addr: SocketAddr,
+    // Seconds since the Unix epoch
+    connected_at: u64,
+    // Outgoing messages waiting to be written to the socket
+    queued_messages: usize,
+}
+
+async fn socket_details(
+    Path(socket_id): Path<SocketId>,
+    State(app_state): State<AppState>,
+) -> Result<Json<SocketDetails>, StatusCode> {
+    let (remote_addr, connected_at, queued_messages) = app_state
+        .lock_sockets(|sockets| {
+            sockets.get_entry(&socket_id).map(|entry| {
+                (
+                    entry.remote_addr,
+                    entry.connected_at,
+                    entry.tx.max_capacity() - entry.tx.capacity(),
+                )
+            })
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let (role, game) = app_state.read_games(|games| {
+        let details = |game: &Game| Some((game.game_id.clone(), game.occupancy()));
+        if let Some(game) = games.get_game_by_host(&socket_id) {
+            (SocketRole::Host, details(game))
+        } else if let Some(game) = games.get_game_by_client(&socket_id) {
+            if game.clients.contains(&socket_id) {
+                (SocketRole::Client, details(game))
+            } else if game.spectators.contains(&socket_id) {
+                (SocketRole::Spectator, details(game))
+            } else {
+                (SocketRole::Pending, details(game))
+            }
+        } else {
+            (SocketRole::None, None)
+        }
+    });
+    let (game_id, occupancy) = game.unzip();
+    Ok(Json(SocketDetails {
+        socket_id,
+        role,
+        game_id,
+        occupancy,
+        remote_addr,
+        connected_at: connected_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        queued_messages,
+    }))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GameDetails {
+    game_id: GameId,
+    host: SocketId,
+    clients: Vec<SocketId>,
+    pending: Vec<SocketId>,
+    spectators: Vec<SocketId>,
+    occupancy: Occupancy,
+    status: GameStatus,
+    // Including the password
+    game_info: GameInfo,
+}
+
+// Every game in creation order, private ones included
+async fn games(State(app_state): State<AppState>) -> Json<Vec<GameDetails>> {
+    Json(app_state.read_games(|games| {
+        games
+            .iter()
+            .map(|game| GameDetails {
+                game_id: game.game_id.clone(),
+                host: game.host.clone(),
+                clients: game.clients.iter().cloned().collect(),
+                pending: game.pending.iter().cloned().collect(),
+                spectators: game.spectators.iter().cloned().collect(),
+                occupancy: game.occupancy(),
+                status: game.status,
+                game_info: game.game_info.clone(),
+            })
+            .collect()
+    }))
+}
+
+async fn game_events(
+    Path(game_id): Path<GameId>,
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<RecordedEvent>>, StatusCode> {
+    app_state
+        .read_games(|games| games.history(&game_id))
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// Members are sent a `gameClosed`, their connections stay open
+async fn close_game(Path(game_id): Path<GameId>, State(app_state): State<AppState>) -> StatusCode {
+    if app_state.close_game(&game_id, "closedByAdmin") {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Stats {
+    games: usize,
+    sockets: usize,
+    // Processed messages by type since startup
+    messages: BTreeMap<&'static str, u64>,
+    joins_succeeded: u64,
+    joins_failed: u64,
+}
+
+// The counters behind /metrics as a JSON snapshot, for reading by hand
+async fn stats(State(app_state): State<AppState>) -> Json<Stats> {
+    let metrics = &app_state.metrics;
+    Json(Stats {
+        games: app_state.read_games(|games| games.len()),
+        sockets: app_state.lock_sockets(|sockets| sockets.len()),
+        messages: metrics.message_counts().collect(),
+        joins_succeeded: metrics.joins_succeeded(),
+        joins_failed: metrics.joins_failed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::{process, register};
+    use serde_json::{json, Value};
+
+    // Creates a game hosted by `host`
+    fn create_game(app_state: &AppState, host: &SocketId, fields: Value) -> GameId {
+        let mut message = json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4});
+        message
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        process(app_state, host, message);
+        app_state.read_games(|games| games.get_game_by_host(host).unwrap().game_id.clone())
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = format!("Bearer {}", token).parse().unwrap();
+        headers.insert(header::AUTHORIZATION, value);
+        headers
+    }
+
+    #[test]
+    fn only_the_admin_token_is_authorized() {
+        let mut config = Config::from_env();
+        config.admin_token = Some("secret".to_string());
+        let app_state = AppState::new(config);
+        assert!(is_authorized(&app_state, &bearer("secret")));
+        assert!(!is_authorized(&app_state, &bearer("secreT")));
+        assert!(!is_authorized(&app_state, &bearer("")));
+        assert!(!is_authorized(&app_state, &HeaderMap::new()));
+    }
+
+    #[test]
+    fn nothing_is_authorized_without_an_admin_token() {
+        let mut config = Config::from_env();
+        config.admin_token = None;
+        let app_state = AppState::new(config);
+        assert!(!is_authorized(&app_state, &bearer("")));
+    }
+
+    #[tokio::test]
+    async fn sockets_are_looked_up_with_their_game() {
+        let app_state = AppState::new(Config::from_env());
+        let host = register(&app_state).0;
+        let client = register(&app_state).0;
+        let game_id = create_game(&app_state, &host, json!({}));
+        process(
+            &app_state,
+            &client,
+            json!({"type": "joinGame", "gameId": game_id}),
+        );
+
+        let details = |socket_id: &SocketId| {
+            socket_details(Path(socket_id.clone()), State(app_state.clone()))
+        };
+        let Json(host_details) = details(&host).await.unwrap();
+        let host_details = serde_json::to_value(host_details).unwrap();
+        assert_eq!(host_details["role"], "host");
+        assert_eq!(host_details["gameId"], json!(game_id));
+        let Json(client_details) = details(&client).await.unwrap();
+        let client_details = serde_json::to_value(client_details).unwrap();
+        assert_eq!(client_details["role"], "pending");
+        assert_eq!(client_details["gameId"], json!(game_id));
+        assert_eq!(client_details["occupancy"]["pending"], 1);
+        let unknown = SocketId("unknown".to_string());
+        assert_eq!(details(&unknown).await.err(), Some(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn game_events_are_returned_in_order() {
+        let app_state = AppState::new(Config::from_env());
+        let host = register(&app_state).0;
+        let client = register(&app_state).0;
+        let leaving = register(&app_state).0;
+        let game_id = create_game(&app_state, &host, json!({"joinPolicy": "open"}));
+        for joiner in [&client, &leaving] {
+            process(
+                &app_state,
+                joiner,
+                json!({"type": "joinGame", "gameId": game_id}),
+            );
+        }
+        process(
+            &app_state,
+            &host,
+            json!({"type": "rejectJoin", "gameId": game_id, "clientId": client, "reason": "full"}),
+        );
+        app_state.write_games(|games| {
+            let messages = crate::process_disconnect(&leaving, games);
+            games.record_events(&messages.events);
+        });
+
+        let Json(events) = game_events(Path(game_id.clone()), State(app_state.clone()))
+            .await
+            .unwrap();
+        let events = serde_json::to_value(events).unwrap();
+        let types: Vec<&Value> = events
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| &event["type"])
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "gameCreated",
+                "joinAccepted",
+                "joinAccepted",
+                "joinRejected",
+                "clientLeft"
+            ]
+        );
+        assert_eq!(events[1]["clientId"], json!(client));
+        let unknown = GameId("unknown".to_string());
+        let missing = game_events(Path(unknown), State(app_state)).await;
+        assert_eq!(missing.err(), Some(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn game_event_history_is_bounded() {
+        let app_state = AppState::new(Config::from_env());
+        let host = register(&app_state).0;
+        let client = register(&app_state).0;
+        let game_id = create_game(&app_state, &host, json!({}));
+        for _ in 0..crate::GAME_HISTORY_LENGTH {
+            process(
+                &app_state,
+                &client,
+                json!({"type": "joinGame", "gameId": game_id}),
+            );
+            process(
+                &app_state,
+                &host,
+                json!({"type": "rejectJoin", "gameId": game_id, "clientId": client, "reason": "no"}),
+            );
+        }
+        let Json(events) = game_events(Path(game_id), State(app_state)).await.unwrap();
+        assert_eq!(events.len(), crate::GAME_HISTORY_LENGTH);
+        assert!(matches!(events[0].event, LobbyEvent::JoinRequested { .. }));
+    }
+
+    #[tokio::test]
+    async fn stats_count_messages_and_joins() {
+        let app_state = AppState::new(Config::from_env());
+        let host = register(&app_state).0;
+        let client = register(&app_state).0;
+        let game_id = create_game(&app_state, &host, json!({"joinPolicy": "open"}));
+        process(
+            &app_state,
+            &client,
+            json!({"type": "joinGame", "gameId": game_id}),
+        );
+        process(
+            &app_state,
+            &client,
+            json!({"type": "joinGame", "gameId": "unknown"}),
+        );
+
+        let Json(stats) = stats(State(app_state)).await;
+        let stats = serde_json::to_value(stats).unwrap();
+        assert_eq!(stats["games"], 1);
+        assert_eq!(stats["sockets"], 2);
+        assert_eq!(stats["messages"]["createGame"], 1);
+        assert_eq!(stats["messages"]["joinGame"], 2);
+        assert_eq!(stats["messages"]["listGames"], 0);
+        assert_eq!(stats["joinsSucceeded"], 1);
+        assert_eq!(stats["joinsFailed"], 1);
+    }
+
+    #[tokio::test]
+    async fn games_are_listed_with_their_members() {
+        let app_state = AppState::new(Config::from_env());
+        let host = register(&app_state).0;
+        let client = register(&app_state).0;
+        let game_id = create_game(
+            &app_state,
+            &host,
+            json!({"joinPolicy": "open", "private": true, "password": "hunter2"}),
+        );
+        process(
+            &app_state,
+            &client,
+            json!({"type": "joinGame", "gameId": game_id, "password": "hunter2"}),
+        );
+
+        let Json(games) = games(State(app_state)).await;
+        let games = serde_json::to_value(games).unwrap();
+        assert_eq!(games.as_array().unwrap().len(), 1);
+        let game = &games[0];
+        assert_eq!(game["gameId"], json!(game_id));
+        assert_eq!(game["host"], json!(host));
+        assert_eq!(game["clients"], json!([client]));
+        assert_eq!(game["occupancy"]["players"], 2);
+        assert_eq!(game["gameInfo"]["password"], "hunter2");
+    }
+
+    #[tokio::test]
+    async fn closed_games_tell_every_member() {
+        let app_state = AppState::new(Config::from_env());
+        let mut members: Vec<_> = (0..3).map(|_| register(&app_state)).collect();
+        let game_id = create_game(&app_state, &members[0].0, json!({"joinPolicy": "open"}));
+        process(
+            &app_state,
+            &members[1].0,
+            json!({"type": "joinGame", "gameId": game_id}),
+        );
+        process(
+            &app_state,
+            &members[2].0,
+            json!({"type": "joinAsSpectator", "gameId": game_id}),
+        );
+
+        let closed = close_game(Path(game_id.clone()), State(app_state.clone())).await;
+        assert_eq!(closed, StatusCode::NO_CONTENT);
+        assert!(app_state.read_games(|games| games.get_game(&game_id).is_none()));
+        for (socket_id, rx) in &mut members {
+            let messages: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+            let closed = serde_json::to_value(messages.last().unwrap()).unwrap();
+            assert_eq!(closed["type"], "gameClosed");
+            assert_eq!(closed["reason"], "closedByAdmin");
+            // The connection itself stays open
+            assert!(app_state.lock_sockets(|sockets| sockets.get(socket_id).is_some()));
+        }
+        let missing = close_game(Path(game_id), State(app_state)).await;
+        assert_eq!(missing, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,563 @@
+use ipnet::IpNet;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub struct Config {
+    // Address the WebSocket server listens on
+    pub bind: SocketAddr,
+    // Serves WSS directly instead of plain HTTP when set
+    pub tls: Option<TlsConfig>,
+    // Addresses refused WebSocket connections
+    pub banned_networks: Vec<IpNet>,
+    // Tokens clients must present to connect, anyone may connect when unset
+    pub connect_tokens: Option<Vec<String>>,
+    // Origins allowed to open WebSockets, any origin when unset
+    pub allowed_origins: Option<Vec<String>>,
+    // Bearer token required by the admin endpoints, which are disabled when unset
+    pub admin_token: Option<String>,
+    // Serves the admin endpoints on a separate listener requiring client
+    // certificates instead of the bearer token
+    pub admin_tls: Option<AdminTlsConfig>,
+    // Operator-provided CIDR -> region table used to infer a region hint for
+    // games created without an explicit region
+    pub region_map: Vec<(IpNet, String)>,
+    // Longer messages are rejected without parsing them
+    pub max_message_bytes: usize,
+    // Limits for the opaque description/candidate values relayed in signaling
+    pub max_signaling_depth: usize,
+    pub max_signaling_elements: usize,
+    // Maximum number of concurrent WebSocket connections, unlimited when unset
+    pub max_connections: Option<usize>,
+    // Connections are closed once they've received more payload bytes than
+    // this in total, unlimited when unset
+    pub max_connection_bytes: Option<u64>,
+    // Messages per second each socket may send, unlimited when unset
+    pub rate_limit: Option<u32>,
+    // Maximum number of concurrent games, unlimited when unset
+    pub max_games: Option<usize>,
+    // Accepted `max_players` values for games
+    pub max_players_range: RangeInclusive<u32>,
+    // Evict the least recently active game without clients instead of
+    // rejecting new games when at `max_games`
+    pub evict_idle_games: bool,
+    pub quick_match_weights: QuickMatchWeights,
+    // Share of accepted clients that must be ready for the host to start
+    pub start_quorum_percent: u32,
+    // Countdown sent with GameStarting
+    pub start_countdown_secs: u32,
+    // Leave started games out of the game list
+    pub hide_started_games: bool,
+    // Sockets that aren't in a game are closed after this long without a
+    // valid message
+    pub idle_timeout: Duration,
+    // Games whose host and members send nothing for this long are closed,
+    // kept until their host leaves when unset
+    pub stale_game_timeout: Option<Duration>,
+    // How long to wait for sockets to close on shutdown
+    pub shutdown_grace: Duration,
+    // How long a disconnected socket keeps its games for a new connection to
+    // resume, sockets are cleaned up right away when unset
+    pub resume_grace: Option<Duration>,
+    // Games are saved here and restored on startup when set. Requires
+    // `resume_grace` for hosts to reclaim them.
+    pub state_file: Option<PathBuf>,
+    pub state_save_interval: Duration,
+    // TURN servers handed to hosts and joining clients
+    pub turn: Option<TurnConfig>,
+    // How often sockets are pinged to detect dead connections
+    pub heartbeat_interval: Duration,
+    // How long clients have to answer a host's PingClients
+    pub liveness_timeout: Duration,
+    // Outgoing messages queued for each socket before senders have to wait
+    pub socket_buffer: usize,
+    pub socket_id_format: IdFormat,
+    pub game_id_format: IdFormat,
+}
+
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    fn from_env() -> Option<Self> {
+        let cert = std::env::var("TLS_CERT").ok();
+        let key = std::env::var("TLS_KEY").ok();
+        match (cert, key) {
+            (None, None) => None,
+            (Some(cert), Some(key)) => Some(Self {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+            _ => panic!("TLS_CERT and TLS_KEY must be set together"),
+        }
+    }
+}
+
+pub struct TurnConfig {
+    pub urls: Vec<String>,
+    // Shared with the TURN server, which verifies the credentials with it
+    pub secret: String,
+    pub credential_ttl: Duration,
+}
+
+impl TurnConfig {
+    fn from_env() -> Option<Self> {
+        let urls = std::env::var("TURN_URLS").ok();
+        let secret = std::env::var("TURN_SECRET").ok();
+        match (urls, secret) {
+            (None, None) => None,
+            (Some(urls), Some(secret)) => Some(Self {
+                urls: urls
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                secret,
+                credential_ttl: parse_duration_secs("TURN_CREDENTIAL_TTL_SECS", 24 * 60 * 60),
+            }),
+            _ => panic!("TURN_URLS and TURN_SECRET must be set together"),
+        }
+    }
+}
+
+pub struct AdminTlsConfig {
+    pub bind: SocketAddr,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // CA that client certificates must be signed by
+    pub client_ca: PathBuf,
+}
+
+impl AdminTlsConfig {
+    fn from_env() -> Option<Self> {
+        let cert = std::env::var("ADMIN_TLS_CERT").ok();
+        let key = std::env::var("ADMIN_TLS_KEY").ok();
+        let client_ca = std::env::var("ADMIN_TLS_CLIENT_CA").ok();
+        match (cert, key, client_ca) {
+            (None, None, None) => None,
+            (Some(cert), Some(key), Some(client_ca)) => Some(Self {
+                bind: parse_env(
+                    "ADMIN_TLS_BIND_ADDR",
+                    SocketAddr::from(([0, 0, 0, 0], 8443)),
+                ),
+                cert: cert.into(),
+                key: key.into(),
+                client_ca: client_ca.into(),
+            }),
+            _ => {
+                panic!("ADMIN_TLS_CERT, ADMIN_TLS_KEY and ADMIN_TLS_CLIENT_CA must be set together")
+            }
+        }
+    }
+}
+
+// How QuickMatch scores the games a client could join
+#[derive(Clone, Copy)]
+pub struct QuickMatchWeights {
+    // Weight of the share of players out of `maxPlayers`
+    pub fill: f64,
+    // Weight of a score halving over each minute without activity in the game
+    pub freshness: f64,
+}
+
+// Shape of randomly generated ids
+pub struct IdFormat {
+    pub length: usize,
+    // Alphanumeric characters when unset
+    pub alphabet: Option<Vec<char>>,
+}
+
+const MIN_ID_LENGTH: usize = 4;
+const MAX_ID_LENGTH: usize = 64;
+// Fewer possible ids could all be taken by clients choosing their own
+const MIN_ID_SPACE: u128 = 1 << 32;
+// Characters of the default alphanumeric alphabet
+const ALPHANUMERIC_LENGTH: usize = 62;
+
+impl Default for IdFormat {
+    fn default() -> Self {
+        Self {
+            length: 16,
+            alphabet: None,
+        }
+    }
+}
+
+impl IdFormat {
+    // Reads `<prefix>_LENGTH` and `<prefix>_ALPHABET`
+    fn from_env(prefix: &str) -> Self {
+        let length_key = format!("{}_LENGTH", prefix);
+        let length = parse_env(&length_key, IdFormat::default().length);
+        if !(MIN_ID_LENGTH..=MAX_ID_LENGTH).contains(&length) {
+            panic!(
+                "{} must be between {} and {}",
+                length_key, MIN_ID_LENGTH, MAX_ID_LENGTH
+            );
+        }
+        let alphabet_key = format!("{}_ALPHABET", prefix);
+        let alphabet = std::env::var(&alphabet_key).ok().map(|value| {
+            let mut alphabet: Vec<char> = value.chars().collect();
+            alphabet.sort_unstable();
+            alphabet.dedup();
+            if alphabet.len() < 2 {
+                panic!(
+                    "{} must contain at least two distinct characters",
+                    alphabet_key
+                );
+            }
+            alphabet
+        });
+        let alphabet_length = alphabet.as_ref().map_or(ALPHANUMERIC_LENGTH, Vec::len);
+        if !id_space_is_safe(alphabet_length, length) {
+            panic!(
+                "{} and {} allow fewer than {} ids",
+                length_key, alphabet_key, MIN_ID_SPACE
+            );
+        }
+        Self { length, alphabet }
+    }
+}
+
+// Whether ids of `length` characters from an alphabet of `alphabet_length`
+// have at least `MIN_ID_SPACE` possible values
+fn id_space_is_safe(alphabet_length: usize, length: usize) -> bool {
+    (alphabet_length as u128)
+        .checked_pow(length as u32)
+        .is_none_or(|space| space >= MIN_ID_SPACE)
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let config = Self {
+            bind: bind_addr(
+                std::env::var("PORT").ok().as_deref(),
+                std::env::var("LOBBY_BIND_ADDR").ok().as_deref(),
+            ),
+            tls: TlsConfig::from_env(),
+            banned_networks: std::env::var("BAN_LIST_FILE")
+                .map(|path| load_ban_list(Path::new(&path)))
+                .unwrap_or_default(),
+            connect_tokens: std::env::var("CONNECT_TOKENS").ok().map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|token| !token.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }),
+            allowed_origins: std::env::var("ALLOWED_ORIGINS")
+                .ok()
+                .and_then(|value| parse_allowed_origins(&value)),
+            admin_token: std::env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            admin_tls: AdminTlsConfig::from_env(),
+            region_map: std::env::var("REGION_MAP")
+                .map(|value| parse_region_map(&value))
+                .unwrap_or_default(),
+            max_message_bytes: parse_env("MAX_MESSAGE_BYTES", 64 * 1024),
+            max_signaling_depth: parse_env("MAX_SIGNALING_DEPTH", 16),
+            max_signaling_elements: parse_env("MAX_SIGNALING_ELEMENTS", 256),
+            max_connections: std::env::var("MAX_CONNECTIONS")
+                .ok()
+                .map(|_| parse_env("MAX_CONNECTIONS", NonZeroUsize::MIN).get()),
+            max_connection_bytes: std::env::var("MAX_CONNECTION_BYTES")
+                .ok()
+                .map(|_| parse_env("MAX_CONNECTION_BYTES", 0)),
+            rate_limit: std::env::var("RATE_LIMIT_PER_SEC")
+                .ok()
+                .map(|_| parse_env("RATE_LIMIT_PER_SEC", NonZeroU32::MIN).get()),
+            max_games: std::env::var("MAX_GAMES")
+                .ok()
+                .map(|_| parse_env("MAX_GAMES", NonZeroUsize::MIN).get()),
+            evict_idle_games: parse_env("EVICT_IDLE_GAMES", false),
+            quick_match_weights: QuickMatchWeights {
+                fill: parse_env("QUICK_MATCH_FILL_WEIGHT", 1.0),
+                freshness: parse_env("QUICK_MATCH_FRESHNESS_WEIGHT", 0.5),
+            },
+            max_players_range: parse_max_players_range(),
+            start_quorum_percent: parse_env("START_QUORUM_PERCENT", 100),
+            start_countdown_secs: parse_env("START_COUNTDOWN_SECS", 5),
+            hide_started_games: parse_env("HIDE_STARTED_GAMES", false),
+            idle_timeout: parse_duration_secs("IDLE_TIMEOUT_SECS", 60),
+            stale_game_timeout: std::env::var("STALE_GAME_TIMEOUT_SECS")
+                .ok()
+                .map(|_| parse_duration_secs("STALE_GAME_TIMEOUT_SECS", 0)),
+            shutdown_grace: parse_duration_secs("SHUTDOWN_GRACE_SECS", 5),
+            resume_grace: std::env::var("RESUME_GRACE_SECS")
+                .ok()
+                .map(|_| parse_duration_secs("RESUME_GRACE_SECS", 0)),
+            state_file: std::env::var("STATE_FILE").ok().map(PathBuf::from),
+            state_save_interval: parse_duration_secs("STATE_SAVE_INTERVAL_SECS", 30),
+            turn: TurnConfig::from_env(),
+            heartbeat_interval: parse_duration_secs("HEARTBEAT_INTERVAL_SECS", 30),
+            liveness_timeout: Duration::from_millis(parse_env("LIVENESS_TIMEOUT_MS", 2000)),
+            socket_buffer: parse_env("SOCKET_BUFFER_SIZE", NonZeroUsize::new(10).unwrap()).get(),
+            socket_id_format: IdFormat::from_env("SOCKET_ID"),
+            game_id_format: IdFormat::from_env("GAME_ID"),
+        };
+        if config.state_file.is_some() && config.resume_grace.is_none() {
+            panic!("STATE_FILE requires RESUME_GRACE_SECS");
+        }
+        config
+    }
+
+    pub fn is_banned(&self, addr: IpAddr) -> bool {
+        // IPv4 peers of a dual-stack listener show up as IPv4-mapped addresses
+        let addr = addr.to_canonical();
+        self.banned_networks.iter().any(|net| net.contains(&addr))
+    }
+
+    pub fn connect_token_valid(&self, token: Option<&str>) -> bool {
+        let Some(connect_tokens) = &self.connect_tokens else {
+            return true;
+        };
+        token.is_some_and(|token| {
+            connect_tokens.iter().fold(false, |valid, allowed| {
+                valid | constant_time_eq(allowed, token)
+            })
+        })
+    }
+
+    // Requests without an Origin header don't come from browsers and are
+    // always allowed
+    pub fn origin_allowed(&self, origin: Option<&[u8]>) -> bool {
+        let (Some(allowed_origins), Some(origin)) = (&self.allowed_origins, origin) else {
+            return true;
+        };
+        allowed_origins
+            .iter()
+            .any(|allowed| allowed.as_bytes().eq_ignore_ascii_case(origin))
+    }
+
+    // Returns the region of the most specific matching CIDR range
+    pub fn infer_region(&self, addr: IpAddr) -> Option<String> {
+        let addr = addr.to_canonical();
+        self.region_map
+            .iter()
+            .filter(|(net, _)| net.contains(&addr))
+            .max_by_key(|(net, _)| net.prefix_len())
+            .map(|(_, region)| region.clone())
+    }
+}
+
+// Compares secrets without exiting at the first differing byte, so response
+// times don't reveal how much of a guessed token was right
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn parse_env<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|err| panic!("Invalid {}: {}", key, err)),
+        Err(_) => default,
+    }
+}
+
+// A full `LOBBY_BIND_ADDR` wins over `PORT`, which listens on every interface
+fn bind_addr(port: Option<&str>, bind: Option<&str>) -> SocketAddr {
+    if let Some(bind) = bind {
+        return bind
+            .parse()
+            .unwrap_or_else(|err| panic!("Invalid LOBBY_BIND_ADDR: {}", err));
+    }
+    let port = port.map_or(8080, |port| {
+        port.parse()
+            .unwrap_or_else(|err| panic!("Invalid PORT: {}", err))
+    });
+    SocketAddr::from(([0, 0, 0, 0], port))
+}
+
+fn parse_duration_secs(key: &str, default: u64) -> Duration {
+    let secs = parse_env(key, default);
+    if secs == 0 {
+        panic!("{} must be positive", key);
+    }
+    Duration::from_secs(secs)
+}
+
+fn parse_max_players_range() -> RangeInclusive<u32> {
+    let min = parse_env("MAX_PLAYERS_MIN", 2);
+    let max = parse_env("MAX_PLAYERS_MAX", 32);
+    if min == 0 || min > max {
+        panic!("MAX_PLAYERS_MIN must be positive and at most MAX_PLAYERS_MAX");
+    }
+    min..=max
+}
+
+// One CIDR range or address per line, `#` starts a comment
+fn load_ban_list(path: &Path) -> Vec<IpNet> {
+    let contents = std::fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err));
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            IpNet::from_str(line)
+                .or_else(|_| IpAddr::from_str(line).map(IpNet::from))
+                .unwrap_or_else(|err| {
+                    panic!("Invalid entry {} in {}: {}", line, path.display(), err)
+                })
+        })
+        .collect()
+}
+
+// Format: `https://example.com,http://localhost:3000`, or `*` for any origin
+fn parse_allowed_origins(value: &str) -> Option<Vec<String>> {
+    let origins: Vec<String> = value
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/'))
+        .filter(|origin| !origin.is_empty())
+        .map(str::to_string)
+        .collect();
+    if origins.iter().any(|origin| origin == "*") {
+        return None;
+    }
+    Some(origins)
+}
+
+// Format: `10.0.0.0/8=eu-north,192.168.0.0/16=eu-central`
+fn parse_region_map(value: &str) -> Vec<(IpNet, String)> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (net, region) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Invalid REGION_MAP entry: {}", entry));
+            let net = IpNet::from_str(net.trim())
+                .unwrap_or_else(|err| panic!("Invalid REGION_MAP entry {}: {}", entry, err));
+            (net, region.trim().to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        IpAddr::from_str(addr).unwrap()
+    }
+
+    #[test]
+    fn secrets_are_equal_only_when_identical() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(constant_time_eq("", ""));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+        assert!(!constant_time_eq("secret", ""));
+    }
+
+    #[test]
+    fn connect_tokens_must_match_one_configured() {
+        let mut config = Config::from_env();
+        config.connect_tokens = None;
+        assert!(config.connect_token_valid(None));
+        config.connect_tokens = Some(vec!["first".to_string(), "second".to_string()]);
+        assert!(config.connect_token_valid(Some("second")));
+        assert!(!config.connect_token_valid(Some("third")));
+        assert!(!config.connect_token_valid(None));
+    }
+
+    #[test]
+    fn regions_are_inferred_from_the_most_specific_range() {
+        let mut config = Config::from_env();
+        config.region_map = parse_region_map("10.0.0.0/8=eu-north, 10.1.0.0/16=eu-central");
+        assert_eq!(
+            config.infer_region(ip("10.2.3.4")).as_deref(),
+            Some("eu-north")
+        );
+        assert_eq!(
+            config.infer_region(ip("10.1.2.3")).as_deref(),
+            Some("eu-central")
+        );
+        assert_eq!(config.infer_region(ip("192.168.0.1")), None);
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_get_the_ipv4_region() {
+        let mut config = Config::from_env();
+        config.region_map = parse_region_map("10.0.0.0/8=eu-north");
+        assert_eq!(
+            config.infer_region(ip("::ffff:10.2.3.4")).as_deref(),
+            Some("eu-north")
+        );
+    }
+
+    #[test]
+    fn the_bind_address_overrides_the_port() {
+        assert_eq!(bind_addr(None, None), "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(
+            bind_addr(Some("9000"), None),
+            "0.0.0.0:9000".parse().unwrap()
+        );
+        assert_eq!(
+            bind_addr(Some("9000"), Some("[::1]:9001")),
+            "[::1]:9001".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn small_id_spaces_are_rejected() {
+        assert!(!id_space_is_safe(2, 4));
+        assert!(!id_space_is_safe(2, 31));
+        assert!(id_space_is_safe(2, 32));
+        assert!(id_space_is_safe(ALPHANUMERIC_LENGTH, 6));
+        assert!(id_space_is_safe(ALPHANUMERIC_LENGTH, MAX_ID_LENGTH));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid PORT")]
+    fn an_invalid_port_is_rejected() {
+        bind_addr(Some("http"), None);
+    }
+
+    #[test]
+    fn a_wildcard_allows_every_origin() {
+        assert_eq!(
+            parse_allowed_origins("https://suomipelit.fi/, http://localhost:3000"),
+            Some(vec![
+                "https://suomipelit.fi".to_string(),
+                "http://localhost:3000".to_string()
+            ])
+        );
+        assert_eq!(parse_allowed_origins("https://suomipelit.fi,*"), None);
+        let mut config = Config::from_env();
+        config.allowed_origins = None;
+        assert!(config.origin_allowed(Some(b"https://evil.example")));
+    }
+
+    #[test]
+    fn banned_networks_are_loaded_from_the_ban_list() {
+        let mut config = Config::from_env();
+        config.banned_networks =
+            load_ban_list(&Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/ban-list.txt"));
+        assert_eq!(config.banned_networks.len(), 3);
+        assert!(config.is_banned(ip("10.1.2.3")));
+        assert!(config.is_banned(ip("192.168.1.7")));
+        assert!(!config.is_banned(ip("192.168.1.8")));
+        assert!(config.is_banned(ip("2001:db8::1")));
+        assert!(config.is_banned(ip("::ffff:10.1.2.3")));
+        assert!(!config.is_banned(ip("127.0.0.1")));
+    }
+}
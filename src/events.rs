@@ -0,0 +1,85 @@
+use crate::{GameId, SocketId};
+use serde::Serialize;
+use std::net::SocketAddr;
+
+// Lifecycle events published to admin subscribers, independent of the
+// per-game client protocol
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum LobbyEvent {
+    #[serde(rename_all = "camelCase")]
+    Connected {
+        socket_id: SocketId,
+        remote_addr: SocketAddr,
+    },
+    #[serde(rename_all = "camelCase")]
+    Disconnected { socket_id: SocketId },
+    #[serde(rename_all = "camelCase")]
+    GameCreated { game_id: GameId, host: SocketId },
+    #[serde(rename_all = "camelCase")]
+    GameClosed { game_id: GameId },
+    #[serde(rename_all = "camelCase")]
+    JoinRequested {
+        game_id: GameId,
+        client_id: SocketId,
+    },
+    #[serde(rename_all = "camelCase")]
+    JoinAccepted {
+        game_id: GameId,
+        client_id: SocketId,
+    },
+    #[serde(rename_all = "camelCase")]
+    SpectatorJoined {
+        game_id: GameId,
+        client_id: SocketId,
+    },
+    #[serde(rename_all = "camelCase")]
+    JoinRejected {
+        game_id: GameId,
+        client_id: SocketId,
+    },
+    #[serde(rename_all = "camelCase")]
+    GameStarting { game_id: GameId },
+    // The host left and a client was promoted in its place
+    #[serde(rename_all = "camelCase")]
+    HostChanged { game_id: GameId, host_id: SocketId },
+    // A pending or accepted client or a spectator left or disconnected
+    #[serde(rename_all = "camelCase")]
+    ClientLeft {
+        game_id: GameId,
+        client_id: SocketId,
+    },
+    #[serde(rename_all = "camelCase")]
+    ClientKicked {
+        game_id: GameId,
+        client_id: SocketId,
+    },
+}
+
+impl LobbyEvent {
+    // The game a game-scoped event belongs to
+    pub fn game_id(&self) -> Option<&GameId> {
+        match self {
+            LobbyEvent::Connected { .. } | LobbyEvent::Disconnected { .. } => None,
+            LobbyEvent::GameCreated { game_id, .. }
+            | LobbyEvent::GameClosed { game_id }
+            | LobbyEvent::GameStarting { game_id }
+            | LobbyEvent::HostChanged { game_id, .. }
+            | LobbyEvent::JoinRequested { game_id, .. }
+            | LobbyEvent::JoinAccepted { game_id, .. }
+            | LobbyEvent::SpectatorJoined { game_id, .. }
+            | LobbyEvent::JoinRejected { game_id, .. }
+            | LobbyEvent::ClientLeft { game_id, .. }
+            | LobbyEvent::ClientKicked { game_id, .. } => Some(game_id),
+        }
+    }
+}
+
+// Entry in a game's event history
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedEvent {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: LobbyEvent,
+}
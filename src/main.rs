@@ -1,548 +1,7534 @@
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{State, WebSocketUpgrade};
+mod admin;
+mod config;
+mod events;
+mod metrics;
+mod persistence;
+mod tls;
+mod turn;
+mod wire;
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use axum::extract::{ConnectInfo, Query, State, WebSocketUpgrade};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::{Error, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use config::{Config, IdFormat, QuickMatchWeights};
+use events::{LobbyEvent, RecordedEvent};
+use indexmap::IndexMap;
+use metrics::Metrics;
 use rand::distributions::{Alphanumeric, DistString};
+use rand::seq::SliceRandom;
 use rand::thread_rng;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+use turn::IceServer;
+use wire::WireFormat;
 
 #[tokio::main]
 async fn main() {
-    let port: u16 = std::env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(8080);
-    let bind = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
+    init_logging();
+
+    let app_state = AppState::new(Config::from_env());
+    if let Some(admin_tls) = &app_state.config.admin_tls {
+        let tls_config =
+            tls::server_config(&admin_tls.cert, &admin_tls.key, Some(&admin_tls.client_ca));
+        let admin_app = admin::routes().with_state(app_state.clone());
+        let admin_server = axum_server::bind_rustls(
+            admin_tls.bind,
+            RustlsConfig::from_config(Arc::new(tls_config)),
+        )
+        .serve(admin_app.into_make_service());
+        info!(bind = %admin_tls.bind, "Starting admin server");
+        tokio::spawn(async { admin_server.await.unwrap() });
+    }
+    let app = app(&app_state);
+
+    if let Some(state_file) = app_state.config.state_file.clone() {
+        let app_state = app_state.clone();
+        let interval = app_state.config.state_save_interval;
+        tokio::spawn(async move {
+            persistence::save_periodically(app_state, &state_file, interval).await
+        });
+    }
+
+    if let Some(timeout) = app_state.config.stale_game_timeout {
+        tokio::spawn(close_stale_games(app_state.clone(), timeout));
+    }
+
+    let bind = app_state.config.bind;
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let Some(tls) = &app_state.config.tls {
+        let tls_config = tls::server_config(&tls.cert, &tls.key, None);
+        let handle = axum_server::Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                shutdown_signal().await;
+                handle.graceful_shutdown(None);
+            }
+        });
+        info!(%bind, "Starting TLS server");
+        axum_server::bind_rustls(bind, RustlsConfig::from_config(Arc::new(tls_config)))
+            .handle(handle)
+            .serve(make_service)
+            .await
+            .unwrap();
+    } else {
+        info!(%bind, "Starting server");
+        axum::Server::bind(&bind)
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .unwrap();
+    }
+
+    info!("Shutting down");
+    // Saved before the sockets are closed, so the games are stored as they
+    // were
+    if let Some(state_file) = &app_state.config.state_file {
+        persistence::save(&app_state, state_file);
+    }
+    app_state.shutdown().await;
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Multiple of `max_message_bytes` the WebSocket transport accepts
+const TRANSPORT_LIMIT_FACTOR: usize = 4;
+
+#[derive(Deserialize)]
+struct ConnectParams {
+    token: Option<String>,
+}
+
+// Browsers can't set headers on WebSocket requests, so the token may also be
+// passed as the `token` query parameter
+fn connect_token<'a>(headers: &'a HeaderMap, params: &'a ConnectParams) -> Option<&'a str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(params.token.as_deref())
+}
 
-    let app = Router::new()
+// The lobby WebSocket, along with the admin routes behind the admin token
+// unless they're served separately over mutual TLS
+fn app(app_state: &AppState) -> Router {
+    let mut app = Router::new()
+        .route("/metrics", get(metrics::metrics))
         .route(
             "/",
             get(
-                |ws: WebSocketUpgrade, State(state): State<AppState>| async {
-                    ws.on_upgrade(|socket| handle_websocket(socket, state))
+                |ws: WebSocketUpgrade,
+                 ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                 headers: HeaderMap,
+                 Query(params): Query<ConnectParams>,
+                 State(state): State<AppState>| async move {
+                    if state.config.is_banned(addr.ip()) {
+                        warn!(%addr, "Refusing WebSocket from banned address");
+                        return StatusCode::FORBIDDEN.into_response();
+                    }
+                    if !state
+                        .config
+                        .connect_token_valid(connect_token(&headers, &params))
+                    {
+                        warn!(%addr, "Refusing WebSocket without a valid token");
+                        return StatusCode::UNAUTHORIZED.into_response();
+                    }
+                    let origin = headers.get(header::ORIGIN);
+                    if !state
+                        .config
+                        .origin_allowed(origin.map(|origin| origin.as_bytes()))
+                    {
+                        warn!(%addr, ?origin, "Refusing WebSocket from disallowed origin");
+                        return StatusCode::FORBIDDEN.into_response();
+                    }
+                    if !offers_known_subprotocol(&headers) {
+                        warn!(%addr, "Refusing WebSocket with unknown subprotocols");
+                        return StatusCode::BAD_REQUEST.into_response();
+                    }
+                    let slot = state.reserve_connection();
+                    // Messages somewhat over the limit are answered with an error,
+                    // much larger ones are cut off by the transport
+                    let transport_limit = state.config.max_message_bytes * TRANSPORT_LIMIT_FACTOR;
+                    ws.protocols(SUBPROTOCOLS.iter().copied())
+                        .max_message_size(transport_limit)
+                        .max_frame_size(transport_limit)
+                        .on_upgrade(move |socket| async move {
+                            let Some(_slot) = slot else {
+                                reject_websocket(socket).await;
+                                return;
+                            };
+                            handle_websocket(socket, addr, state).await
+                        })
+                        .into_response()
                 },
             ),
-        )
-        .with_state(AppState::new());
+        );
+    if app_state.config.admin_tls.is_none() && app_state.config.admin_token.is_some() {
+        app = app.merge(admin::token_routes(app_state));
+    }
+    app.with_state(app_state.clone())
+}
 
-    println!("Starting server on {}", bind);
-    axum::Server::bind(&bind)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+// WebSocket subprotocols by PROTOCOL_VERSION. The first of them, in this
+// order, that the client offers is echoed back.
+const SUBPROTOCOLS: &[&str] = &["suomipelit.v1", MESSAGE_PACK_SUBPROTOCOL];
+
+// Connections negotiating this use MessagePack from the start, so that even
+// the Welcome is sent in binary
+const MESSAGE_PACK_SUBPROTOCOL: &str = "suomipelit.v1.msgpack";
+
+// Clients offering no subprotocol at all predate the negotiation and speak v1
+fn offers_known_subprotocol(headers: &HeaderMap) -> bool {
+    let mut offered = headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .peekable();
+    offered.peek().is_none() || offered.any(|protocol| SUBPROTOCOLS.contains(&protocol))
 }
 
 #[derive(Clone)]
 struct AppState {
-    games: Arc<Mutex<Games>>,
+    config: Arc<Config>,
+    games: Arc<RwLock<Games>>,
     sockets: Arc<Mutex<Sockets>>,
+    events: broadcast::Sender<LobbyEvent>,
+    // Open WebSocket connections, counted before they're upgraded
+    connections: Arc<AtomicUsize>,
+    // Sockets pushed changes to the game list
+    list_subscribers: Arc<Mutex<HashSet<SocketId>>>,
+    sessions: Arc<Mutex<Sessions>>,
+    metrics: Arc<Metrics>,
+}
+
+// Counts towards the connection limit until dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl AppState {
-    fn new() -> Self {
-        Self {
-            games: Arc::new(Mutex::new(Games::new())),
+    fn new(config: Config) -> Self {
+        let app_state = Self {
+            games: Arc::new(RwLock::new(Games::new(config.hide_started_games))),
+            config: Arc::new(config),
             sockets: Arc::new(Mutex::new(Sockets::new())),
+            events: broadcast::channel(100).0,
+            connections: Arc::new(AtomicUsize::new(0)),
+            list_subscribers: Arc::new(Mutex::new(HashSet::new())),
+            sessions: Arc::new(Mutex::new(Sessions::new())),
+            metrics: Arc::new(Metrics::new()),
+        };
+        if let Some(saved) = app_state
+            .config
+            .state_file
+            .as_deref()
+            .and_then(persistence::load)
+        {
+            saved.restore(&app_state);
+        }
+        app_state
+    }
+
+    // Returns None when at the connection limit
+    fn reserve_connection(&self) -> Option<ConnectionSlot> {
+        let count = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+        let slot = ConnectionSlot(self.connections.clone());
+        match self.config.max_connections {
+            Some(max_connections) if count > max_connections => None,
+            _ => Some(slot),
+        }
+    }
+
+    fn publish(&self, event: LobbyEvent) {
+        // Sending only fails when there are no subscribers
+        let _ = self.events.send(event);
+    }
+
+    // Tells every socket the server is going away and waits for them to close,
+    // at most for the configured grace period
+    async fn shutdown(&self) {
+        let sockets = self.lock_sockets(|sockets| sockets.ids());
+        for socket_id in sockets {
+            self.send_to(
+                &socket_id,
+                OutgoingMessage::ServerShutdown {
+                    reason: "Server restarting".to_string(),
+                },
+            );
+        }
+        let drained = async {
+            while !self.lock_sockets(|sockets| sockets.is_empty()) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        if tokio::time::timeout(self.config.shutdown_grace, drained)
+            .await
+            .is_err()
+        {
+            warn!("Sockets still open after the shutdown grace period");
+        }
+    }
+
+    // The socket may have disconnected after it was looked up from the games,
+    // in which case the message is dropped. Never waits for a slow socket:
+    // when its buffer is full, droppable messages are dropped and otherwise
+    // the socket is unregistered, which makes it close.
+    fn send_to(&self, socket_id: &SocketId, message: OutgoingMessage) {
+        let Some(tx) = self.lock_sockets(|sockets| sockets.get(socket_id)) else {
+            debug!(socket_id = %socket_id.0, "Socket is gone, dropping message");
+            return;
+        };
+        match tx.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(message)) if message.is_droppable() => {
+                debug!(
+                    socket_id = %socket_id.0,
+                    message_type = message.message_type(),
+                    "Socket is falling behind, dropping message"
+                );
+            }
+            Err(TrySendError::Full(message)) => {
+                warn!(
+                    socket_id = %socket_id.0,
+                    message_type = message.message_type(),
+                    "Socket is falling behind, disconnecting it"
+                );
+                self.lock_sockets(|sockets| sockets.unregister(socket_id));
+            }
+            Err(TrySendError::Closed(_)) => {
+                debug!(socket_id = %socket_id.0, "Socket is gone, dropping message");
+            }
         }
     }
 
-    fn lock_games<T, F>(&self, f: F) -> T
+    // Most messages only look games up, so readers don't wait for each other
+    fn read_games<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Games) -> T,
+    {
+        let guard = read_recovering(&self.games, "games");
+        f(&guard)
+    }
+
+    fn write_games<T, F>(&self, f: F) -> T
     where
         F: FnOnce(&mut Games) -> T,
     {
-        let mut guard = self.games.lock().unwrap();
+        let mut guard = write_recovering(&self.games, "games");
         f(&mut guard)
     }
 
+    // Like write_games, but also returns how `f` changed the game list when
+    // someone is subscribed to the changes
+    fn write_games_watching_list<T, F>(&self, f: F) -> (T, Option<GameListChanges>)
+    where
+        F: FnOnce(&mut Games) -> T,
+    {
+        let watched = !self.lock_list_subscribers(|subscribers| subscribers.is_empty());
+        self.write_games(|games| {
+            let before = watched.then(|| games.snapshot());
+            let result = f(games);
+            let changes = before.and_then(|before| {
+                let after = games.snapshot();
+                if Arc::ptr_eq(&before, &after) {
+                    return None;
+                }
+                let changes = GameListChanges::between(&before, &after);
+                (!changes.is_empty()).then_some(changes)
+            });
+            (result, changes)
+        })
+    }
+
+    // List subscribers get the changes before members are told about them,
+    // so that no member sees a closed game still listed after its GameClosed
+    fn deliver(
+        &self,
+        other_messages: Vec<(SocketId, OutgoingMessage)>,
+        events: Vec<LobbyEvent>,
+        list_changes: Option<GameListChanges>,
+    ) {
+        if let Some(changes) = list_changes {
+            self.push_list_changes(changes);
+        }
+        for (other_socket_id, outgoing) in other_messages {
+            self.send_to(&other_socket_id, outgoing);
+        }
+        for event in events {
+            self.publish(event);
+        }
+    }
+
+    fn push_list_changes(&self, changes: GameListChanges) {
+        let subscribers: Vec<SocketId> =
+            self.lock_list_subscribers(|subscribers| subscribers.iter().cloned().collect());
+        for socket_id in subscribers {
+            for game in &changes.changed {
+                self.send_to(
+                    &socket_id,
+                    OutgoingMessage::GameInfoChanged { game: game.clone() },
+                );
+            }
+            for game_id in &changes.removed {
+                self.send_to(
+                    &socket_id,
+                    OutgoingMessage::GameRemoved {
+                        game_id: game_id.clone(),
+                    },
+                );
+            }
+        }
+    }
+
     fn lock_sockets<T, F>(&self, f: F) -> T
     where
         F: FnOnce(&mut Sockets) -> T,
     {
-        let mut guard = self.sockets.lock().unwrap();
+        let mut guard = lock_recovering(&self.sockets, "sockets");
+        f(&mut guard)
+    }
+
+    fn lock_sessions<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Sessions) -> T,
+    {
+        let mut guard = lock_recovering(&self.sessions, "sessions");
+        f(&mut guard)
+    }
+
+    // Removes the socket from its games and tells the other members
+    fn disconnect(&self, socket_id: &SocketId) {
+        let (
+            MessagesToSend {
+                other_messages,
+                events,
+                ..
+            },
+            list_changes,
+        ) = self.write_games_watching_list(|games| {
+            let messages = process_disconnect(socket_id, games);
+            games.record_events(&messages.events);
+            messages
+        });
+        self.deliver(other_messages, events, list_changes);
+    }
+
+    // Keeps the socket in its games while its session can be resumed, listing
+    // it as suspended. Returns the time of disconnect to expire the session
+    // with, None if the socket has no session.
+    fn suspend(&self, socket_id: &SocketId) -> Option<Instant> {
+        let disconnected_at = self.lock_sessions(|sessions| sessions.disconnect(socket_id))?;
+        let ((), list_changes) =
+            self.write_games_watching_list(|games| games.set_suspended(socket_id, true));
+        self.deliver(Vec::new(), Vec::new(), list_changes);
+        Some(disconnected_at)
+    }
+
+    // Hands the disconnected session of `token` to the connection registered
+    // as `socket_id`. Returns the resumed socket id and a fresh token for it.
+    fn resume_session(
+        &self,
+        token: &ResumeToken,
+        socket_id: &SocketId,
+    ) -> Option<(SocketId, ResumeToken)> {
+        let (resumed, resume_token) =
+            self.lock_sessions(|sessions| sessions.resume(token, socket_id))?;
+        self.lock_sockets(|sockets| sockets.rename(socket_id, resumed.clone()));
+        let ((), list_changes) = self.write_games_watching_list(|games| {
+            // A name set before resuming replaces the one of the session
+            if let Some(name) = games.names.remove(socket_id) {
+                games.set_name(&resumed, Some(name));
+            }
+            games.set_suspended(&resumed, false);
+        });
+        self.deliver(Vec::new(), Vec::new(), list_changes);
+        Some((resumed, resume_token))
+    }
+
+    // Closes a game, telling its host and members why. Returns false if
+    // there's no such game.
+    fn close_game(&self, game_id: &GameId, reason: &str) -> bool {
+        let (messages, list_changes) = self.write_games_watching_list(|games| {
+            let game = games.remove(game_id)?;
+            let host = game.host.clone();
+            Some(game_closed_messages(game, reason).with_other(
+                host,
+                OutgoingMessage::GameClosed {
+                    game_id: game_id.clone(),
+                    reason: reason.to_string(),
+                },
+            ))
+        });
+        let Some(MessagesToSend {
+            other_messages,
+            events,
+            ..
+        }) = messages
+        else {
+            return false;
+        };
+        self.deliver(other_messages, events, list_changes);
+        true
+    }
+
+    fn lock_list_subscribers<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut HashSet<SocketId>) -> T,
+    {
+        let mut guard = lock_recovering(&self.list_subscribers, "list subscribers");
         f(&mut guard)
     }
 }
 
+// Filtered with `RUST_LOG` directives, `info` when unset. `LOG_FORMAT` picks
+// the output:
+// - `text` (default): one human-readable line per event
+// - `pretty`: multi-line, easier to read while developing
+// - `json`: one JSON object per line, for log aggregators
+fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into());
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    let format = std::env::var("LOG_FORMAT").ok();
+    match log_format(format.as_deref()) {
+        Some(LogFormat::Text) => subscriber.init(),
+        Some(LogFormat::Pretty) => subscriber.pretty().init(),
+        Some(LogFormat::Json) => subscriber.json().init(),
+        None => panic!("Invalid LOG_FORMAT: {}", format.unwrap_or_default()),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Pretty,
+    Json,
+}
+
+// Returns None for unknown formats
+fn log_format(value: Option<&str>) -> Option<LogFormat> {
+    match value {
+        Some("text") | None => Some(LogFormat::Text),
+        Some("pretty") => Some(LogFormat::Pretty),
+        Some("json") => Some(LogFormat::Json),
+        Some(_) => None,
+    }
+}
+
+// A handler panicking while holding a lock poisons it. Keep serving with
+// the state as it was left instead of failing every later lock.
+fn lock_recovering<'a, T>(mutex: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|err| {
+        error!(mutex = name, "Recovering poisoned mutex");
+        mutex.clear_poison();
+        err.into_inner()
+    })
+}
+
+fn read_recovering<'a, T>(lock: &'a RwLock<T>, name: &str) -> RwLockReadGuard<'a, T> {
+    lock.read().unwrap_or_else(|err| {
+        error!(lock = name, "Recovering poisoned lock");
+        lock.clear_poison();
+        err.into_inner()
+    })
+}
+
+fn write_recovering<'a, T>(lock: &'a RwLock<T>, name: &str) -> RwLockWriteGuard<'a, T> {
+    lock.write().unwrap_or_else(|err| {
+        error!(lock = name, "Recovering poisoned lock");
+        lock.clear_poison();
+        err.into_inner()
+    })
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct GameInfo {
     server_name: String,
-    player_amount: u32,
     max_players: u32,
     requires_password: bool,
+    region: Option<String>,
+    join_policy: JoinPolicy,
+    // Checked by the server when the game requires a password, never listed
+    password: Option<String>,
+    migratable: bool,
+    // Game build, only games of the same version can play together
+    version: String,
+    // Left out of the game list and quick match, joinable by id. Independent
+    // of `requires_password`.
+    #[serde(default)]
+    private: bool,
+    // Clients connect to each other as well as to the host
+    #[serde(default)]
+    mesh: bool,
+    // Game specific settings like the map or mode, listed as is
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+impl GameInfo {
+    // Games requiring a password without the server knowing it are left for
+    // the host to check from NewClient
+    fn check_password(&self, password: Option<&str>) -> bool {
+        match (&self.password, self.requires_password) {
+            (Some(expected), true) => password == Some(expected.as_str()),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum JoinPolicy {
+    // Joins are accepted immediately
+    Open,
+    // Joins with the game's password are accepted immediately
+    Password,
+    // The host accepts or rejects each join
+    #[default]
+    Approval,
+    // No joins without an invite token
+    Closed,
 }
 
 struct Game {
     game_id: GameId,
     host: SocketId,
+    // Clients accepted by the host
     clients: HashSet<SocketId>,
+    // Clients that have sent JoinGame but haven't been accepted or rejected yet
+    pending: HashSet<SocketId>,
+    // Watching the game without taking a player slot
+    spectators: HashSet<SocketId>,
+    // Accepted clients the host let answer joins, kick and update the game
+    // info too
+    co_hosts: HashSet<SocketId>,
+    // Slots held for invited clients, consumed by joining with the token
+    reservations: HashSet<InviteToken>,
+    // Tokens of invited joiners still waiting for approval, reserved again if
+    // they don't get in
+    pending_invites: HashMap<SocketId, InviteToken>,
     game_info: GameInfo,
+    // Sequence number of the latest game-scoped event sent to members
+    event_seq: u64,
+    created_at: SystemTime,
+    // Last time the host or a member sent a message. Behind its own lock so
+    // relaying messages doesn't need write access to the games.
+    last_activity: Mutex<Instant>,
+    // Recent game-scoped events, oldest first
+    history: VecDeque<RecordedEvent>,
+    liveness_check: Option<LivenessCheck>,
+    // Accepted clients that have marked themselves ready to start
+    ready: HashSet<SocketId>,
+    // Host and members that lost their connection but can still resume their
+    // session, keeping their places until it expires
+    suspended: HashSet<SocketId>,
+    status: GameStatus,
 }
 
-enum JoinGameError {
-    GameNotFound,
-    AlreadyJoined,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum GameStatus {
+    Open,
+    // The host started the game, no more joins are accepted
+    Starting,
+}
+
+// Started by the host with PingClients
+struct LivenessCheck {
+    // Accepted clients at the time of the ping
+    pinged: HashSet<SocketId>,
+    responded: HashSet<SocketId>,
 }
 
-struct Games(Vec<Game>);
+// Number of events kept in each game's history
+const GAME_HISTORY_LENGTH: usize = 64;
 
-impl Games {
-    fn new() -> Self {
-        Self(Vec::new())
+// Random game ids tried before giving up on CreateGame, only reached when
+// most ids are taken
+const MAX_GAME_ID_ATTEMPTS: usize = 32;
+
+// Largest limit accepted by ListGames
+const MAX_LIST_LIMIT: usize = 100;
+
+// Longer chat messages are cut, in characters
+const MAX_CHAT_LENGTH: usize = 500;
+
+// Largest relayed Broadcast payload, in bytes of JSON
+const MAX_BROADCAST_BYTES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Occupancy {
+    // Connected host and accepted clients
+    players: u32,
+    // Host and accepted clients within their resume grace, still holding
+    // their slots
+    suspended: u32,
+    // Don't take player slots
+    spectators: u32,
+    pending: u32,
+    reserved: u32,
+}
+
+impl Occupancy {
+    // Slots held by players, suspended players, pending joiners and
+    // reservations
+    fn taken_slots(&self) -> u32 {
+        self.players + self.suspended + self.pending + self.reserved
     }
 
-    fn add(&mut self, game: Game) {
-        self.0.push(game);
+    // Slots left for joiners without an invite token
+    fn free_slots(&self, max_players: u32) -> u32 {
+        max_players.saturating_sub(self.taken_slots())
     }
+}
 
-    fn update_info(&mut self, host: &SocketId, info: GameInfo) -> bool {
-        if let Some(game) = self.0.iter_mut().find(|game| game.host == *host) {
-            game.game_info = info;
-            true
-        } else {
-            false
+impl Game {
+    // As listed to clients
+    fn outgoing_info(&self) -> OutgoingGameInfo {
+        let occupancy = self.occupancy();
+        OutgoingGameInfo {
+            game_id: self.game_id.clone(),
+            server_name: self.game_info.server_name.clone(),
+            player_amount: occupancy.players,
+            spectator_amount: occupancy.spectators,
+            max_players: self.game_info.max_players,
+            requires_password: self.game_info.requires_password,
+            region: self.game_info.region.clone(),
+            join_policy: self.game_info.join_policy,
+            status: self.status,
+            version: self.game_info.version.clone(),
+            created_at: self.created_at,
+            metadata: self.game_info.metadata.clone(),
+            host_name: None,
         }
     }
 
-    fn join_game(
-        &mut self,
-        game_id: &GameId,
-        client: &SocketId,
-    ) -> Result<SocketId, JoinGameError> {
-        let game = self
-            .0
-            .iter_mut()
-            .find(|game| game.game_id == *game_id)
-            .ok_or(JoinGameError::GameNotFound)?;
-        if !game.clients.insert(client.clone()) {
-            Err(JoinGameError::AlreadyJoined)
-        } else {
-            Ok(game.host.clone())
+    // Returns whether the client was pending, giving back its reservation
+    fn remove_pending(&mut self, client: &SocketId) -> bool {
+        if let Some(token) = self.pending_invites.remove(client) {
+            self.reservations.insert(token);
         }
+        self.pending.remove(client)
     }
 
-    fn remove_game(&mut self, host: &SocketId) -> bool {
-        if let Some(index) = self.0.iter().position(|game| game.host == *host) {
-            self.0.remove(index);
-            true
-        } else {
-            false
-        }
+    // Nearly full games are preferred to help them start, and recently active
+    // ones since their hosts are likely still around
+    fn quick_match_score(&self, weights: QuickMatchWeights) -> f64 {
+        let fill = self.occupancy().players as f64 / self.game_info.max_players as f64;
+        let idle = lock_recovering(&self.last_activity, "game activity").elapsed();
+        let freshness = 0.5f64.powf(idle.as_secs_f64() / 60.0);
+        weights.fill * fill + weights.freshness * freshness
     }
 
-    fn remove_client(&mut self, client: &SocketId) {
-        for game in self.0.iter_mut() {
-            game.clients.remove(client);
+    fn occupancy(&self) -> Occupancy {
+        let suspended = std::iter::once(&self.host)
+            .chain(&self.clients)
+            .filter(|player| self.suspended.contains(player))
+            .count() as u32;
+        Occupancy {
+            players: self.clients.len() as u32 + 1 - suspended,
+            suspended,
+            spectators: self.spectators.len() as u32,
+            pending: self.pending.len() as u32,
+            reserved: self.reservations.len() as u32,
         }
     }
 
-    fn list(&self) -> Vec<OutgoingGameInfo> {
-        self.0
-            .iter()
-            .map(|game| OutgoingGameInfo {
-                game_id: game.game_id.clone(),
-                server_name: game.game_info.server_name.clone(),
-                player_amount: game.game_info.player_amount,
-                max_players: game.game_info.max_players,
-                requires_password: game.game_info.requires_password,
-            })
-            .collect()
+    fn is_member(&self, socket_id: &SocketId) -> bool {
+        self.clients.contains(socket_id)
+            || self.pending.contains(socket_id)
+            || self.spectators.contains(socket_id)
     }
 
-    fn get_game_by_host(&self, host: &SocketId) -> Option<&Game> {
-        self.0.iter().find(|game| game.host == *host)
+    fn next_seq(&mut self) -> u64 {
+        self.event_seq += 1;
+        self.event_seq
     }
 
-    fn get_game_by_client(&self, client: &SocketId) -> Option<&Game> {
-        self.0.iter().find(|game| game.clients.contains(client))
+    fn is_manager(&self, socket_id: &SocketId) -> bool {
+        self.host == *socket_id || self.co_hosts.contains(socket_id)
+    }
+
+    fn remove_member(&mut self, client: &SocketId) -> Option<LeftGame> {
+        self.ready.remove(client);
+        self.suspended.remove(client);
+        self.co_hosts.remove(client);
+        let accepted = self.clients.remove(client);
+        let removed = accepted | self.remove_pending(client) | self.spectators.remove(client);
+        if !removed {
+            return None;
+        }
+        Some(LeftGame {
+            game_id: self.game_id.clone(),
+            host: self.host.clone(),
+            seq: self.next_seq(),
+            accepted,
+        })
     }
 }
 
-struct Sockets(HashMap<SocketId, mpsc::Sender<OutgoingMessage>>);
+// A pending or accepted client or a spectator removed from a game
+struct LeftGame {
+    game_id: GameId,
+    host: SocketId,
+    seq: u64,
+    // The client was accepted, so it was part of the lobby state
+    accepted: bool,
+}
 
-impl Sockets {
-    fn new() -> Self {
-        Self(HashMap::new())
-    }
+#[derive(Clone, Copy)]
+enum JoinGameError {
+    GameNotFound,
+    OwnGame,
+    InOtherGame,
+    AlreadyMember,
+    JoinPending,
+    GameFull,
+    InvalidInviteToken,
+    GameClosed,
+    WrongPassword,
+    GameStarted,
+}
 
-    fn get(&self, socket_id: &SocketId) -> mpsc::Sender<OutgoingMessage> {
-        self.0.get(socket_id).unwrap().clone()
-    }
+enum StartGameError {
+    NotHost,
+    AlreadyStarting,
+    NotReady(Vec<SocketId>),
+}
 
-    fn register(&mut self) -> (SocketId, mpsc::Receiver<OutgoingMessage>) {
-        let (tx, rx) = mpsc::channel(10);
-        let id = SocketId::random();
-        self.0.insert(id.clone(), tx);
-        (id, rx)
-    }
+struct JoinedGame {
+    host: SocketId,
+    // Told about pending joins along with the host, so they can answer them
+    co_hosts: Vec<SocketId>,
+    // Sequence number of the join event
+    seq: u64,
+    // Whether the join policy accepted the client without the host
+    accepted: bool,
+}
 
-    fn unregister(&mut self, socket_id: &SocketId) {
-        self.0.remove(socket_id);
-    }
+struct HostMigration {
+    game_id: GameId,
+    new_host: SocketId,
+    // Accepted clients other than the new host, and spectators
+    clients: Vec<SocketId>,
+    // Pending joins, rejected since the new host never saw them
+    rejected: Vec<SocketId>,
+    seq: u64,
 }
 
-async fn handle_websocket(socket: WebSocket, app_state: AppState) {
-    let (socket_id, rx) = app_state.lock_sockets(|sockets| sockets.register());
+enum KickError {
+    NotHost,
+    NotMember,
+}
 
-    let mut client = SocketState {
-        socket_id,
-        socket,
-        app_state: app_state.clone(),
-        rx,
-    };
-    client.run().await;
+enum CoHostError {
+    NotHost,
+    // Only accepted clients can be co-hosts
+    NotClient,
+}
 
-    app_state.lock_sockets(|sockets| sockets.unregister(&client.socket_id));
+enum JoinResponseError {
+    NotHost,
+    NotPending,
 }
 
-struct SocketState {
-    socket_id: SocketId,
-    socket: WebSocket,
-    app_state: AppState,
-    rx: mpsc::Receiver<OutgoingMessage>,
+enum LivenessCheckError {
+    NotHost,
+    AlreadyRunning,
 }
 
-impl SocketState {
-    async fn run(&mut self) {
-        loop {
-            select! {
-                msg = self.socket.recv() => {
-                    if !self.handle_message(msg).await {
-                        break;
-                    }
-                },
-                Some(outgoing) = self.rx.recv() => {
-                    self.send(outgoing).await;
-                },
-                else => break
-            }
-        }
+enum ReserveSlotError {
+    NotHost,
+    NotEnoughSlots,
+    UnknownInviteToken,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GameFilters {
+    region: Option<String>,
+    // Only games of exactly this version, which clients should always pass
+    version: Option<String>,
+}
+
+impl GameFilters {
+    fn matches(&self, game: &Game) -> bool {
+        self.region
+            .as_ref()
+            .is_none_or(|region| game.game_info.region.as_ref() == Some(region))
+            && self
+                .version
+                .as_ref()
+                .is_none_or(|version| game.game_info.version == *version)
     }
+}
 
-    // Returns true if the socket should continue to run
-    async fn handle_message(&mut self, message: Option<Result<Message, Error>>) -> bool {
-        let Some(message) = message else {
-            self.app_state.lock_games(|games| {
-                process_disconnect(&self.socket_id, games);
-            });
-            return false
-        };
-        let Ok(message) = message else {
-            println!("Error receiving websocket message");
-            return true
-        };
-        let Ok(data) = message.to_text() else {
-            println!("Received non-text message");
-            self.send(OutgoingMessage::Error {
-                reason: "Invalid message".to_string(),
-            }).await;
-            return true
-        };
-        if data.is_empty() {
-            println!("Received empty message from {}", self.socket_id.0);
-        } else {
-            println!("Received message from {}: {}", self.socket_id.0, data);
-            let incoming_message = match serde_json::from_str(data) {
-                Ok(incoming) => incoming,
-                Err(err) => {
-                    println!("Invalid message from socket {}: {}", self.socket_id.0, data);
-                    self.send(OutgoingMessage::Error {
-                        reason: format!("Invalid message: {}", err),
-                    })
-                    .await;
-                    return true;
-                }
-            };
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListQuery {
+    // Case-insensitive substring of the server name
+    name_filter: Option<String>,
+    // Only games of exactly this version
+    version_filter: Option<String>,
+    // Comma-separated regions, e.g. `eu-north,eu-central`
+    region_filter: Option<String>,
+    sort: Option<SortBy>,
+    // Moves full games after the others
+    #[serde(default)]
+    hide_full: bool,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
 
-            let MessagesToSend {
-                self_message,
-                other_message,
-            } = self.app_state.lock_games(|games| {
-                process_incoming_message(&self.socket_id, games, incoming_message)
-            });
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum SortBy {
+    PlayersAsc,
+    PlayersDesc,
+    Name,
+    Newest,
+    Oldest,
+}
 
-            if let Some(outgoing) = self_message {
-                self.send(outgoing).await;
-            };
-            if let Some((other_socket_id, outgoing)) = other_message {
-                let tx = self
-                    .app_state
-                    .lock_sockets(|sockets| sockets.get(&other_socket_id));
-                tx.send(outgoing).await.unwrap();
-            }
+// Games added or updated and removed between two listings
+struct GameListChanges {
+    changed: Vec<OutgoingGameInfo>,
+    removed: Vec<GameId>,
+}
+
+impl GameListChanges {
+    fn between(before: &[OutgoingGameInfo], after: &[OutgoingGameInfo]) -> Self {
+        let previous: HashMap<&GameId, &OutgoingGameInfo> =
+            before.iter().map(|game| (&game.game_id, game)).collect();
+        let current: HashSet<&GameId> = after.iter().map(|game| &game.game_id).collect();
+        Self {
+            changed: after
+                .iter()
+                .filter(|game| previous.get(&game.game_id) != Some(game))
+                .cloned()
+                .collect(),
+            removed: before
+                .iter()
+                .filter(|game| !current.contains(&game.game_id))
+                .map(|game| game.game_id.clone())
+                .collect(),
         }
-        true
     }
 
-    async fn send(&mut self, message: OutgoingMessage) {
-        let data = serde_json::to_string(&message).unwrap();
-        self.socket.send(data.into()).await.unwrap();
+    fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
     }
 }
 
-struct MessagesToSend {
-    self_message: Option<OutgoingMessage>,
-    other_message: Option<(SocketId, OutgoingMessage)>,
+struct Games {
+    // In creation order, which the game list keeps
+    games: IndexMap<GameId, Game>,
+    // Game of each host and of each client, pending client or spectator, kept
+    // in sync by every method adding or removing them
+    hosts: HashMap<SocketId, GameId>,
+    members: HashMap<SocketId, GameId>,
+    // Display names set with SetName, kept here as the listing shows them for
+    // hosts
+    names: HashMap<SocketId, String>,
+    // Cached game list, rebuilt on the first listing after a mutation
+    snapshot: OnceLock<Arc<Vec<OutgoingGameInfo>>>,
+    // Leave games that have been started out of the listing
+    hide_started: bool,
 }
 
-impl MessagesToSend {
-    fn self_(message: OutgoingMessage) -> Self {
+impl Games {
+    fn new(hide_started: bool) -> Self {
         Self {
-            self_message: Some(message),
-            other_message: None,
+            games: IndexMap::new(),
+            hosts: HashMap::new(),
+            members: HashMap::new(),
+            names: HashMap::new(),
+            snapshot: OnceLock::new(),
+            hide_started,
         }
     }
 
-    fn other(id: SocketId, message: OutgoingMessage) -> Self {
-        Self {
-            self_message: None,
-            other_message: Some((id, message)),
-        }
+    // All mutable access goes through here so the snapshot can't go stale
+    fn games_mut(&mut self) -> &mut IndexMap<GameId, Game> {
+        self.snapshot.take();
+        &mut self.games
     }
 
-    fn none() -> Self {
-        Self {
-            self_message: None,
-            other_message: None,
+    fn add(&mut self, game: Game) {
+        self.hosts.insert(game.host.clone(), game.game_id.clone());
+        for member in game
+            .clients
+            .iter()
+            .chain(&game.pending)
+            .chain(&game.spectators)
+        {
+            self.members.insert(member.clone(), game.game_id.clone());
         }
+        self.games_mut().insert(game.game_id.clone(), game);
     }
-}
 
-fn process_incoming_message(
+    // Removing keeps the creation order of the remaining games
+    fn remove(&mut self, game_id: &GameId) -> Option<Game> {
+        let game = self.games_mut().shift_remove(game_id)?;
+        self.hosts.remove(&game.host);
+        for member in game
+            .clients
+            .iter()
+            .chain(&game.pending)
+            .chain(&game.spectators)
+        {
+            self.unindex_member(member, game_id);
+        }
+        Some(game)
+    }
+
+    // Marks the host or member as suspended in its games, or as connected again
+    fn set_suspended(&mut self, socket_id: &SocketId, suspended: bool) {
+        let game_ids: Vec<GameId> = self
+            .hosts
+            .get(socket_id)
+            .into_iter()
+            .chain(self.members.get(socket_id))
+            .cloned()
+            .collect();
+        for game_id in game_ids {
+            let game = &mut self.games_mut()[&game_id];
+            if suspended {
+                game.suspended.insert(socket_id.clone());
+            } else {
+                game.suspended.remove(socket_id);
+            }
+        }
+    }
+
+    fn unindex_member(&mut self, member: &SocketId, game_id: &GameId) {
+        if self.members.get(member) == Some(game_id) {
+            self.members.remove(member);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.games.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Game> {
+        self.games.values()
+    }
+
+    // Activity isn't part of the listing, so this leaves the snapshot intact
+    fn touch(&self, socket_id: &SocketId) {
+        let now = Instant::now();
+        let hosted = self.get_game_by_host(socket_id);
+        let joined = self.get_game_by_client(socket_id);
+        for game in hosted.into_iter().chain(joined) {
+            *lock_recovering(&game.last_activity, "game activity") = now;
+        }
+    }
+
+    // Games whose host and members have sent nothing for `timeout`
+    fn stale_games(&self, timeout: Duration) -> Vec<GameId> {
+        self.games
+            .values()
+            .filter(|game| {
+                lock_recovering(&game.last_activity, "game activity").elapsed() >= timeout
+            })
+            .map(|game| game.game_id.clone())
+            .collect()
+    }
+
+    // Removes the least recently active game without clients
+    fn evict_idle_game(&mut self) -> Option<Game> {
+        let game_id = self
+            .games
+            .values()
+            .filter(|game| game.clients.is_empty() && game.pending.is_empty())
+            .min_by_key(|game| *lock_recovering(&game.last_activity, "game activity"))
+            .map(|game| game.game_id.clone())?;
+        self.remove(&game_id)
+    }
+
+    // Returns the updated game and the sequence number of the update, None if
+    // the socket doesn't manage a game
+    fn update_info<F>(&mut self, manager: &SocketId, f: F) -> Option<(GameId, u64)>
+    where
+        F: FnOnce(&mut GameInfo),
+    {
+        let game_id = self.get_managed_game(manager)?.game_id.clone();
+        let game = &mut self.games_mut()[&game_id];
+        let info = &mut game.game_info;
+        f(info);
+        // Password games keep requiring it whatever the update says
+        info.requires_password |= info.join_policy == JoinPolicy::Password;
+        let seq = game.next_seq();
+        Some((game_id, seq))
+    }
+
+    fn join_game(
+        &mut self,
+        game_id: &GameId,
+        client: &SocketId,
+        password: Option<&str>,
+        invite_token: Option<&InviteToken>,
+    ) -> Result<JoinedGame, JoinGameError> {
+        if !self.games.contains_key(game_id) {
+            return Err(JoinGameError::GameNotFound);
+        }
+        if self.in_other_game(client, game_id) {
+            return Err(JoinGameError::InOtherGame);
+        }
+        let game = &mut self.games_mut()[game_id];
+        if game.host == *client {
+            return Err(JoinGameError::OwnGame);
+        }
+        if game.clients.contains(client) || game.spectators.contains(client) {
+            return Err(JoinGameError::AlreadyMember);
+        }
+        if game.pending.contains(client) {
+            return Err(JoinGameError::JoinPending);
+        }
+        if game.status == GameStatus::Starting {
+            return Err(JoinGameError::GameStarted);
+        }
+        let policy = game.game_info.join_policy;
+        match invite_token {
+            // Invited clients skip the password and closed checks, but still
+            // need approval if the host wants to approve joins
+            Some(token) => {
+                if !game.reservations.remove(token) {
+                    return Err(JoinGameError::InvalidInviteToken);
+                }
+            }
+            None => {
+                if policy == JoinPolicy::Closed {
+                    return Err(JoinGameError::GameClosed);
+                }
+                if !game.game_info.check_password(password) {
+                    return Err(JoinGameError::WrongPassword);
+                }
+                if game.occupancy().free_slots(game.game_info.max_players) == 0 {
+                    return Err(JoinGameError::GameFull);
+                }
+            }
+        }
+        let accepted = policy != JoinPolicy::Approval;
+        if accepted {
+            game.clients.insert(client.clone());
+        } else {
+            game.pending.insert(client.clone());
+            if let Some(token) = invite_token {
+                game.pending_invites.insert(client.clone(), token.clone());
+            }
+        }
+        let joined = JoinedGame {
+            host: game.host.clone(),
+            co_hosts: game.co_hosts.iter().cloned().collect(),
+            seq: game.next_seq(),
+            accepted,
+        };
+        self.members.insert(client.clone(), game_id.clone());
+        Ok(joined)
+    }
+
+    // Spectators aren't limited by `max_players`, but are otherwise let in
+    // like joiners without an invite. Returns the host and the sequence number
+    // of the join event.
+    fn join_as_spectator(
+        &mut self,
+        game_id: &GameId,
+        client: &SocketId,
+        password: Option<&str>,
+    ) -> Result<(SocketId, u64), JoinGameError> {
+        if !self.games.contains_key(game_id) {
+            return Err(JoinGameError::GameNotFound);
+        }
+        if self.in_other_game(client, game_id) {
+            return Err(JoinGameError::InOtherGame);
+        }
+        let game = &mut self.games_mut()[game_id];
+        if game.host == *client {
+            return Err(JoinGameError::OwnGame);
+        }
+        if game.is_member(client) {
+            return Err(JoinGameError::AlreadyMember);
+        }
+        if game.game_info.join_policy == JoinPolicy::Closed {
+            return Err(JoinGameError::GameClosed);
+        }
+        if !game.game_info.check_password(password) {
+            return Err(JoinGameError::WrongPassword);
+        }
+        game.spectators.insert(client.clone());
+        let joined = (game.host.clone(), game.next_seq());
+        self.members.insert(client.clone(), game_id.clone());
+        Ok(joined)
+    }
+
+    fn get_hosted_game_mut(&mut self, host: &SocketId, game_id: &GameId) -> Option<&mut Game> {
+        self.games_mut()
+            .get_mut(game_id)
+            .filter(|game| game.host == *host)
+    }
+
+    // The game the socket hosts or is a co-host of
+    fn get_managed_game(&self, socket_id: &SocketId) -> Option<&Game> {
+        self.get_game_by_host(socket_id).or_else(|| {
+            self.get_game_by_client(socket_id)
+                .filter(|game| game.co_hosts.contains(socket_id))
+        })
+    }
+
+    fn get_managed_game_mut(&mut self, manager: &SocketId, game_id: &GameId) -> Option<&mut Game> {
+        self.games_mut()
+            .get_mut(game_id)
+            .filter(|game| game.is_manager(manager))
+    }
+
+    // Returns the host and the sequence number of the accept event
+    fn accept_client(
+        &mut self,
+        manager: &SocketId,
+        game_id: &GameId,
+        client: &SocketId,
+    ) -> Result<(SocketId, u64), JoinResponseError> {
+        let game = self
+            .get_managed_game_mut(manager, game_id)
+            .ok_or(JoinResponseError::NotHost)?;
+        if !game.pending.remove(client) {
+            return Err(JoinResponseError::NotPending);
+        }
+        game.pending_invites.remove(client);
+        game.clients.insert(client.clone());
+        Ok((game.host.clone(), game.next_seq()))
+    }
+
+    // Rejects a pending join or removes an already accepted client. Returns
+    // the host and the sequence number of the reject event.
+    fn reject_client(
+        &mut self,
+        manager: &SocketId,
+        game_id: &GameId,
+        client: &SocketId,
+    ) -> Result<(SocketId, u64), JoinResponseError> {
+        let game = self
+            .get_managed_game_mut(manager, game_id)
+            .ok_or(JoinResponseError::NotHost)?;
+        if !game.remove_pending(client) && !game.clients.remove(client) {
+            return Err(JoinResponseError::NotPending);
+        }
+        game.ready.remove(client);
+        game.co_hosts.remove(client);
+        game.suspended.remove(client);
+        let rejected = (game.host.clone(), game.next_seq());
+        self.unindex_member(client, game_id);
+        Ok(rejected)
+    }
+
+    fn kick_client(
+        &mut self,
+        manager: &SocketId,
+        game_id: &GameId,
+        client: &SocketId,
+    ) -> Result<LeftGame, KickError> {
+        let left = self
+            .get_managed_game_mut(manager, game_id)
+            .ok_or(KickError::NotHost)?
+            .remove_member(client)
+            .ok_or(KickError::NotMember)?;
+        self.unindex_member(client, game_id);
+        Ok(left)
+    }
+
+    // Returns the sequence number of the promotion
+    fn add_co_host(
+        &mut self,
+        host: &SocketId,
+        game_id: &GameId,
+        client: &SocketId,
+    ) -> Result<u64, CoHostError> {
+        let game = self
+            .get_hosted_game_mut(host, game_id)
+            .ok_or(CoHostError::NotHost)?;
+        if !game.clients.contains(client) {
+            return Err(CoHostError::NotClient);
+        }
+        game.co_hosts.insert(client.clone());
+        Ok(game.next_seq())
+    }
+
+    // Returns whether the ready state changed, None if the client isn't
+    // accepted in the game. Readiness isn't part of the listing, so this
+    // leaves the snapshot intact.
+    fn set_ready(&mut self, client: &SocketId, game_id: &GameId, ready: bool) -> Option<bool> {
+        let game = self
+            .games
+            .get_mut(game_id)
+            .filter(|game| game.clients.contains(client))?;
+        if ready {
+            Some(game.ready.insert(client.clone()))
+        } else {
+            Some(game.ready.remove(client))
+        }
+    }
+
+    // Requires at least `quorum_percent` of the accepted clients to be ready.
+    // Returns the members to notify and the sequence number of the start event.
+    fn start_game(
+        &mut self,
+        host: &SocketId,
+        game_id: &GameId,
+        quorum_percent: u32,
+    ) -> Result<(Vec<SocketId>, u64), StartGameError> {
+        let game = self
+            .get_hosted_game_mut(host, game_id)
+            .ok_or(StartGameError::NotHost)?;
+        if game.status == GameStatus::Starting {
+            return Err(StartGameError::AlreadyStarting);
+        }
+        let required = (game.clients.len() as u32 * quorum_percent).div_ceil(100);
+        if (game.ready.len() as u32) < required {
+            let not_ready = game
+                .clients
+                .iter()
+                .filter(|client| !game.ready.contains(*client))
+                .cloned()
+                .collect();
+            return Err(StartGameError::NotReady(not_ready));
+        }
+        game.status = GameStatus::Starting;
+        let members = game
+            .clients
+            .iter()
+            .chain(&game.spectators)
+            .cloned()
+            .collect();
+        Ok((members, game.next_seq()))
+    }
+
+    // Returns the clients to ping. The liveness check isn't part of the listing,
+    // so this leaves the snapshot intact.
+    fn start_liveness_check(
+        &mut self,
+        host: &SocketId,
+        game_id: &GameId,
+    ) -> Result<Vec<SocketId>, LivenessCheckError> {
+        let game = self
+            .games
+            .get_mut(game_id)
+            .filter(|game| game.host == *host)
+            .ok_or(LivenessCheckError::NotHost)?;
+        if game.liveness_check.is_some() {
+            return Err(LivenessCheckError::AlreadyRunning);
+        }
+        game.liveness_check = Some(LivenessCheck {
+            pinged: game.clients.clone(),
+            responded: HashSet::new(),
+        });
+        Ok(game.clients.iter().cloned().collect())
+    }
+
+    fn record_pong(&mut self, client: &SocketId, game_id: &GameId) {
+        let check = self
+            .games
+            .get_mut(game_id)
+            .and_then(|game| game.liveness_check.as_mut());
+        if let Some(check) = check {
+            if check.pinged.contains(client) {
+                check.responded.insert(client.clone());
+            }
+        }
+    }
+
+    // Returns the host and the result to send to it. Clients that left during
+    // the check are left out.
+    fn finish_liveness_check(&mut self, game_id: &GameId) -> Option<(SocketId, OutgoingMessage)> {
+        let game = self.games.get_mut(game_id)?;
+        let check = game.liveness_check.take()?;
+        let (responsive, unresponsive) = check
+            .pinged
+            .into_iter()
+            .filter(|client| game.clients.contains(client))
+            .partition(|client| check.responded.contains(client));
+        Some((
+            game.host.clone(),
+            OutgoingMessage::ClientLiveness {
+                game_id: game_id.clone(),
+                responsive,
+                unresponsive,
+            },
+        ))
+    }
+
+    fn reserve_slots(
+        &mut self,
+        host: &SocketId,
+        game_id: &GameId,
+        count: u32,
+    ) -> Result<Vec<InviteToken>, ReserveSlotError> {
+        let game = self
+            .get_hosted_game_mut(host, game_id)
+            .ok_or(ReserveSlotError::NotHost)?;
+        if count > game.occupancy().free_slots(game.game_info.max_players) {
+            return Err(ReserveSlotError::NotEnoughSlots);
+        }
+        let tokens: Vec<InviteToken> = (0..count).map(|_| InviteToken::random()).collect();
+        game.reservations.extend(tokens.iter().cloned());
+        Ok(tokens)
+    }
+
+    fn release_slot(
+        &mut self,
+        host: &SocketId,
+        game_id: &GameId,
+        invite_token: &InviteToken,
+    ) -> Result<(), ReserveSlotError> {
+        let game = self
+            .get_hosted_game_mut(host, game_id)
+            .ok_or(ReserveSlotError::NotHost)?;
+        if game.reservations.remove(invite_token) {
+            Ok(())
+        } else {
+            Err(ReserveSlotError::UnknownInviteToken)
+        }
+    }
+
+    // Hands a migratable game over to one of its accepted clients
+    fn migrate_host(&mut self, host: &SocketId) -> Option<HostMigration> {
+        let game_id = self
+            .get_game_by_host(host)
+            .filter(|game| game.game_info.migratable && !game.clients.is_empty())?
+            .game_id
+            .clone();
+        let game = &mut self.games_mut()[&game_id];
+        // Co-hosts already help running the game, so one of them takes over
+        let new_host = game
+            .co_hosts
+            .iter()
+            .next()
+            .or_else(|| game.clients.iter().next())?
+            .clone();
+        game.clients.remove(&new_host);
+        game.ready.remove(&new_host);
+        game.co_hosts.remove(&new_host);
+        game.host = new_host.clone();
+        game.suspended.remove(host);
+        game.liveness_check = None;
+        let migration = HostMigration {
+            game_id: game.game_id.clone(),
+            new_host,
+            clients: game
+                .clients
+                .iter()
+                .chain(&game.spectators)
+                .cloned()
+                .collect(),
+            rejected: game.pending.drain().collect(),
+            seq: game.next_seq(),
+        };
+        for rejected in &migration.rejected {
+            game.suspended.remove(rejected);
+        }
+        let invites: Vec<InviteToken> = game
+            .pending_invites
+            .drain()
+            .map(|(_, token)| token)
+            .collect();
+        game.reservations.extend(invites);
+        self.hosts.remove(host);
+        self.unindex_member(&migration.new_host, &game_id);
+        for rejected in &migration.rejected {
+            self.unindex_member(rejected, &game_id);
+        }
+        self.hosts.insert(migration.new_host.clone(), game_id);
+        Some(migration)
+    }
+
+    fn remove_game(&mut self, host: &SocketId) -> Option<Game> {
+        let game_id = self.get_game_by_host(host)?.game_id.clone();
+        self.remove(&game_id)
+    }
+
+    // Removes the client from the game it's in
+    fn remove_client(&mut self, client: &SocketId) -> Vec<LeftGame> {
+        let Some(game_id) = self.members.remove(client) else {
+            return Vec::new();
+        };
+        self.games_mut()
+            .get_mut(&game_id)
+            .and_then(|game| game.remove_member(client))
+            .into_iter()
+            .collect()
+    }
+
+    fn leave_game(&mut self, client: &SocketId, game_id: &GameId) -> Option<LeftGame> {
+        if !self.games.get(game_id)?.is_member(client) {
+            return None;
+        }
+        let left = self.games_mut()[game_id].remove_member(client);
+        self.unindex_member(client, game_id);
+        left
+    }
+
+    // History isn't part of the listing, so this leaves the snapshot intact
+    fn record_events(&mut self, events: &[LobbyEvent]) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        for event in events {
+            let Some(game_id) = event.game_id() else {
+                continue;
+            };
+            let Some(game) = self.games.get_mut(game_id) else {
+                continue;
+            };
+            if game.history.len() == GAME_HISTORY_LENGTH {
+                game.history.pop_front();
+            }
+            game.history.push_back(RecordedEvent {
+                at,
+                event: event.clone(),
+            });
+        }
+    }
+
+    fn history(&self, game_id: &GameId) -> Option<Vec<RecordedEvent>> {
+        self.games
+            .get(game_id)
+            .map(|game| game.history.iter().cloned().collect())
+    }
+
+    // Returns a page of the games matching the query along with the total
+    // number of matching games. An empty query returns the cached list as is.
+    fn list(&self, query: &ListQuery) -> (Arc<Vec<OutgoingGameInfo>>, usize) {
+        let games = self.snapshot();
+        let name_filter = query
+            .name_filter
+            .as_deref()
+            .filter(|name_filter| !name_filter.is_empty())
+            .map(str::to_lowercase);
+        if name_filter.is_none()
+            && query.version_filter.is_none()
+            && query.region_filter.is_none()
+            && query.sort.is_none()
+            && !query.hide_full
+            && query.offset.is_none()
+            && query.limit.is_none()
+        {
+            let total = games.len();
+            return (games, total);
+        }
+        let regions: Option<HashSet<&str>> = query.region_filter.as_deref().map(|regions| {
+            regions
+                .split(',')
+                .map(str::trim)
+                .filter(|region| !region.is_empty())
+                .collect()
+        });
+        let mut matching: Vec<&OutgoingGameInfo> = games
+            .iter()
+            .filter(|game| {
+                name_filter
+                    .as_ref()
+                    .is_none_or(|name_filter| game.server_name.to_lowercase().contains(name_filter))
+            })
+            .filter(|game| {
+                query
+                    .version_filter
+                    .as_ref()
+                    .is_none_or(|version| game.version == *version)
+            })
+            .filter(|game| {
+                regions.as_ref().is_none_or(|regions| {
+                    game.region
+                        .as_deref()
+                        .is_some_and(|region| regions.contains(region))
+                })
+            })
+            .collect();
+        // The sorts are stable, so ties keep insertion order
+        match query.sort {
+            Some(SortBy::PlayersAsc) => matching.sort_by_key(|game| game.player_amount),
+            Some(SortBy::PlayersDesc) => {
+                matching.sort_by_key(|game| std::cmp::Reverse(game.player_amount))
+            }
+            Some(SortBy::Name) => {
+                matching.sort_by_cached_key(|game| game.server_name.to_lowercase())
+            }
+            Some(SortBy::Newest) => matching.sort_by_key(|game| std::cmp::Reverse(game.created_at)),
+            Some(SortBy::Oldest) => matching.sort_by_key(|game| game.created_at),
+            None => {}
+        }
+        if query.hide_full {
+            matching.sort_by_key(|game| game.player_amount >= game.max_players);
+        }
+        let total = matching.len();
+        let offset = query.offset.unwrap_or(0).min(total);
+        // Like with an empty query, every match is returned without a limit
+        let limit = query
+            .limit
+            .map_or(usize::MAX, |limit| limit.min(MAX_LIST_LIMIT));
+        let page = matching[offset..]
+            .iter()
+            .take(limit)
+            .map(|game| (*game).clone())
+            .collect();
+        (Arc::new(page), total)
+    }
+
+    fn name(&self, socket_id: &SocketId) -> Option<&String> {
+        self.names.get(socket_id)
+    }
+
+    // Host names are part of the listing, so renaming a host rebuilds it
+    fn set_name(&mut self, socket_id: &SocketId, name: Option<String>) {
+        if self.hosts.contains_key(socket_id) {
+            self.snapshot.take();
+        }
+        match name {
+            Some(name) => self.names.insert(socket_id.clone(), name),
+            None => self.names.remove(socket_id),
+        };
+    }
+
+    fn outgoing_info(&self, game: &Game) -> OutgoingGameInfo {
+        OutgoingGameInfo {
+            host_name: self.name(&game.host).cloned(),
+            ..game.outgoing_info()
+        }
+    }
+
+    fn snapshot(&self) -> Arc<Vec<OutgoingGameInfo>> {
+        self.snapshot
+            .get_or_init(|| {
+                Arc::new(
+                    self.games
+                        .values()
+                        .filter(|game| !game.game_info.private)
+                        .filter(|game| !self.hide_started || game.status == GameStatus::Open)
+                        .map(|game| self.outgoing_info(game))
+                        .collect(),
+                )
+            })
+            .clone()
+    }
+
+    // Picks the best scoring game the client could join right away
+    fn quick_match(
+        &self,
+        client: &SocketId,
+        filters: &GameFilters,
+        has_password: bool,
+        weights: QuickMatchWeights,
+    ) -> Option<GameId> {
+        self.games
+            .values()
+            .filter(|game| game.host != *client && !game.is_member(client))
+            .filter(|game| !game.game_info.private)
+            .filter(|game| game.game_info.join_policy != JoinPolicy::Closed)
+            .filter(|game| game.status == GameStatus::Open)
+            .filter(|game| has_password || !game.game_info.requires_password)
+            .filter(|game| game.occupancy().free_slots(game.game_info.max_players) > 0)
+            .filter(|game| filters.matches(game))
+            .map(|game| (game.quick_match_score(weights), game))
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, game)| game.game_id.clone())
+    }
+
+    fn get_game(&self, game_id: &GameId) -> Option<&Game> {
+        self.games.get(game_id)
+    }
+
+    fn get_game_by_host(&self, host: &SocketId) -> Option<&Game> {
+        self.hosts
+            .get(host)
+            .and_then(|game_id| self.games.get(game_id))
+            .filter(|game| game.host == *host)
+    }
+
+    // The host and accepted clients of the game, if the sender is one of them,
+    // along with the seq of the chat message. The seq isn't listed, so this
+    // leaves the snapshot intact.
+    fn chat_members(
+        &mut self,
+        game_id: &GameId,
+        sender: &SocketId,
+    ) -> Option<(Vec<SocketId>, u64)> {
+        self.games
+            .get_mut(game_id)
+            .filter(|game| game.host == *sender || game.clients.contains(sender))
+            .map(|game| {
+                let members = std::iter::once(&game.host)
+                    .chain(&game.clients)
+                    .cloned()
+                    .collect();
+                (members, game.next_seq())
+            })
+    }
+
+    fn get_game_by_client(&self, client: &SocketId) -> Option<&Game> {
+        self.members
+            .get(client)
+            .and_then(|game_id| self.games.get(game_id))
+            .filter(|game| game.is_member(client))
+    }
+
+    // Sockets are in one game at a time, as signaling is routed by the game
+    // found for the socket. Joining another game requires leaving the current
+    // one first.
+    fn in_other_game(&self, socket_id: &SocketId, game_id: &GameId) -> bool {
+        self.get_game_by_host(socket_id)
+            .into_iter()
+            .chain(self.get_game_by_client(socket_id))
+            .any(|game| game.game_id != *game_id)
+    }
+}
+
+struct SocketEntry {
+    tx: mpsc::Sender<OutgoingMessage>,
+    remote_addr: SocketAddr,
+    connected_at: SystemTime,
+}
+
+struct Sockets(HashMap<SocketId, SocketEntry>);
+
+impl Sockets {
+    fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    fn get(&self, socket_id: &SocketId) -> Option<mpsc::Sender<OutgoingMessage>> {
+        self.0.get(socket_id).map(|entry| entry.tx.clone())
+    }
+
+    fn get_entry(&self, socket_id: &SocketId) -> Option<&SocketEntry> {
+        self.0.get(socket_id)
+    }
+
+    fn ids(&self) -> Vec<SocketId> {
+        self.0.keys().cloned().collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn register(
+        &mut self,
+        id_format: &IdFormat,
+        buffer: usize,
+        remote_addr: SocketAddr,
+    ) -> (
+        SocketId,
+        mpsc::Receiver<OutgoingMessage>,
+        mpsc::WeakSender<OutgoingMessage>,
+    ) {
+        let (tx, rx) = mpsc::channel(buffer);
+        let id = SocketId::random(id_format);
+        let connection = tx.downgrade();
+        self.0.insert(
+            id.clone(),
+            SocketEntry {
+                tx,
+                remote_addr,
+                connected_at: SystemTime::now(),
+            },
+        );
+        (id, rx, connection)
+    }
+
+    fn unregister(&mut self, socket_id: &SocketId) {
+        self.0.remove(socket_id);
+    }
+
+    // Unregisters the socket unless a connection resuming its session has
+    // taken over the id
+    fn unregister_connection(
+        &mut self,
+        socket_id: &SocketId,
+        connection: &mpsc::WeakSender<OutgoingMessage>,
+    ) {
+        let owned = match (self.0.get(socket_id), connection.upgrade()) {
+            (Some(entry), Some(tx)) => entry.tx.same_channel(&tx),
+            _ => false,
+        };
+        if owned {
+            self.0.remove(socket_id);
+        }
+    }
+
+    // Moves the connection registered as `from` to `to`
+    fn rename(&mut self, from: &SocketId, to: SocketId) {
+        if let Some(entry) = self.0.remove(from) {
+            self.0.insert(to, entry);
+        }
+    }
+}
+
+struct Session {
+    token: ResumeToken,
+    // When the connection was lost, None while connected
+    disconnected_at: Option<Instant>,
+}
+
+// Resume tokens of sockets, kept for `resume_grace` after they disconnect so
+// that a new connection can take their place
+struct Sessions {
+    sessions: HashMap<SocketId, Session>,
+    tokens: HashMap<ResumeToken, SocketId>,
+}
+
+impl Sessions {
+    fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+            tokens: HashMap::new(),
+        }
+    }
+
+    fn issue(&mut self, socket_id: &SocketId) -> ResumeToken {
+        let token = ResumeToken::random();
+        self.tokens.insert(token.clone(), socket_id.clone());
+        self.sessions.insert(
+            socket_id.clone(),
+            Session {
+                token: token.clone(),
+                disconnected_at: None,
+            },
+        );
+        token
+    }
+
+    fn token(&self, socket_id: &SocketId) -> Option<&ResumeToken> {
+        self.sessions.get(socket_id).map(|session| &session.token)
+    }
+
+    // Adds a saved session as disconnected now, returning the time of
+    // disconnect to expire it with
+    fn restore(&mut self, socket_id: &SocketId, token: ResumeToken) -> Instant {
+        let now = Instant::now();
+        self.tokens.insert(token.clone(), socket_id.clone());
+        self.sessions.insert(
+            socket_id.clone(),
+            Session {
+                token,
+                disconnected_at: Some(now),
+            },
+        );
+        now
+    }
+
+    fn remove(&mut self, socket_id: &SocketId) {
+        if let Some(session) = self.sessions.remove(socket_id) {
+            self.tokens.remove(&session.token);
+        }
+    }
+
+    // Returns the time of disconnect to expire the session with, None if the
+    // socket has no session
+    fn disconnect(&mut self, socket_id: &SocketId) -> Option<Instant> {
+        let session = self.sessions.get_mut(socket_id)?;
+        let now = Instant::now();
+        session.disconnected_at = Some(now);
+        Some(now)
+    }
+
+    // Removes the session unless it was resumed after disconnecting at
+    // `disconnected_at`. Returns whether it was removed.
+    fn expire(&mut self, socket_id: &SocketId, disconnected_at: Instant) -> bool {
+        let expired = self
+            .sessions
+            .get(socket_id)
+            .is_some_and(|session| session.disconnected_at == Some(disconnected_at));
+        if expired {
+            self.remove(socket_id);
+        }
+        expired
+    }
+
+    // Hands the disconnected session of `token` to the connection registered
+    // as `socket_id`, whose own session is dropped. Returns the resumed
+    // socket id and a fresh token for it.
+    fn resume(
+        &mut self,
+        token: &ResumeToken,
+        socket_id: &SocketId,
+    ) -> Option<(SocketId, ResumeToken)> {
+        let resumed = self.tokens.get(token)?.clone();
+        // Connected sockets can't be taken over
+        self.sessions.get(&resumed)?.disconnected_at?;
+        self.remove(socket_id);
+        self.remove(&resumed);
+        let token = self.issue(&resumed);
+        Some((resumed, token))
+    }
+}
+
+// Cleans up after a socket whose session wasn't resumed in time
+async fn expire_session(app_state: AppState, socket_id: SocketId, disconnected_at: Instant) {
+    let Some(grace) = app_state.config.resume_grace else {
+        return;
+    };
+    tokio::time::sleep(grace).await;
+    if app_state.lock_sessions(|sessions| sessions.expire(&socket_id, disconnected_at)) {
+        info!(socket_id = %socket_id.0, "Session expired");
+        app_state.disconnect(&socket_id);
+    }
+}
+
+async fn reject_websocket(mut socket: WebSocket) {
+    warn!("Rejecting connection, at the connection limit");
+    // Closing is best effort, the client is gone either way
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::AGAIN,
+            reason: "Too many connections".into(),
+        })))
+        .await;
+}
+
+async fn handle_websocket(socket: WebSocket, addr: SocketAddr, app_state: AppState) {
+    let config = &app_state.config;
+    let (socket_id, rx, connection) = app_state.lock_sockets(|sockets| {
+        sockets.register(&config.socket_id_format, config.socket_buffer, addr)
+    });
+    // Everything logged for the connection carries its socket id, updated if
+    // it resumes another session
+    let span = info_span!("socket", socket_id = %socket_id.0);
+    run_websocket(socket, addr, app_state, socket_id, rx, connection)
+        .instrument(span)
+        .await
+}
+
+async fn run_websocket(
+    socket: WebSocket,
+    addr: SocketAddr,
+    app_state: AppState,
+    socket_id: SocketId,
+    rx: mpsc::Receiver<OutgoingMessage>,
+    connection: mpsc::WeakSender<OutgoingMessage>,
+) {
+    info!(%addr, "Connected");
+    app_state.publish(LobbyEvent::Connected {
+        socket_id: socket_id.clone(),
+        remote_addr: addr,
+    });
+
+    let wire_format = (socket.protocol().map(|protocol| protocol.as_bytes())
+        == Some(MESSAGE_PACK_SUBPROTOCOL.as_bytes()))
+    .then_some(WireFormat::MessagePack);
+    let mut client = SocketState {
+        socket_id,
+        socket,
+        region_hint: app_state.config.infer_region(addr.ip()),
+        deprecation_warnings: HashSet::new(),
+        received_bytes: 0,
+        unanswered_pings: 0,
+        ping_seq: 0,
+        pending_ping: None,
+        idle_deadline: tokio::time::Instant::now() + app_state.config.idle_timeout,
+        rate_limiter: app_state.config.rate_limit.map(RateLimiter::new),
+        wire_format,
+        app_state: app_state.clone(),
+        rx,
+    };
+    let resume_token = app_state
+        .config
+        .resume_grace
+        .map(|_| app_state.lock_sessions(|sessions| sessions.issue(&client.socket_id)));
+    let welcomed = async {
+        let welcome = OutgoingMessage::Welcome {
+            socket_id: client.socket_id.clone(),
+            resume_token,
+        };
+        client.send(welcome).await?;
+        client.send(server_hello(&app_state.config)).await
+    }
+    .await;
+    match welcomed {
+        Ok(()) => client.run().await,
+        Err(_) => client.send_failed().await,
+    }
+
+    app_state.lock_sockets(|sockets| sockets.unregister_connection(&client.socket_id, &connection));
+    info!("Disconnected");
+    app_state.publish(LobbyEvent::Disconnected {
+        socket_id: client.socket_id,
+    });
+}
+
+fn server_hello(config: &Config) -> OutgoingMessage {
+    let mut features = vec![
+        "spectators",
+        "chat",
+        "listSubscriptions",
+        "messagePack",
+        "mesh",
+    ];
+    if config.resume_grace.is_some() {
+        features.push("resume");
+    }
+    if config.state_file.is_some() {
+        features.push("persistence");
+    }
+    if config.turn.is_some() {
+        features.push("turn");
+    }
+    OutgoingMessage::ServerHello {
+        version: env!("CARGO_PKG_VERSION"),
+        max_players_limit: *config.max_players_range.end(),
+        features,
+    }
+}
+
+// Token bucket allowing `rate` messages per second in bursts of up to `rate`
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    // Whether the client has been told about dropped messages since the last
+    // accepted one
+    warned: bool,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+            warned: false,
+        }
+    }
+
+    // Returns false if the message should be dropped
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        self.warned = false;
+        true
+    }
+}
+
+// Consecutive heartbeat pings a socket may leave unanswered before it's
+// considered dead
+const MAX_UNANSWERED_PINGS: u32 = 2;
+
+struct SocketState {
+    socket_id: SocketId,
+    socket: WebSocket,
+    // Region inferred from the remote address, used for games created without one
+    region_hint: Option<String>,
+    // Deprecated message types this socket has already been warned about
+    deprecation_warnings: HashSet<&'static str>,
+    // Payload bytes received over the connection's lifetime
+    received_bytes: u64,
+    // Heartbeat pings sent since the last message from the client
+    unanswered_pings: u32,
+    ping_seq: u64,
+    // Sequence number carried by the latest ping and when it was sent. A newer
+    // ping replaces it, so a late pong isn't reported as a stale latency.
+    pending_ping: Option<(u64, Instant)>,
+    // Sockets outside games are closed if they send no valid message by this
+    idle_deadline: tokio::time::Instant,
+    rate_limiter: Option<RateLimiter>,
+    // Set by the negotiated subprotocol or the first message, replies are JSON
+    // until then
+    wire_format: Option<WireFormat>,
+    app_state: AppState,
+    rx: mpsc::Receiver<OutgoingMessage>,
+}
+
+impl SocketState {
+    async fn run(&mut self) {
+        let period = self.app_state.config.heartbeat_interval;
+        let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            select! {
+                msg = self.socket.recv() => match self.handle_message(msg).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(_) => {
+                        self.send_failed().await;
+                        break;
+                    }
+                },
+                outgoing = self.rx.recv() => {
+                    // The sender is only dropped when the socket falls behind
+                    let Some(outgoing) = outgoing else {
+                        self.close(close_code::POLICY, "Too slow to receive messages").await;
+                        break;
+                    };
+                    let shutdown = matches!(outgoing, OutgoingMessage::ServerShutdown { .. });
+                    if self.send(outgoing).await.is_err() {
+                        self.send_failed().await;
+                        break;
+                    }
+                    if shutdown {
+                        self.close(close_code::AWAY, "Server shutting down").await;
+                        break;
+                    }
+                },
+                _ = heartbeat.tick() => {
+                    if !self.heartbeat().await {
+                        break;
+                    }
+                },
+                _ = tokio::time::sleep_until(self.idle_deadline) => {
+                    if !self.idle_timeout().await {
+                        break;
+                    }
+                },
+                else => break
+            }
+        }
+    }
+
+    // Sends a close frame and cleans up after the socket
+    async fn close(&mut self, code: u16, reason: &'static str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        // The client may already be gone
+        let _ = self.socket.send(Message::Close(Some(frame))).await;
+        self.disconnect();
+    }
+
+    // Returns true if the socket should continue to run
+    async fn idle_timeout(&mut self) -> bool {
+        let socket_id = &self.socket_id;
+        let in_game = self.app_state.read_games(|games| {
+            games.get_game_by_host(socket_id).is_some()
+                || games.get_game_by_client(socket_id).is_some()
+        });
+        if in_game {
+            self.reset_idle_deadline();
+            return true;
+        }
+        info!("Closing idle socket");
+        self.close(close_code::NORMAL, "Idle timeout").await;
+        false
+    }
+
+    fn reset_idle_deadline(&mut self) {
+        self.idle_deadline = tokio::time::Instant::now() + self.app_state.config.idle_timeout;
+    }
+
+    // Returns true if the socket should continue to run
+    async fn heartbeat(&mut self) -> bool {
+        if self.unanswered_pings >= MAX_UNANSWERED_PINGS {
+            info!("Socket stopped answering pings");
+            self.disconnect();
+            return false;
+        }
+        self.unanswered_pings += 1;
+        self.ping_seq += 1;
+        self.pending_ping = Some((self.ping_seq, Instant::now()));
+        // A failed send means the connection is gone, recv will report it
+        let _ = self
+            .socket
+            .send(Message::Ping(self.ping_seq.to_be_bytes().to_vec()))
+            .await;
+        true
+    }
+
+    // Round trip time if the pong answers the latest ping
+    fn pong_latency(&mut self, payload: &[u8]) -> Option<Duration> {
+        let (seq, sent_at) = self.pending_ping?;
+        if payload != seq.to_be_bytes() {
+            return None;
+        }
+        self.pending_ping = None;
+        Some(sent_at.elapsed())
+    }
+
+    // Returns true if the socket should continue to run, or an error if
+    // writing to it failed
+    async fn handle_message(
+        &mut self,
+        message: Option<Result<Message, Error>>,
+    ) -> Result<bool, Error> {
+        let Some(message) = message else {
+            self.disconnect();
+            return Ok(false);
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(err) if is_invalid_encoding(&err) => {
+                warn!("Received text message with invalid UTF-8");
+                self.send(OutgoingMessage::error(ErrorCode::InvalidEncoding))
+                    .await?;
+                // The transport stops reading after the error, so the socket is
+                // closed once the client has been told why
+                self.close(close_code::INVALID, "Invalid UTF-8").await;
+                return Ok(false);
+            }
+            Err(err) => {
+                warn!(%err, "Error receiving websocket message");
+                return Ok(true);
+            }
+        };
+        // Any message shows the connection is alive, not just pongs
+        self.unanswered_pings = 0;
+        if let Message::Pong(payload) = &message {
+            if let Some(latency) = self.pong_latency(payload) {
+                self.send(OutgoingMessage::Latency {
+                    ms: latency.as_millis() as u64,
+                })
+                .await?;
+            }
+            return Ok(true);
+        }
+        self.received_bytes += payload_len(&message) as u64;
+        if let Some(max_connection_bytes) = self.app_state.config.max_connection_bytes {
+            if self.received_bytes > max_connection_bytes {
+                warn!(
+                    received_bytes = self.received_bytes,
+                    "Closing socket over the connection byte limit"
+                );
+                self.close(close_code::POLICY, "Connection byte limit exceeded")
+                    .await;
+                return Ok(false);
+            }
+        }
+        let (format, data) = match &message {
+            Message::Text(text) => (WireFormat::Json, text.as_bytes()),
+            Message::Binary(data) => (WireFormat::MessagePack, data.as_slice()),
+            // The transport already queued the pong reply, which is flushed
+            // with the next write
+            Message::Ping(_) => return Ok(true),
+            // Pongs were handled above
+            Message::Pong(_) => return Ok(true),
+            // The games are cleaned up right away. Reading on flushes the
+            // close reply the transport queued, after which the stream ends.
+            Message::Close(frame) => {
+                debug!(code = ?frame.as_ref().map(|frame| frame.code), "Received close frame");
+                self.disconnect();
+                while let Some(Ok(_)) = self.socket.recv().await {}
+                return Ok(false);
+            }
+        };
+        if let Some(rate_limiter) = &mut self.rate_limiter {
+            if !rate_limiter.try_acquire() {
+                // Only the first dropped message of a burst is answered
+                if !rate_limiter.warned {
+                    rate_limiter.warned = true;
+                    self.send(OutgoingMessage::error(ErrorCode::RateLimited))
+                        .await?;
+                }
+                return Ok(true);
+            }
+        }
+        if data.len() > self.app_state.config.max_message_bytes {
+            warn!(bytes = data.len(), "Received message over the size limit");
+            self.send(OutgoingMessage::error(ErrorCode::MessageTooLarge))
+                .await?;
+            return Ok(true);
+        }
+        if data.is_empty() {
+            debug!("Received empty message");
+        } else {
+            match self.wire_format {
+                None => self.wire_format = Some(format),
+                Some(wire_format) if wire_format != format => {
+                    warn!(?format, expected = ?wire_format, "Received message in the wrong format");
+                    self.send(OutgoingMessage::error(ErrorCode::InvalidMessage))
+                        .await?;
+                    return Ok(true);
+                }
+                Some(_) => {}
+            }
+            // Binary messages aren't worth logging as is
+            let logged = match &message {
+                Message::Text(text) => text.as_str(),
+                _ => "<binary>",
+            };
+            debug!(data = logged, "Received message");
+            let incoming_message = match format.decode(data) {
+                Ok(incoming) => {
+                    self.reset_idle_deadline();
+                    incoming
+                }
+                Err(err) => {
+                    warn!(%err, data = logged, "Invalid message");
+                    self.send(OutgoingMessage::Error {
+                        code: ErrorCode::InvalidMessage,
+                        reason: format!("Invalid message: {}", err),
+                    })
+                    .await?;
+                    return Ok(true);
+                }
+            };
+
+            if let Some(deprecation) = incoming_message.deprecation() {
+                if self.deprecation_warnings.insert(deprecation.message_type) {
+                    self.send(OutgoingMessage::Deprecation {
+                        message_type: deprecation.message_type,
+                        replacement: deprecation.replacement,
+                    })
+                    .await?;
+                }
+            }
+
+            if let IncomingMessage::Resume { token, last_seq } = incoming_message {
+                self.resume(token, last_seq).await?;
+                return Ok(true);
+            }
+
+            let (
+                MessagesToSend {
+                    self_message,
+                    other_messages,
+                    events,
+                    liveness_check,
+                    list_subscription,
+                },
+                list_changes,
+            ) = if incoming_message.is_read_only() {
+                let messages = self.app_state.read_games(|games| {
+                    process_read_only_message(
+                        &self.socket_id,
+                        &self.app_state.config,
+                        &self.app_state.metrics,
+                        games,
+                        incoming_message,
+                    )
+                });
+                (messages, None)
+            } else {
+                self.app_state.write_games_watching_list(|games| {
+                    let messages = process_incoming_message(
+                        &self.socket_id,
+                        &self.app_state.config,
+                        &self.app_state.metrics,
+                        self.region_hint.as_ref(),
+                        games,
+                        incoming_message,
+                    );
+                    games.record_events(&messages.events);
+                    messages
+                })
+            };
+            if let Some(subscribed) = list_subscription {
+                self.app_state.lock_list_subscribers(|subscribers| {
+                    if subscribed {
+                        subscribers.insert(self.socket_id.clone());
+                    } else {
+                        subscribers.remove(&self.socket_id);
+                    }
+                });
+            }
+
+            // Others are notified even if replying to this socket fails
+            let sent = match self_message {
+                Some(outgoing) => self.send(outgoing).await,
+                None => Ok(()),
+            };
+            self.app_state.deliver(other_messages, events, list_changes);
+            if let Some(game_id) = liveness_check {
+                tokio::spawn(finish_liveness_check(self.app_state.clone(), game_id));
+            }
+            sent?;
+        }
+        Ok(true)
+    }
+
+    // Cleanup is deferred while the socket's session can be resumed
+    fn disconnect(&mut self) {
+        self.app_state
+            .lock_list_subscribers(|subscribers| subscribers.remove(&self.socket_id));
+        match self.app_state.suspend(&self.socket_id) {
+            Some(disconnected_at) => {
+                tokio::spawn(expire_session(
+                    self.app_state.clone(),
+                    self.socket_id.clone(),
+                    disconnected_at,
+                ));
+            }
+            None => self.app_state.disconnect(&self.socket_id),
+        }
+    }
+
+    // Takes over the game memberships of a disconnected socket. Only fresh
+    // connections can resume, so the two sockets' games never need merging.
+    async fn resume(&mut self, token: ResumeToken, last_seq: Option<u64>) -> Result<(), Error> {
+        let socket_id = &self.socket_id;
+        let in_game = self.app_state.read_games(|games| {
+            games.get_game_by_host(socket_id).is_some()
+                || games.get_game_by_client(socket_id).is_some()
+        });
+        if in_game {
+            return self
+                .send(OutgoingMessage::error(ErrorCode::AlreadyJoined))
+                .await;
+        }
+        let Some((resumed, resume_token)) = self.app_state.resume_session(&token, socket_id) else {
+            return self
+                .send(OutgoingMessage::error(ErrorCode::InvalidResumeToken))
+                .await;
+        };
+        info!(resumed = %resumed.0, "Socket resumed session");
+        tracing::Span::current().record("socket_id", tracing::field::display(&resumed.0));
+        self.socket_id = resumed.clone();
+        let resumed = self
+            .app_state
+            .read_games(|games| resumed_message(games, resumed, resume_token, last_seq));
+        self.send(resumed).await
+    }
+
+    async fn send_failed(&mut self) {
+        warn!("Failed to send, disconnecting");
+        self.disconnect();
+    }
+
+    async fn send(&mut self, message: OutgoingMessage) -> Result<(), Error> {
+        let format = self.wire_format.unwrap_or(WireFormat::Json);
+        self.socket.send(format.encode(&message)).await
+    }
+}
+
+fn payload_len(message: &Message) -> usize {
+    match message {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(frame) => frame.as_ref().map_or(0, |frame| frame.reason.len()),
+    }
+}
+
+// Text frames are validated as UTF-8 by the transport, which reports failures
+// as receive errors
+fn is_invalid_encoding(err: &Error) -> bool {
+    matches!(
+        std::error::Error::source(err).and_then(|source| source.downcast_ref()),
+        Some(tungstenite::Error::Utf8)
+    )
+}
+
+struct MessagesToSend {
+    self_message: Option<OutgoingMessage>,
+    other_messages: Vec<(SocketId, OutgoingMessage)>,
+    events: Vec<LobbyEvent>,
+    // Game whose liveness check was started and should be finished after the
+    // timeout
+    liveness_check: Option<GameId>,
+    // Whether the socket subscribed to or unsubscribed from game list updates
+    list_subscription: Option<bool>,
+}
+
+impl MessagesToSend {
+    fn self_(message: OutgoingMessage) -> Self {
+        Self {
+            self_message: Some(message),
+            other_messages: Vec::new(),
+            events: Vec::new(),
+            liveness_check: None,
+            list_subscription: None,
+        }
+    }
+
+    fn other(id: SocketId, message: OutgoingMessage) -> Self {
+        Self {
+            self_message: None,
+            other_messages: vec![(id, message)],
+            events: Vec::new(),
+            liveness_check: None,
+            list_subscription: None,
+        }
+    }
+
+    fn none() -> Self {
+        Self {
+            self_message: None,
+            other_messages: Vec::new(),
+            events: Vec::new(),
+            liveness_check: None,
+            list_subscription: None,
+        }
+    }
+
+    fn with_other(mut self, id: SocketId, message: OutgoingMessage) -> Self {
+        self.other_messages.push((id, message));
+        self
+    }
+
+    fn with_event(mut self, event: LobbyEvent) -> Self {
+        self.events.push(event);
+        self
+    }
+
+    fn with_liveness_check(mut self, game_id: GameId) -> Self {
+        self.liveness_check = Some(game_id);
+        self
+    }
+
+    fn with_list_subscription(mut self, subscribed: bool) -> Self {
+        self.list_subscription = Some(subscribed);
+        self
+    }
+}
+
+// Closes games left idle longer than `timeout`, checking every half of it
+async fn close_stale_games(app_state: AppState, timeout: Duration) {
+    let period = timeout / 2;
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        for game_id in app_state.read_games(|games| games.stale_games(timeout)) {
+            info!(game_id = %game_id.0, "Closing stale game");
+            app_state.close_game(&game_id, "idle");
+        }
+    }
+}
+
+// Reports the clients that answered a PingClients to the host once the
+// timeout has passed
+async fn finish_liveness_check(app_state: AppState, game_id: GameId) {
+    tokio::time::sleep(app_state.config.liveness_timeout).await;
+    let Some((host, message)) =
+        app_state.write_games(|games| games.finish_liveness_check(&game_id))
+    else {
+        return;
+    };
+    app_state.send_to(&host, message);
+}
+
+fn process_read_only_message(
+    socket_id: &SocketId,
+    config: &Config,
+    metrics: &Metrics,
+    games: &Games,
+    message: IncomingMessage,
+) -> MessagesToSend {
+    metrics.record_message(message.message_type());
+    games.touch(socket_id);
+    answer_read_only_message(socket_id, config, games, message)
+}
+
+fn answer_read_only_message(
+    socket_id: &SocketId,
+    config: &Config,
+    games: &Games,
+    message: IncomingMessage,
+) -> MessagesToSend {
+    match message {
+        IncomingMessage::WebrtcSignaling {
+            client_id: target_socket_id,
+            description,
+            candidate,
+        } => {
+            let mut elements = config.max_signaling_elements;
+            let within_limits = [&description, &candidate]
+                .into_iter()
+                .flatten()
+                .all(|value| is_within_limits(value, config.max_signaling_depth, &mut elements));
+            if !within_limits {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::SignalingTooLarge));
+            }
+            if let Some(target_socket_id) = target_socket_id {
+                // WebRTC signaling from host -> send to client of the game
+                if let Some(game) = games
+                    .get_game_by_host(socket_id)
+                    .filter(|game| game.is_member(&target_socket_id))
+                {
+                    MessagesToSend::other(
+                        target_socket_id,
+                        OutgoingMessage::WebrtcSignaling {
+                            game_id: game.game_id.clone(),
+                            client_id: None,
+                            description,
+                            candidate,
+                        },
+                    )
+                } else if let Some(game) = games.get_game_by_client(socket_id).filter(|game| {
+                    game.game_info.mesh
+                        && game.clients.contains(socket_id)
+                        && game.clients.contains(&target_socket_id)
+                }) {
+                    // WebRTC signaling between clients of a mesh game -> send
+                    // to the other client
+                    MessagesToSend::other(
+                        target_socket_id,
+                        OutgoingMessage::WebrtcSignaling {
+                            game_id: game.game_id.clone(),
+                            client_id: Some(socket_id.clone()),
+                            description,
+                            candidate,
+                        },
+                    )
+                } else {
+                    MessagesToSend::none()
+                }
+            } else if let Some(game) = games.get_game_by_client(socket_id) {
+                // WebRTC signaling from client -> send to host
+                MessagesToSend::other(
+                    game.host.clone(),
+                    OutgoingMessage::WebrtcSignaling {
+                        game_id: game.game_id.clone(),
+                        client_id: Some(socket_id.clone()),
+                        description,
+                        candidate,
+                    },
+                )
+            } else {
+                MessagesToSend::none()
+            }
+        }
+        IncomingMessage::ListGames { query } => {
+            let (games, total) = games.list(&query);
+            MessagesToSend::self_(OutgoingMessage::GameList { games, total })
+        }
+        IncomingMessage::GetGame { game_id } => {
+            MessagesToSend::self_(match games.get_game(&game_id) {
+                Some(game) => OutgoingMessage::GameInfo {
+                    game: games.outgoing_info(game),
+                },
+                None => OutgoingMessage::error(ErrorCode::GameNotFound),
+            })
+        }
+        IncomingMessage::ListClients { game_id } => MessagesToSend::self_(
+            match games
+                .get_game(&game_id)
+                .filter(|game| game.host == *socket_id)
+            {
+                Some(game) => OutgoingMessage::ClientList {
+                    game_id,
+                    clients: game
+                        .clients
+                        .iter()
+                        .map(|client_id| ListedClient {
+                            client_id: client_id.clone(),
+                            name: games.name(client_id).cloned(),
+                        })
+                        .collect(),
+                },
+                None => OutgoingMessage::error(ErrorCode::NotHost),
+            },
+        ),
+        IncomingMessage::Broadcast { game_id, payload } => {
+            let Some(game) = games
+                .get_game(&game_id)
+                .filter(|game| game.host == *socket_id)
+            else {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::NotHost));
+            };
+            if serde_json::to_vec(&payload).map_or(true, |json| json.len() > MAX_BROADCAST_BYTES) {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::PayloadTooLarge));
+            }
+            game.clients
+                .iter()
+                .fold(MessagesToSend::none(), |messages, client| {
+                    messages.with_other(
+                        client.clone(),
+                        OutgoingMessage::HostBroadcast {
+                            game_id: game_id.clone(),
+                            payload: payload.clone(),
+                        },
+                    )
+                })
+        }
+        // The current list is sent right away, changes to it as they happen
+        IncomingMessage::SubscribeGameList => {
+            MessagesToSend::self_(OutgoingMessage::GameListUpdated {
+                games: games.snapshot(),
+            })
+            .with_list_subscription(true)
+        }
+        IncomingMessage::UnsubscribeGameList => {
+            MessagesToSend::none().with_list_subscription(false)
+        }
+        // Only called for read-only messages
+        _ => MessagesToSend::none(),
+    }
+}
+
+fn process_incoming_message(
+    socket_id: &SocketId,
+    config: &Config,
+    metrics: &Metrics,
+    region_hint: Option<&String>,
+    games: &mut Games,
+    message: IncomingMessage,
+) -> MessagesToSend {
+    metrics.record_message(message.message_type());
+    games.touch(socket_id);
+    match message {
+        message @ (IncomingMessage::WebrtcSignaling { .. }
+        | IncomingMessage::ListGames { .. }
+        | IncomingMessage::GetGame { .. }
+        | IncomingMessage::ListClients { .. }
+        | IncomingMessage::Broadcast { .. }
+        | IncomingMessage::SubscribeGameList
+        | IncomingMessage::UnsubscribeGameList) => {
+            answer_read_only_message(socket_id, config, games, message)
+        }
+        IncomingMessage::CreateGame {
+            game_id,
+            server_name,
+            max_players,
+            requires_password,
+            region,
+            join_policy,
+            password,
+            migratable,
+            version,
+            private,
+            mesh,
+            metadata,
+        } => {
+            // Hosts are looked up by socket, so each socket hosts at most one
+            // game. The existing game has to be closed by disconnecting.
+            if games.get_game_by_host(socket_id).is_some() {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::AlreadyHosting));
+            }
+            // Members would end up in two games, one of them as the host
+            if games.get_game_by_client(socket_id).is_some() {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::AlreadyInGame));
+            }
+            let Some(server_name) = validate_server_name(&server_name) else {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidServerName));
+            };
+            if !config.max_players_range.contains(&max_players) {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidMaxPlayers));
+            }
+            let join_policy = join_policy.unwrap_or_default();
+            if join_policy == JoinPolicy::Password && password.is_none() {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::PasswordRequired));
+            }
+            let metadata = metadata.unwrap_or_default();
+            if !valid_metadata(&metadata) {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidMetadata));
+            }
+            if game_id
+                .as_ref()
+                .is_some_and(|game_id| games.get_game(game_id).is_some())
+            {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::GameIdTaken));
+            }
+            // Generated before evicting so that a failure leaves the games as
+            // they were
+            let Some(game_id) = game_id.or_else(|| {
+                std::iter::repeat_with(|| GameId::random(&config.game_id_format))
+                    .take(MAX_GAME_ID_ATTEMPTS)
+                    .find(|game_id| games.get_game(game_id).is_none())
+            }) else {
+                warn!("Failed to generate a free game id");
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::ServerFull));
+            };
+            let mut evicted = None;
+            if config
+                .max_games
+                .is_some_and(|max_games| games.len() >= max_games)
+            {
+                evicted = config
+                    .evict_idle_games
+                    .then(|| games.evict_idle_game())
+                    .flatten();
+                if evicted.is_none() {
+                    return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::ServerFull));
+                }
+            }
+            games.add(Game {
+                game_id: game_id.clone(),
+                host: socket_id.clone(),
+                clients: HashSet::new(),
+                pending: HashSet::new(),
+                spectators: HashSet::new(),
+                co_hosts: HashSet::new(),
+                reservations: HashSet::new(),
+                pending_invites: HashMap::new(),
+                event_seq: 0,
+                created_at: SystemTime::now(),
+                last_activity: Mutex::new(Instant::now()),
+                history: VecDeque::new(),
+                liveness_check: None,
+                ready: HashSet::new(),
+                suspended: HashSet::new(),
+                status: GameStatus::Open,
+                game_info: GameInfo {
+                    server_name,
+                    max_players,
+                    requires_password: requires_password.unwrap_or(password.is_some())
+                        || join_policy == JoinPolicy::Password,
+                    region: region.or_else(|| region_hint.cloned()),
+                    join_policy,
+                    password,
+                    migratable: migratable.unwrap_or(false),
+                    version: version.unwrap_or_else(|| "unknown".to_string()),
+                    private: private.unwrap_or(false),
+                    mesh: mesh.unwrap_or(false),
+                    metadata,
+                },
+            });
+            let mut messages = evicted.map_or_else(MessagesToSend::none, |game| {
+                let host = game.host.clone();
+                let evicted_game_id = game.game_id.clone();
+                game_closed_messages(game, "evicted").with_other(
+                    host,
+                    OutgoingMessage::GameClosed {
+                        game_id: evicted_game_id,
+                        reason: "evicted".to_string(),
+                    },
+                )
+            });
+            messages.self_message = Some(OutgoingMessage::GameCreated {
+                game_id: game_id.clone(),
+                ice_servers: turn::ice_servers(config.turn.as_ref(), socket_id),
+            });
+            messages.with_event(LobbyEvent::GameCreated {
+                game_id,
+                host: socket_id.clone(),
+            })
+        }
+        IncomingMessage::UpdateGameInfo {
+            max_players,
+            server_name,
+            requires_password,
+            metadata,
+        } => {
+            let Some(server_name) = validate_server_name(&server_name) else {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidServerName));
+            };
+            if !valid_max_players(config, games, socket_id, max_players) {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidMaxPlayers));
+            }
+            if metadata
+                .as_ref()
+                .is_some_and(|metadata| !valid_metadata(metadata))
+            {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidMetadata));
+            }
+            match games.update_info(socket_id, |info| {
+                info.server_name = server_name;
+                info.max_players = max_players;
+                if let Some(requires_password) = requires_password {
+                    info.requires_password = requires_password;
+                }
+                if let Some(metadata) = metadata {
+                    info.metadata = metadata;
+                }
+            }) {
+                Some((game_id, seq)) => info_updated_messages(games, socket_id, &game_id, seq),
+                None => MessagesToSend::self_(OutgoingMessage::error(ErrorCode::NotHost)),
+            }
+        }
+        IncomingMessage::PatchGameInfo {
+            max_players,
+            server_name,
+            requires_password,
+            password,
+            metadata,
+        } => {
+            let server_name = match server_name.as_deref().map(validate_server_name) {
+                Some(None) => {
+                    return MessagesToSend::self_(OutgoingMessage::error(
+                        ErrorCode::InvalidServerName,
+                    ))
+                }
+                Some(Some(server_name)) => Some(server_name),
+                None => None,
+            };
+            if max_players.is_some_and(|max_players| {
+                !valid_max_players(config, games, socket_id, max_players)
+            }) {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidMaxPlayers));
+            }
+            if metadata
+                .as_ref()
+                .is_some_and(|metadata| !valid_metadata(metadata))
+            {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidMetadata));
+            }
+            match games.update_info(socket_id, |info| {
+                if let Some(server_name) = server_name {
+                    info.server_name = server_name;
+                }
+                if let Some(max_players) = max_players {
+                    info.max_players = max_players;
+                }
+                if let Some(requires_password) = requires_password {
+                    info.requires_password = requires_password;
+                }
+                if let Some(password) = password {
+                    info.password = Some(password);
+                }
+                if let Some(metadata) = metadata {
+                    info.metadata = metadata;
+                }
+            }) {
+                Some((game_id, seq)) => info_updated_messages(games, socket_id, &game_id, seq),
+                None => MessagesToSend::self_(OutgoingMessage::error(ErrorCode::NotHost)),
+            }
+        }
+        // Handled by the socket, which owns the connection being resumed
+        IncomingMessage::Resume { .. } => MessagesToSend::none(),
+        IncomingMessage::Capabilities => MessagesToSend::self_(OutgoingMessage::Capabilities {
+            incoming: INCOMING_MESSAGE_TYPES,
+            outgoing: OUTGOING_MESSAGE_TYPES,
+            protocol_version: PROTOCOL_VERSION,
+        }),
+        IncomingMessage::JoinGame {
+            game_id,
+            password,
+            invite_token,
+        } => join_game(
+            socket_id,
+            config,
+            games,
+            metrics,
+            game_id,
+            password,
+            invite_token,
+        ),
+        IncomingMessage::JoinAsSpectator { game_id, password } => {
+            match games.join_as_spectator(&game_id, socket_id, password.as_deref()) {
+                Ok((host, seq)) => MessagesToSend::self_(OutgoingMessage::AcceptJoin {
+                    game_id: game_id.clone(),
+                    seq,
+                    ice_servers: turn::ice_servers(config.turn.as_ref(), socket_id),
+                })
+                .with_other(
+                    host,
+                    OutgoingMessage::NewSpectator {
+                        game_id: game_id.clone(),
+                        client_id: socket_id.clone(),
+                        seq,
+                    },
+                )
+                .with_event(LobbyEvent::SpectatorJoined {
+                    game_id,
+                    client_id: socket_id.clone(),
+                }),
+                Err(err) => MessagesToSend::self_(OutgoingMessage::error(err.into())),
+            }
+        }
+        IncomingMessage::QuickMatch { filters, password } => {
+            match games.quick_match(
+                socket_id,
+                &filters,
+                password.is_some(),
+                config.quick_match_weights,
+            ) {
+                Some(game_id) => {
+                    join_game(socket_id, config, games, metrics, game_id, password, None)
+                }
+                None => MessagesToSend::self_(OutgoingMessage::NoMatch),
+            }
+        }
+        IncomingMessage::AcceptJoin {
+            game_id,
+            client_id: accepted_socket_id,
+        } => match games.accept_client(socket_id, &game_id, &accepted_socket_id) {
+            Err(err) => MessagesToSend::self_(OutgoingMessage::error(err.into())),
+            Ok((host, seq)) => {
+                let mut messages = with_mesh_peers(
+                    MessagesToSend::other(
+                        accepted_socket_id.clone(),
+                        OutgoingMessage::AcceptJoin {
+                            game_id: game_id.clone(),
+                            seq,
+                            ice_servers: turn::ice_servers(
+                                config.turn.as_ref(),
+                                &accepted_socket_id,
+                            ),
+                        },
+                    ),
+                    games,
+                    &game_id,
+                    &accepted_socket_id,
+                );
+                // The host connects to the client, so it has to know when a
+                // co-host accepted it
+                if host != *socket_id {
+                    messages = messages.with_other(
+                        host,
+                        OutgoingMessage::NewClient {
+                            game_id: game_id.clone(),
+                            client_id: accepted_socket_id.clone(),
+                            name: games.name(&accepted_socket_id).cloned(),
+                            password: None,
+                            accepted: true,
+                            seq,
+                        },
+                    );
+                }
+                messages.with_event(LobbyEvent::JoinAccepted {
+                    game_id,
+                    client_id: accepted_socket_id,
+                })
+            }
+        },
+        IncomingMessage::RejectJoin {
+            game_id,
+            client_id: rejected_socket_id,
+            reason,
+        } => match games.reject_client(socket_id, &game_id, &rejected_socket_id) {
+            Err(err) => MessagesToSend::self_(OutgoingMessage::error(err.into())),
+            Ok((host, seq)) => {
+                let mut messages = MessagesToSend::other(
+                    rejected_socket_id.clone(),
+                    OutgoingMessage::RejectJoin {
+                        game_id: game_id.clone(),
+                        reason,
+                        seq,
+                    },
+                );
+                if host != *socket_id {
+                    messages = messages.with_other(
+                        host,
+                        OutgoingMessage::ClientLeft {
+                            game_id: game_id.clone(),
+                            client_id: rejected_socket_id.clone(),
+                            seq,
+                        },
+                    );
+                }
+                messages.with_event(LobbyEvent::JoinRejected {
+                    game_id,
+                    client_id: rejected_socket_id,
+                })
+            }
+        },
+        IncomingMessage::AddCoHost {
+            game_id,
+            client_id: co_host,
+        } => match games.add_co_host(socket_id, &game_id, &co_host) {
+            Err(err) => MessagesToSend::self_(OutgoingMessage::error(err.into())),
+            Ok(seq) => {
+                MessagesToSend::other(co_host, OutgoingMessage::BecameCoHost { game_id, seq })
+            }
+        },
+        IncomingMessage::PingClients { game_id } => {
+            match games.start_liveness_check(socket_id, &game_id) {
+                Err(err) => MessagesToSend::self_(OutgoingMessage::error(err.into())),
+                Ok(clients) => clients
+                    .into_iter()
+                    .fold(MessagesToSend::none(), |messages, client| {
+                        messages.with_other(
+                            client,
+                            OutgoingMessage::HostPing {
+                                game_id: game_id.clone(),
+                            },
+                        )
+                    })
+                    .with_liveness_check(game_id),
+            }
+        }
+        IncomingMessage::PongHost { game_id } => {
+            games.record_pong(socket_id, &game_id);
+            MessagesToSend::none()
+        }
+        IncomingMessage::LeaveGame { game_id } => match games.leave_game(socket_id, &game_id) {
+            Some(left) => {
+                let accepted = left.accepted;
+                let messages = client_left_messages(MessagesToSend::none(), socket_id, left);
+                if accepted {
+                    with_lobby_state(messages, games, &game_id, socket_id)
+                } else {
+                    messages
+                }
+            }
+            None => MessagesToSend::self_(OutgoingMessage::error(ErrorCode::NotInGame)),
+        },
+        IncomingMessage::ChatMessage { game_id, text } => {
+            let Some((members, seq)) = games.chat_members(&game_id, socket_id) else {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::NotInGame));
+            };
+            let text: String = text.trim().chars().take(MAX_CHAT_LENGTH).collect();
+            if text.is_empty() {
+                return MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidMessage));
+            }
+            let sender_name = games.name(socket_id);
+            let chat = || OutgoingMessage::ChatMessage {
+                game_id: game_id.clone(),
+                sender_id: socket_id.clone(),
+                sender_name: sender_name.cloned(),
+                text: text.clone(),
+                seq,
+            };
+            // The sender gets its own message back as sent, after trimming
+            members
+                .into_iter()
+                .filter(|member| member != socket_id)
+                .fold(MessagesToSend::self_(chat()), |messages, member| {
+                    messages.with_other(member, chat())
+                })
+        }
+        IncomingMessage::SetName { name } => match validate_player_name(&name) {
+            Some(name) => {
+                games.set_name(socket_id, Some(name));
+                MessagesToSend::none()
+            }
+            None => MessagesToSend::self_(OutgoingMessage::error(ErrorCode::InvalidName)),
+        },
+        IncomingMessage::KickPlayer {
+            game_id,
+            client_id: kicked_socket_id,
+            reason,
+        } => match games.kick_client(socket_id, &game_id, &kicked_socket_id) {
+            Err(err) => MessagesToSend::self_(OutgoingMessage::error(err.into())),
+            // Signaling is only relayed between members, so removal also
+            // stops any WebRTC negotiation with the kicked client
+            Ok(left) => {
+                let mut messages = MessagesToSend::other(
+                    kicked_socket_id.clone(),
+                    OutgoingMessage::Kicked {
+                        game_id: game_id.clone(),
+                        reason,
+                        seq: left.seq,
+                    },
+                );
+                if left.host != *socket_id {
+                    messages = messages.with_other(
+                        left.host,
+                        OutgoingMessage::ClientLeft {
+                            game_id: game_id.clone(),
+                            client_id: kicked_socket_id.clone(),
+                            seq: left.seq,
+                        },
+                    );
+                }
+                let messages = messages.with_event(LobbyEvent::ClientKicked {
+                    game_id: game_id.clone(),
+                    client_id: kicked_socket_id,
+                });
+                if left.accepted {
+                    with_lobby_state(messages, games, &game_id, socket_id)
+                } else {
+                    messages
+                }
+            }
+        },
+        IncomingMessage::SetReady { game_id, ready } => {
+            match games.set_ready(socket_id, &game_id, ready) {
+                Some(true) => with_lobby_state(MessagesToSend::none(), games, &game_id, socket_id),
+                Some(false) => MessagesToSend::none(),
+                None => MessagesToSend::self_(OutgoingMessage::error(ErrorCode::NotInGame)),
+            }
+        }
+        IncomingMessage::StartGame { game_id } => {
+            match games.start_game(socket_id, &game_id, config.start_quorum_percent) {
+                Err(err) => MessagesToSend::self_(match err {
+                    StartGameError::NotHost => OutgoingMessage::error(ErrorCode::NotHost),
+                    StartGameError::AlreadyStarting => {
+                        OutgoingMessage::error(ErrorCode::AlreadyStarting)
+                    }
+                    StartGameError::NotReady(not_ready) => {
+                        OutgoingMessage::NotReady { game_id, not_ready }
+                    }
+                }),
+                Ok((clients, seq)) => {
+                    let starting = || OutgoingMessage::GameStarting {
+                        game_id: game_id.clone(),
+                        countdown: config.start_countdown_secs,
+                        seq,
+                    };
+                    clients
+                        .into_iter()
+                        .fold(MessagesToSend::self_(starting()), |messages, client| {
+                            messages.with_other(client, starting())
+                        })
+                        .with_event(LobbyEvent::GameStarting { game_id })
+                }
+            }
+        }
+        IncomingMessage::ReserveSlot { game_id, count } => {
+            match games.reserve_slots(socket_id, &game_id, count) {
+                Err(err) => MessagesToSend::self_(OutgoingMessage::error(err.into())),
+                Ok(invite_tokens) => MessagesToSend::self_(OutgoingMessage::SlotsReserved {
+                    game_id,
+                    invite_tokens,
+                }),
+            }
+        }
+        IncomingMessage::ReleaseSlot {
+            game_id,
+            invite_token,
+        } => match games.release_slot(socket_id, &game_id, &invite_token) {
+            Err(err) => MessagesToSend::self_(OutgoingMessage::error(err.into())),
+            Ok(()) => MessagesToSend::none(),
+        },
+    }
+}
+
+const MIN_SERVER_NAME_LENGTH: usize = 1;
+const MAX_SERVER_NAME_LENGTH: usize = 48;
+
+// Returns the name without surrounding whitespace, or None if it contains
+// control characters or its length in characters is out of bounds
+fn validate_server_name(server_name: &str) -> Option<String> {
+    validate_name(server_name, MIN_SERVER_NAME_LENGTH, MAX_SERVER_NAME_LENGTH)
+}
+
+const MIN_PLAYER_NAME_LENGTH: usize = 1;
+const MAX_PLAYER_NAME_LENGTH: usize = 32;
+
+// Display names are validated like server names
+fn validate_player_name(name: &str) -> Option<String> {
+    validate_name(name, MIN_PLAYER_NAME_LENGTH, MAX_PLAYER_NAME_LENGTH)
+}
+
+fn validate_name(name: &str, min_length: usize, max_length: usize) -> Option<String> {
+    let name = name.trim();
+    let length = name.chars().count();
+    let valid = (min_length..=max_length).contains(&length) && !name.chars().any(char::is_control);
+    valid.then(|| name.to_string())
+}
+
+const MAX_METADATA_KEYS: usize = 16;
+// Total length of the keys and values
+const MAX_METADATA_BYTES: usize = 1024;
+
+fn valid_metadata(metadata: &HashMap<String, String>) -> bool {
+    let bytes: usize = metadata
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum();
+    metadata.len() <= MAX_METADATA_KEYS && bytes <= MAX_METADATA_BYTES
+}
+
+// Within the configured range and not below the slots already taken in the
+// socket's game
+fn valid_max_players(
+    config: &Config,
+    games: &Games,
+    socket_id: &SocketId,
+    max_players: u32,
+) -> bool {
+    config.max_players_range.contains(&max_players)
+        && games
+            .get_managed_game(socket_id)
+            .is_none_or(|game| game.occupancy().taken_slots() <= max_players)
+}
+
+// Checks that a JSON value is nested at most `depth` levels deep and contains
+// at most `elements` values in total. `elements` is decremented as the value is
+// walked.
+fn is_within_limits(value: &serde_json::Value, depth: usize, elements: &mut usize) -> bool {
+    if depth == 0 || *elements == 0 {
+        return false;
+    }
+    *elements -= 1;
+    match value {
+        serde_json::Value::Array(values) => values
+            .iter()
+            .all(|value| is_within_limits(value, depth - 1, elements)),
+        serde_json::Value::Object(map) => map
+            .values()
+            .all(|value| is_within_limits(value, depth - 1, elements)),
+        _ => true,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn join_game(
+    socket_id: &SocketId,
+    config: &Config,
+    games: &mut Games,
+    metrics: &Metrics,
+    game_id: GameId,
+    password: Option<String>,
+    invite_token: Option<InviteToken>,
+) -> MessagesToSend {
+    let joined = games.join_game(
+        &game_id,
+        socket_id,
+        password.as_deref(),
+        invite_token.as_ref(),
+    );
+    metrics.record_join(joined.is_ok());
+    let name = games.name(socket_id);
+    match joined {
+        Err(err) => {
+            debug!(reason = %err, "Join rejected");
+            MessagesToSend::self_(OutgoingMessage::error(err.into()))
+        }
+        Ok(JoinedGame {
+            host,
+            co_hosts,
+            seq,
+            accepted: false,
+        }) => std::iter::once(host)
+            .chain(co_hosts)
+            .fold(MessagesToSend::none(), |messages, manager| {
+                messages.with_other(
+                    manager,
+                    OutgoingMessage::NewClient {
+                        game_id: game_id.clone(),
+                        client_id: socket_id.clone(),
+                        name: name.cloned(),
+                        password: password.clone(),
+                        accepted: false,
+                        seq,
+                    },
+                )
+            })
+            .with_event(LobbyEvent::JoinRequested {
+                game_id,
+                client_id: socket_id.clone(),
+            }),
+        // The host is only told about the client, the password was already
+        // checked if needed
+        Ok(JoinedGame {
+            host,
+            seq,
+            accepted: true,
+            ..
+        }) => with_mesh_peers(
+            MessagesToSend::self_(OutgoingMessage::AcceptJoin {
+                game_id: game_id.clone(),
+                seq,
+                ice_servers: turn::ice_servers(config.turn.as_ref(), socket_id),
+            }),
+            games,
+            &game_id,
+            socket_id,
+        )
+        .with_other(
+            host,
+            OutgoingMessage::NewClient {
+                game_id: game_id.clone(),
+                client_id: socket_id.clone(),
+                name: name.cloned(),
+                password: None,
+                accepted: true,
+                seq,
+            },
+        )
+        .with_event(LobbyEvent::JoinAccepted {
+            game_id,
+            client_id: socket_id.clone(),
+        }),
+    }
+}
+
+// Introduces a client just accepted to a mesh game and the other clients to
+// each other. Following the AcceptJoin, the client is sent the peers it should
+// connect to.
+fn with_mesh_peers(
+    messages: MessagesToSend,
+    games: &Games,
+    game_id: &GameId,
+    client: &SocketId,
+) -> MessagesToSend {
+    let Some(game) = games.get_game(game_id).filter(|game| game.game_info.mesh) else {
+        return messages;
+    };
+    let peers: Vec<SocketId> = game
+        .clients
+        .iter()
+        .filter(|peer| *peer != client)
+        .cloned()
+        .collect();
+    let messages = peers.iter().fold(messages, |messages, peer| {
+        messages.with_other(
+            peer.clone(),
+            OutgoingMessage::PeerJoined {
+                game_id: game_id.clone(),
+                client_id: client.clone(),
+            },
+        )
+    });
+    messages.with_other(
+        client.clone(),
+        OutgoingMessage::MeshPeers {
+            game_id: game_id.clone(),
+            peers,
+        },
+    )
+}
+
+impl From<JoinGameError> for ErrorCode {
+    fn from(err: JoinGameError) -> Self {
+        match err {
+            JoinGameError::GameNotFound => ErrorCode::GameNotFound,
+            JoinGameError::OwnGame => ErrorCode::CannotJoinOwnGame,
+            JoinGameError::InOtherGame => ErrorCode::AlreadyInGame,
+            JoinGameError::AlreadyMember => ErrorCode::AlreadyJoined,
+            JoinGameError::JoinPending => ErrorCode::JoinPending,
+            JoinGameError::GameFull => ErrorCode::GameFull,
+            JoinGameError::InvalidInviteToken => ErrorCode::InvalidInviteToken,
+            JoinGameError::GameClosed => ErrorCode::GameClosed,
+            JoinGameError::WrongPassword => ErrorCode::WrongPassword,
+            JoinGameError::GameStarted => ErrorCode::GameStarted,
+        }
+    }
+}
+
+// Renders the reason sent to the client, so logs and clients agree
+impl std::fmt::Display for JoinGameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(ErrorCode::from(*self).reason())
+    }
+}
+
+impl From<JoinResponseError> for ErrorCode {
+    fn from(err: JoinResponseError) -> Self {
+        match err {
+            JoinResponseError::NotHost => ErrorCode::NotHost,
+            JoinResponseError::NotPending => ErrorCode::NotPending,
+        }
+    }
+}
+
+impl From<ReserveSlotError> for ErrorCode {
+    fn from(err: ReserveSlotError) -> Self {
+        match err {
+            ReserveSlotError::NotHost => ErrorCode::NotHost,
+            ReserveSlotError::NotEnoughSlots => ErrorCode::NotEnoughSlots,
+            ReserveSlotError::UnknownInviteToken => ErrorCode::UnknownInviteToken,
+        }
+    }
+}
+
+impl From<LivenessCheckError> for ErrorCode {
+    fn from(err: LivenessCheckError) -> Self {
+        match err {
+            LivenessCheckError::NotHost => ErrorCode::NotHost,
+            LivenessCheckError::AlreadyRunning => ErrorCode::LivenessCheckRunning,
+        }
+    }
+}
+
+impl From<CoHostError> for ErrorCode {
+    fn from(err: CoHostError) -> Self {
+        match err {
+            CoHostError::NotHost => ErrorCode::NotHost,
+            CoHostError::NotClient => ErrorCode::ClientNotInGame,
+        }
+    }
+}
+
+impl From<KickError> for ErrorCode {
+    fn from(err: KickError) -> Self {
+        match err {
+            KickError::NotHost => ErrorCode::NotHost,
+            KickError::NotMember => ErrorCode::ClientNotInGame,
+        }
+    }
+}
+
+// Tells the members of a removed game it's gone. Pending clients are told
+// too, their join can't be answered anymore.
+fn game_closed_messages(game: Game, reason: &str) -> MessagesToSend {
+    let messages = game
+        .clients
+        .into_iter()
+        .chain(game.pending)
+        .chain(game.spectators)
+        .fold(MessagesToSend::none(), |messages, client| {
+            messages.with_other(
+                client,
+                OutgoingMessage::GameClosed {
+                    game_id: game.game_id.clone(),
+                    reason: reason.to_string(),
+                },
+            )
+        });
+    messages.with_event(LobbyEvent::GameClosed {
+        game_id: game.game_id,
+    })
+}
+
+// Tells the host, clients and spectators other than the updater about a game
+// info update
+fn info_updated_messages(
+    games: &Games,
+    updater: &SocketId,
+    game_id: &GameId,
+    seq: u64,
+) -> MessagesToSend {
+    let Some(game) = games.get_game(game_id) else {
+        return MessagesToSend::none();
+    };
+    let info = games.outgoing_info(game);
+    std::iter::once(&game.host)
+        .chain(&game.clients)
+        .chain(&game.spectators)
+        .filter(|member| *member != updater)
+        .fold(MessagesToSend::none(), |messages, member| {
+            messages.with_other(
+                member.clone(),
+                OutgoingMessage::GameInfoUpdated {
+                    game: info.clone(),
+                    seq,
+                },
+            )
+        })
+}
+
+fn resumed_message(
+    games: &Games,
+    socket_id: SocketId,
+    resume_token: ResumeToken,
+    last_seq: Option<u64>,
+) -> OutgoingMessage {
+    let seq = games
+        .get_game_by_host(&socket_id)
+        .or_else(|| games.get_game_by_client(&socket_id))
+        .map(|game| game.event_seq);
+    OutgoingMessage::Resumed {
+        socket_id,
+        resume_token,
+        seq,
+        caught_up: seq == last_seq,
+    }
+}
+
+fn process_disconnect(socket_id: &SocketId, games: &mut Games) -> MessagesToSend {
+    games.set_name(socket_id, None);
+    if let Some(migration) = games.migrate_host(socket_id) {
+        return host_migration_messages(migration);
+    }
+    if let Some(game) = games.remove_game(socket_id) {
+        return game_closed_messages(game, "hostLeft");
+    }
+    games
+        .remove_client(socket_id)
+        .into_iter()
+        .fold(MessagesToSend::none(), |messages, left| {
+            let game_id = left.game_id.clone();
+            let accepted = left.accepted;
+            let messages = client_left_messages(messages, socket_id, left);
+            if accepted {
+                with_lobby_state(messages, games, &game_id, socket_id)
+            } else {
+                messages
+            }
+        })
+}
+
+// Sends the ready state of the game's accepted clients to the host, clients
+// and spectators. The sender gets it as its own reply, which must not be taken
+// already.
+fn with_lobby_state(
+    messages: MessagesToSend,
+    games: &Games,
+    game_id: &GameId,
+    sender: &SocketId,
+) -> MessagesToSend {
+    let Some(game) = games.get_game(game_id) else {
+        return messages;
+    };
+    let ready: Vec<(SocketId, bool)> = game
+        .clients
+        .iter()
+        .map(|client| (client.clone(), game.ready.contains(client)))
+        .collect();
+    let lobby_state = || OutgoingMessage::LobbyState {
+        game_id: game_id.clone(),
+        ready: ready.clone(),
+    };
+    std::iter::once(&game.host)
+        .chain(&game.clients)
+        .chain(&game.spectators)
+        .fold(messages, |mut messages, member| {
+            if member == sender {
+                messages.self_message = Some(lobby_state());
+                messages
+            } else {
+                messages.with_other(member.clone(), lobby_state())
+            }
+        })
+}
+
+fn client_left_messages(
+    messages: MessagesToSend,
+    client_id: &SocketId,
+    left: LeftGame,
+) -> MessagesToSend {
+    messages
+        .with_other(
+            left.host,
+            OutgoingMessage::ClientLeft {
+                game_id: left.game_id.clone(),
+                client_id: client_id.clone(),
+                seq: left.seq,
+            },
+        )
+        .with_event(LobbyEvent::ClientLeft {
+            game_id: left.game_id,
+            client_id: client_id.clone(),
+        })
+}
+
+fn host_migration_messages(migration: HostMigration) -> MessagesToSend {
+    let HostMigration {
+        game_id,
+        new_host,
+        clients,
+        rejected,
+        seq,
+    } = migration;
+    let messages = MessagesToSend::other(
+        new_host.clone(),
+        OutgoingMessage::BecameHost {
+            game_id: game_id.clone(),
+            seq,
+        },
+    );
+    let messages = clients.into_iter().fold(messages, |messages, client| {
+        messages.with_other(
+            client,
+            OutgoingMessage::HostChanged {
+                game_id: game_id.clone(),
+                host_id: new_host.clone(),
+                seq,
+            },
+        )
+    });
+    rejected
+        .into_iter()
+        .fold(messages, |messages, client| {
+            messages.with_other(
+                client,
+                OutgoingMessage::RejectJoin {
+                    game_id: game_id.clone(),
+                    reason: "Host left".to_string(),
+                    seq,
+                },
+            )
+        })
+        .with_event(LobbyEvent::HostChanged {
+            game_id,
+            host_id: new_host,
+        })
+}
+
+fn random_string(format: &IdFormat) -> String {
+    let mut rng = thread_rng();
+    match &format.alphabet {
+        Some(alphabet) => (0..format.length)
+            .map(|_| *alphabet.choose(&mut rng).unwrap())
+            .collect(),
+        None => Alphanumeric.sample_string(&mut rng, format.length),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+struct SocketId(String);
+
+impl SocketId {
+    fn random(format: &IdFormat) -> Self {
+        Self(random_string(format))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+struct GameId(String); // String for backwards compat
+
+impl GameId {
+    fn random(format: &IdFormat) -> Self {
+        Self(random_string(format))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+struct InviteToken(String);
+
+impl InviteToken {
+    fn random() -> Self {
+        Self(random_string(&IdFormat::default()))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+struct ResumeToken(String);
+
+impl ResumeToken {
+    fn random() -> Self {
+        Self(random_string(&IdFormat {
+            length: 32,
+            alphabet: None,
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum IncomingMessage {
+    #[serde(rename_all = "camelCase")]
+    WebrtcSignaling {
+        client_id: Option<SocketId>,
+        description: Option<serde_json::Value>,
+        candidate: Option<serde_json::Value>,
+    },
+    #[serde(rename_all = "camelCase")]
+    CreateGame {
+        server_name: String,
+        max_players: u32,
+        game_id: Option<GameId>,
+        requires_password: Option<bool>,
+        region: Option<String>,
+        // Defaults to `JoinPolicy::Approval`
+        join_policy: Option<JoinPolicy>,
+        // Checked by the server on join, implies `requires_password` unless
+        // it's explicitly false. Required for `JoinPolicy::Password`.
+        password: Option<String>,
+        // Promote a client to host instead of closing the game when the host
+        // leaves
+        migratable: Option<bool>,
+        // Defaults to "unknown"
+        version: Option<String>,
+        // Keep the game out of the game list, defaults to false
+        private: Option<bool>,
+        // Introduce accepted clients to each other for a full mesh, defaults
+        // to false
+        mesh: Option<bool>,
+        metadata: Option<HashMap<String, String>>,
+    },
+    // A `playerAmount` field is still accepted for backwards compat, but the
+    // amount is now derived from accepted clients
+    #[serde(rename_all = "camelCase")]
+    UpdateGameInfo {
+        server_name: String,
+        max_players: u32,
+        // Kept as is when unset
+        requires_password: Option<bool>,
+        // Replaces the metadata when set
+        metadata: Option<HashMap<String, String>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    PatchGameInfo {
+        server_name: Option<String>,
+        max_players: Option<u32>,
+        requires_password: Option<bool>,
+        password: Option<String>,
+        // Replaces the metadata when set
+        metadata: Option<HashMap<String, String>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    ListGames {
+        #[serde(flatten)]
+        query: ListQuery,
+    },
+    // Also finds private games, the id is what keeps them hidden
+    #[serde(rename_all = "camelCase")]
+    GetGame {
+        game_id: GameId,
+    },
+    // Accepted clients of the host's game
+    #[serde(rename_all = "camelCase")]
+    ListClients {
+        game_id: GameId,
+    },
+    // Display name shown to the other members of the socket's games
+    SetName {
+        name: String,
+    },
+    SubscribeGameList,
+    UnsubscribeGameList,
+    #[serde(rename_all = "camelCase")]
+    JoinGame {
+        game_id: GameId,
+        password: Option<String>,
+        invite_token: Option<InviteToken>,
+    },
+    #[serde(rename_all = "camelCase")]
+    JoinAsSpectator {
+        game_id: GameId,
+        password: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    QuickMatch {
+        #[serde(default)]
+        filters: GameFilters,
+        password: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    AcceptJoin {
+        game_id: GameId,
+        client_id: SocketId,
+    },
+    #[serde(rename_all = "camelCase")]
+    RejectJoin {
+        game_id: GameId,
+        client_id: SocketId,
+        reason: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    ReserveSlot {
+        game_id: GameId,
+        count: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    ReleaseSlot {
+        game_id: GameId,
+        invite_token: InviteToken,
+    },
+    #[serde(rename_all = "camelCase")]
+    LeaveGame {
+        game_id: GameId,
+    },
+    #[serde(rename_all = "camelCase")]
+    KickPlayer {
+        game_id: GameId,
+        client_id: SocketId,
+        reason: Option<String>,
+    },
+    // Lets an accepted client answer joins, kick and update the game info
+    #[serde(rename_all = "camelCase")]
+    AddCoHost {
+        game_id: GameId,
+        client_id: SocketId,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetReady {
+        game_id: GameId,
+        ready: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    StartGame {
+        game_id: GameId,
+    },
+    #[serde(rename_all = "camelCase")]
+    PingClients {
+        game_id: GameId,
+    },
+    #[serde(rename_all = "camelCase")]
+    PongHost {
+        game_id: GameId,
+    },
+    #[serde(rename_all = "camelCase")]
+    ChatMessage {
+        game_id: GameId,
+        text: String,
+    },
+    // Relayed to the clients of the host's game as is
+    #[serde(rename_all = "camelCase")]
+    Broadcast {
+        game_id: GameId,
+        payload: serde_json::Value,
+    },
+    // `lastSeq` is the latest game event `seq` the client saw
+    #[serde(rename_all = "camelCase")]
+    Resume {
+        token: ResumeToken,
+        last_seq: Option<u64>,
+    },
+    Capabilities,
+}
+
+struct Deprecation {
+    message_type: &'static str,
+    replacement: &'static str,
+}
+
+impl IncomingMessage {
+    // Deprecated messages keep working, but the sender is warned once per
+    // connection to migrate to the replacement
+    fn deprecation(&self) -> Option<Deprecation> {
+        let replacement = match self {
+            IncomingMessage::UpdateGameInfo { .. } => "patchGameInfo",
+            _ => return None,
+        };
+        Some(Deprecation {
+            message_type: self.message_type(),
+            replacement,
+        })
+    }
+
+    // Processed under a read lock of the games, so concurrent list requests
+    // and signaling don't wait for each other
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            IncomingMessage::WebrtcSignaling { .. }
+                | IncomingMessage::ListGames { .. }
+                | IncomingMessage::GetGame { .. }
+                | IncomingMessage::ListClients { .. }
+                | IncomingMessage::Broadcast { .. }
+                | IncomingMessage::SubscribeGameList
+                | IncomingMessage::UnsubscribeGameList
+        )
+    }
+
+    fn message_type(&self) -> &'static str {
+        match self {
+            IncomingMessage::WebrtcSignaling { .. } => "webrtcSignaling",
+            IncomingMessage::CreateGame { .. } => "createGame",
+            IncomingMessage::UpdateGameInfo { .. } => "updateGameInfo",
+            IncomingMessage::PatchGameInfo { .. } => "patchGameInfo",
+            IncomingMessage::ListGames { .. } => "listGames",
+            IncomingMessage::GetGame { .. } => "getGame",
+            IncomingMessage::ListClients { .. } => "listClients",
+            IncomingMessage::SetName { .. } => "setName",
+            IncomingMessage::SubscribeGameList => "subscribeGameList",
+            IncomingMessage::UnsubscribeGameList => "unsubscribeGameList",
+            IncomingMessage::JoinGame { .. } => "joinGame",
+            IncomingMessage::JoinAsSpectator { .. } => "joinAsSpectator",
+            IncomingMessage::QuickMatch { .. } => "quickMatch",
+            IncomingMessage::AcceptJoin { .. } => "acceptJoin",
+            IncomingMessage::RejectJoin { .. } => "rejectJoin",
+            IncomingMessage::ReserveSlot { .. } => "reserveSlot",
+            IncomingMessage::ReleaseSlot { .. } => "releaseSlot",
+            IncomingMessage::LeaveGame { .. } => "leaveGame",
+            IncomingMessage::KickPlayer { .. } => "kickPlayer",
+            IncomingMessage::AddCoHost { .. } => "addCoHost",
+            IncomingMessage::SetReady { .. } => "setReady",
+            IncomingMessage::StartGame { .. } => "startGame",
+            IncomingMessage::PingClients { .. } => "pingClients",
+            IncomingMessage::PongHost { .. } => "pongHost",
+            IncomingMessage::ChatMessage { .. } => "chatMessage",
+            IncomingMessage::Broadcast { .. } => "broadcast",
+            IncomingMessage::Resume { .. } => "resume",
+            IncomingMessage::Capabilities => "capabilities",
+        }
+    }
+}
+
+// Bumped on incompatible protocol changes
+const PROTOCOL_VERSION: u32 = 1;
+
+// Advertised in Capabilities, keep in sync with the `message_type` methods
+const INCOMING_MESSAGE_TYPES: &[&str] = &[
+    "webrtcSignaling",
+    "createGame",
+    "updateGameInfo",
+    "patchGameInfo",
+    "listGames",
+    "getGame",
+    "listClients",
+    "setName",
+    "subscribeGameList",
+    "unsubscribeGameList",
+    "joinGame",
+    "joinAsSpectator",
+    "quickMatch",
+    "acceptJoin",
+    "rejectJoin",
+    "reserveSlot",
+    "releaseSlot",
+    "leaveGame",
+    "kickPlayer",
+    "addCoHost",
+    "setReady",
+    "startGame",
+    "pingClients",
+    "pongHost",
+    "chatMessage",
+    "broadcast",
+    "resume",
+    "capabilities",
+];
+const OUTGOING_MESSAGE_TYPES: &[&str] = &[
+    "error",
+    "welcome",
+    "serverHello",
+    "resumed",
+    "webrtcSignaling",
+    "gameCreated",
+    "gameList",
+    "gameInfo",
+    "clientList",
+    "gameListUpdated",
+    "gameInfoChanged",
+    "gameInfoUpdated",
+    "gameRemoved",
+    "newClient",
+    "newSpectator",
+    "noMatch",
+    "meshPeers",
+    "peerJoined",
+    "acceptJoin",
+    "rejectJoin",
+    "gameClosed",
+    "deprecation",
+    "slotsReserved",
+    "hostPing",
+    "clientLiveness",
+    "notReady",
+    "lobbyState",
+    "gameStarting",
+    "becameHost",
+    "becameCoHost",
+    "hostChanged",
+    "clientLeft",
+    "kicked",
+    "serverShutdown",
+    "chatMessage",
+    "hostBroadcast",
+    "latency",
+    "capabilities",
+];
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum ErrorCode {
+    InvalidEncoding,
+    InvalidMessage,
+    InternalError,
+    SignalingTooLarge,
+    PasswordRequired,
+    ServerFull,
+    NotHost,
+    NotInGame,
+    ClientNotInGame,
+    GameNotFound,
+    AlreadyJoined,
+    JoinPending,
+    NotPending,
+    GameFull,
+    InvalidInviteToken,
+    UnknownInviteToken,
+    NotEnoughSlots,
+    GameClosed,
+    WrongPassword,
+    GameStarted,
+    AlreadyStarting,
+    LivenessCheckRunning,
+    RateLimited,
+    MessageTooLarge,
+    GameIdTaken,
+    AlreadyHosting,
+    InvalidServerName,
+    InvalidMaxPlayers,
+    InvalidResumeToken,
+    CannotJoinOwnGame,
+    AlreadyInGame,
+    InvalidMetadata,
+    InvalidName,
+    PayloadTooLarge,
+}
+
+impl ErrorCode {
+    fn reason(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidEncoding => "Invalid message encoding",
+            ErrorCode::InvalidMessage => "Invalid message",
+            ErrorCode::InternalError => "Internal server error",
+            ErrorCode::SignalingTooLarge => "Signaling payload too large",
+            ErrorCode::PasswordRequired => "Password join policy requires a password",
+            ErrorCode::ServerFull => "Server is full",
+            ErrorCode::NotHost => "You're not a game host",
+            ErrorCode::NotInGame => "Not in game",
+            ErrorCode::ClientNotInGame => "Client not in game",
+            ErrorCode::GameNotFound => "Game not found",
+            ErrorCode::AlreadyJoined => "Already joined",
+            ErrorCode::JoinPending => "Join request already pending",
+            ErrorCode::NotPending => "No pending join from client",
+            ErrorCode::GameFull => "Game is full",
+            ErrorCode::InvalidInviteToken => "Invalid invite token",
+            ErrorCode::UnknownInviteToken => "Unknown invite token",
+            ErrorCode::NotEnoughSlots => "Not enough free slots",
+            ErrorCode::GameClosed => "Game is closed",
+            ErrorCode::WrongPassword => "Wrong password",
+            ErrorCode::GameStarted => "Game already started",
+            ErrorCode::AlreadyStarting => "Game already starting",
+            ErrorCode::LivenessCheckRunning => "Liveness check already running",
+            ErrorCode::RateLimited => "Too many messages, slow down",
+            ErrorCode::MessageTooLarge => "Message too large",
+            ErrorCode::GameIdTaken => "Game id already in use",
+            ErrorCode::AlreadyHosting => "Already hosting a game",
+            ErrorCode::InvalidServerName => "Invalid server name",
+            ErrorCode::InvalidMaxPlayers => "Invalid max players",
+            ErrorCode::InvalidResumeToken => "Invalid or expired resume token",
+            ErrorCode::CannotJoinOwnGame => "Can't join your own game",
+            ErrorCode::AlreadyInGame => "Already in another game, leave it first",
+            ErrorCode::InvalidMetadata => "Too much game metadata",
+            ErrorCode::InvalidName => "Invalid name",
+            ErrorCode::PayloadTooLarge => "Broadcast payload too large",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum OutgoingMessage {
+    #[serde(rename_all = "camelCase")]
+    // `reason` is for display, clients should match on `code`
+    Error {
+        code: ErrorCode,
+        reason: String,
+    },
+
+    // First message on every connection. The resume token is only set when
+    // sessions can be resumed.
+    #[serde(rename_all = "camelCase")]
+    Welcome {
+        socket_id: SocketId,
+        resume_token: Option<ResumeToken>,
+    },
+
+    // Sent after Welcome so clients can enable features the server supports
+    #[serde(rename_all = "camelCase")]
+    ServerHello {
+        version: &'static str,
+        max_players_limit: u32,
+        features: Vec<&'static str>,
+    },
+
+    // Replaces the token from Welcome, which can't be used again
+    #[serde(rename_all = "camelCase")]
+    Resumed {
+        socket_id: SocketId,
+        resume_token: ResumeToken,
+        // Latest event of the socket's game, if it's in one
+        seq: Option<u64>,
+        // Whether that's the `lastSeq` the client resumed with, so it missed
+        // no game events
+        caught_up: bool,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    WebrtcSignaling {
+        game_id: GameId,
+        client_id: Option<SocketId>,
+        description: Option<serde_json::Value>,
+        candidate: Option<serde_json::Value>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    GameCreated {
+        game_id: GameId,
+        ice_servers: Vec<IceServer>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    GameList {
+        games: Arc<Vec<OutgoingGameInfo>>,
+        // Number of matching games before pagination
+        total: usize,
+    },
+
+    // Answer to GetGame
+    #[serde(rename_all = "camelCase")]
+    GameInfo {
+        game: OutgoingGameInfo,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    ClientList {
+        game_id: GameId,
+        clients: Vec<ListedClient>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    GameListUpdated {
+        games: Arc<Vec<OutgoingGameInfo>>,
+    },
+
+    // Sent to list subscribers for new and updated games
+    #[serde(rename_all = "camelCase")]
+    GameInfoChanged {
+        game: OutgoingGameInfo,
+    },
+
+    // Sent to the members of a game when its host or a co-host updates it
+    #[serde(rename_all = "camelCase")]
+    GameInfoUpdated {
+        game: OutgoingGameInfo,
+        seq: u64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    GameRemoved {
+        game_id: GameId,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    NewClient {
+        game_id: GameId,
+        client_id: SocketId,
+        name: Option<String>,
+        password: Option<String>,
+        // The client was already accepted, by the join policy or a co-host, and
+        // needs no AcceptJoin
+        accepted: bool,
+        seq: u64,
+    },
+
+    // Spectators signal with the host like clients do
+    #[serde(rename_all = "camelCase")]
+    NewSpectator {
+        game_id: GameId,
+        client_id: SocketId,
+        seq: u64,
+    },
+
+    NoMatch,
+
+    // Sent to a client accepted to a mesh game, listing the clients it should
+    // connect to
+    #[serde(rename_all = "camelCase")]
+    MeshPeers {
+        game_id: GameId,
+        peers: Vec<SocketId>,
+    },
+
+    // Sent to the clients of a mesh game when another client is accepted
+    #[serde(rename_all = "camelCase")]
+    PeerJoined {
+        game_id: GameId,
+        client_id: SocketId,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    AcceptJoin {
+        game_id: GameId,
+        seq: u64,
+        ice_servers: Vec<IceServer>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    RejectJoin {
+        game_id: GameId,
+        reason: String,
+        seq: u64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    GameClosed {
+        game_id: GameId,
+        reason: String,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    Deprecation {
+        message_type: &'static str,
+        replacement: &'static str,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    SlotsReserved {
+        game_id: GameId,
+        invite_tokens: Vec<InviteToken>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    HostPing {
+        game_id: GameId,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    ClientLiveness {
+        game_id: GameId,
+        responsive: Vec<SocketId>,
+        unresponsive: Vec<SocketId>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    NotReady {
+        game_id: GameId,
+        not_ready: Vec<SocketId>,
+    },
+
+    // Whether each accepted client is ready, sent to members when a client
+    // changes its readiness or an accepted client leaves
+    #[serde(rename_all = "camelCase")]
+    LobbyState {
+        game_id: GameId,
+        ready: Vec<(SocketId, bool)>,
+    },
+
+    // Sent to the host and accepted clients
+    #[serde(rename_all = "camelCase")]
+    GameStarting {
+        game_id: GameId,
+        // Seconds until the game starts
+        countdown: u32,
+        seq: u64,
+    },
+
+    // Sent to the client promoted to host when the previous host left
+    #[serde(rename_all = "camelCase")]
+    BecameHost {
+        game_id: GameId,
+        seq: u64,
+    },
+
+    // Sent to a client the host made a co-host
+    #[serde(rename_all = "camelCase")]
+    BecameCoHost {
+        game_id: GameId,
+        seq: u64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    HostChanged {
+        game_id: GameId,
+        host_id: SocketId,
+        seq: u64,
+    },
+
+    // Sent to the host when a pending or accepted client leaves or disconnects
+    #[serde(rename_all = "camelCase")]
+    ClientLeft {
+        game_id: GameId,
+        client_id: SocketId,
+        seq: u64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    Kicked {
+        game_id: GameId,
+        reason: Option<String>,
+        seq: u64,
+    },
+
+    // Sent to every socket before the server closes them on shutdown
+    ServerShutdown {
+        reason: String,
+    },
+
+    // Round trip time of the latest heartbeat ping
+    Latency {
+        ms: u64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    ChatMessage {
+        game_id: GameId,
+        sender_id: SocketId,
+        sender_name: Option<String>,
+        text: String,
+        seq: u64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    HostBroadcast {
+        game_id: GameId,
+        payload: serde_json::Value,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    Capabilities {
+        incoming: &'static [&'static str],
+        outgoing: &'static [&'static str],
+        protocol_version: u32,
+    },
+}
+
+impl OutgoingMessage {
+    // Error with the default reason for the code
+    fn error(code: ErrorCode) -> Self {
+        OutgoingMessage::Error {
+            code,
+            reason: code.reason().to_string(),
+        }
+    }
+
+    // Can be dropped for a socket falling behind without breaking its state,
+    // anything else disconnects the socket instead
+    fn is_droppable(&self) -> bool {
+        matches!(
+            self,
+            OutgoingMessage::GameInfoChanged { .. }
+                | OutgoingMessage::GameRemoved { .. }
+                | OutgoingMessage::ChatMessage { .. }
+        )
+    }
+
+    fn message_type(&self) -> &'static str {
+        match self {
+            OutgoingMessage::Error { .. } => "error",
+            OutgoingMessage::Welcome { .. } => "welcome",
+            OutgoingMessage::ServerHello { .. } => "serverHello",
+            OutgoingMessage::Resumed { .. } => "resumed",
+            OutgoingMessage::WebrtcSignaling { .. } => "webrtcSignaling",
+            OutgoingMessage::GameCreated { .. } => "gameCreated",
+            OutgoingMessage::GameList { .. } => "gameList",
+            OutgoingMessage::GameInfo { .. } => "gameInfo",
+            OutgoingMessage::ClientList { .. } => "clientList",
+            OutgoingMessage::GameListUpdated { .. } => "gameListUpdated",
+            OutgoingMessage::GameInfoChanged { .. } => "gameInfoChanged",
+            OutgoingMessage::GameInfoUpdated { .. } => "gameInfoUpdated",
+            OutgoingMessage::GameRemoved { .. } => "gameRemoved",
+            OutgoingMessage::NewClient { .. } => "newClient",
+            OutgoingMessage::NewSpectator { .. } => "newSpectator",
+            OutgoingMessage::NoMatch => "noMatch",
+            OutgoingMessage::MeshPeers { .. } => "meshPeers",
+            OutgoingMessage::PeerJoined { .. } => "peerJoined",
+            OutgoingMessage::AcceptJoin { .. } => "acceptJoin",
+            OutgoingMessage::RejectJoin { .. } => "rejectJoin",
+            OutgoingMessage::GameClosed { .. } => "gameClosed",
+            OutgoingMessage::Deprecation { .. } => "deprecation",
+            OutgoingMessage::SlotsReserved { .. } => "slotsReserved",
+            OutgoingMessage::HostPing { .. } => "hostPing",
+            OutgoingMessage::ClientLiveness { .. } => "clientLiveness",
+            OutgoingMessage::NotReady { .. } => "notReady",
+            OutgoingMessage::LobbyState { .. } => "lobbyState",
+            OutgoingMessage::GameStarting { .. } => "gameStarting",
+            OutgoingMessage::BecameHost { .. } => "becameHost",
+            OutgoingMessage::BecameCoHost { .. } => "becameCoHost",
+            OutgoingMessage::HostChanged { .. } => "hostChanged",
+            OutgoingMessage::ClientLeft { .. } => "clientLeft",
+            OutgoingMessage::Kicked { .. } => "kicked",
+            OutgoingMessage::ServerShutdown { .. } => "serverShutdown",
+            OutgoingMessage::Latency { .. } => "latency",
+            OutgoingMessage::ChatMessage { .. } => "chatMessage",
+            OutgoingMessage::HostBroadcast { .. } => "hostBroadcast",
+            OutgoingMessage::Capabilities { .. } => "capabilities",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListedClient {
+    client_id: SocketId,
+    name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutgoingGameInfo {
+    game_id: GameId,
+    server_name: String,
+    player_amount: u32,
+    spectator_amount: u32,
+    max_players: u32,
+    requires_password: bool,
+    region: Option<String>,
+    join_policy: JoinPolicy,
+    status: GameStatus,
+    version: String,
+    // Sent as the age at the time of sending, so listings stay comparable
+    #[serde(rename = "ageSeconds", serialize_with = "serialize_age")]
+    created_at: SystemTime,
+    metadata: HashMap<String, String>,
+    // Null for hosts without a display name
+    host_name: Option<String>,
+}
+
+fn serialize_age<S: serde::Serializer>(
+    created_at: &SystemTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let age = SystemTime::now()
+        .duration_since(*created_at)
+        .unwrap_or_default();
+    serializer.serialize_u64(age.as_secs())
+}
+
+// Processes a message against the games of a full app state and records its
+// events, like the socket handler does
+#[cfg(test)]
+fn process(
+    app_state: &AppState,
     socket_id: &SocketId,
-    games: &mut Games,
-    message: IncomingMessage,
+    message: serde_json::Value,
 ) -> MessagesToSend {
-    match message {
-        IncomingMessage::WebrtcSignaling {
-            client_id: target_socket_id,
-            description,
-            candidate,
-        } => {
-            if let Some(target_socket_id) = target_socket_id {
-                // WebRTC signaling from host -> send to client
-                if let Some(game) = games.get_game_by_host(socket_id) {
-                    MessagesToSend::other(
-                        target_socket_id,
-                        OutgoingMessage::WebrtcSignaling {
-                            game_id: game.game_id.clone(),
-                            client_id: None,
-                            description,
-                            candidate,
-                        },
-                    )
-                } else {
-                    MessagesToSend::none()
+    app_state.write_games(|games| {
+        let messages = process_incoming_message(
+            socket_id,
+            &app_state.config,
+            &app_state.metrics,
+            None,
+            games,
+            serde_json::from_value(message).unwrap(),
+        );
+        games.record_events(&messages.events);
+        messages
+    })
+}
+
+// Registers a socket like a new connection does
+#[cfg(test)]
+fn register(app_state: &AppState) -> (SocketId, mpsc::Receiver<OutgoingMessage>) {
+    let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let (socket_id, rx, _) =
+        app_state.lock_sockets(|sockets| sockets.register(&IdFormat::default(), 10, addr));
+    (socket_id, rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Message as ClientMessage;
+
+    fn socket(id: &str) -> SocketId {
+        SocketId(id.to_string())
+    }
+
+    // Processes messages like the socket handler does, without connections
+    struct Lobby {
+        config: Config,
+        metrics: Metrics,
+        games: Games,
+    }
+
+    impl Lobby {
+        fn new() -> Self {
+            Self::with_config(|_| {})
+        }
+
+        fn with_config(f: impl FnOnce(&mut Config)) -> Self {
+            let mut config = Config::from_env();
+            f(&mut config);
+            Self {
+                games: Games::new(config.hide_started_games),
+                config,
+                metrics: Metrics::new(),
+            }
+        }
+
+        fn send(&mut self, socket_id: &str, message: Value) -> Sent {
+            let message = serde_json::from_value(message).unwrap();
+            Sent(process_incoming_message(
+                &socket(socket_id),
+                &self.config,
+                &self.metrics,
+                None,
+                &mut self.games,
+                message,
+            ))
+        }
+
+        fn disconnect(&mut self, socket_id: &str) -> Sent {
+            Sent(process_disconnect(&socket(socket_id), &mut self.games))
+        }
+
+        // Creates a game with the given fields on top of the required ones
+        fn create_game(&mut self, host: &str, fields: Value) -> GameId {
+            let mut message = json!({
+                "type": "createGame",
+                "serverName": "Test game",
+                "maxPlayers": 4,
+            });
+            message
+                .as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            let reply = self.send(host, message).reply();
+            assert_eq!(reply["type"], "gameCreated", "{}", reply);
+            serde_json::from_value(reply["gameId"].clone()).unwrap()
+        }
+
+        // Joins and gets accepted by the host
+        fn join_accepted(&mut self, client: &str, game_id: &GameId) {
+            self.send(client, json!({"type": "joinGame", "gameId": game_id}));
+            let host = self.games.get_game(game_id).unwrap().host.0.clone();
+            let accepted = self.send(
+                &host,
+                json!({"type": "acceptJoin", "gameId": game_id, "clientId": client}),
+            );
+            assert_eq!(accepted.to(client)[0]["type"], "acceptJoin");
+        }
+    }
+
+    // Messages as serialized for their recipients
+    struct Sent(MessagesToSend);
+
+    impl Sent {
+        fn reply(&self) -> Value {
+            to_json(self.0.self_message.as_ref().expect("no reply"))
+        }
+
+        fn to(&self, socket_id: &str) -> Vec<Value> {
+            self.0
+                .other_messages
+                .iter()
+                .filter(|(recipient, _)| recipient.0 == socket_id)
+                .map(|(_, message)| to_json(message))
+                .collect()
+        }
+    }
+
+    fn to_json(message: &OutgoingMessage) -> Value {
+        serde_json::to_value(message).unwrap()
+    }
+
+    fn list(lobby: &mut Lobby, query: Value) -> Value {
+        let mut message = json!({"type": "listGames"});
+        message
+            .as_object_mut()
+            .unwrap()
+            .extend(query.as_object().unwrap().clone());
+        lobby.send("lister", message).reply()
+    }
+
+    fn listed_names(list: &Value) -> Vec<&str> {
+        list["games"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|game| game["serverName"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn only_accepted_clients_count_as_players() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.send("a", json!({"type": "joinGame", "gameId": game_id}));
+        lobby.send("b", json!({"type": "joinGame", "gameId": game_id}));
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["playerAmount"], 1);
+
+        let accepted = lobby.send(
+            "host",
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": "a"}),
+        );
+        assert_eq!(accepted.to("a")[0]["type"], "acceptJoin");
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["playerAmount"], 2);
+
+        // Players reported by the host no longer count
+        lobby.send(
+            "host",
+            json!({"type": "updateGameInfo", "serverName": "Test game", "maxPlayers": 4, "playerAmount": 4}),
+        );
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["playerAmount"], 2);
+    }
+
+    #[test]
+    fn only_the_host_accepts_pending_joins() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.send("a", json!({"type": "joinGame", "gameId": game_id}));
+        let accepted = lobby.send(
+            "b",
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": "a"}),
+        );
+        assert_eq!(accepted.reply()["code"], "notHost");
+        let accepted = lobby.send(
+            "host",
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": "c"}),
+        );
+        assert_eq!(accepted.reply()["code"], "notPending");
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["playerAmount"], 1);
+    }
+
+    #[test]
+    fn games_without_a_region_get_the_inferred_one() {
+        let mut games = Games::new(false);
+        let hint = "eu-north".to_string();
+        for (host, region) in [("inferred", None), ("explicit", Some("eu-west"))] {
+            let message = json!({
+                "type": "createGame",
+                "serverName": host,
+                "maxPlayers": 4,
+                "region": region,
+            });
+            process_incoming_message(
+                &socket(host),
+                &Config::from_env(),
+                &Metrics::new(),
+                Some(&hint),
+                &mut games,
+                serde_json::from_value(message).unwrap(),
+            );
+        }
+        let mut lobby = Lobby::new();
+        lobby.games = games;
+        let listed = list(&mut lobby, json!({}));
+        assert_eq!(listed["games"][0]["region"], "eu-north");
+        assert_eq!(listed["games"][1]["region"], "eu-west");
+    }
+
+    // Reserves slots in the host's game, returning the invite tokens
+    fn reserve_slots(lobby: &mut Lobby, game_id: &GameId, count: u32) -> Vec<Value> {
+        let reserved = lobby.send(
+            "host",
+            json!({"type": "reserveSlot", "gameId": game_id, "count": count}),
+        );
+        reserved.reply()["inviteTokens"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn reservations_block_public_joins_but_not_invited_ones() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"maxPlayers": 3}));
+        let tokens = reserve_slots(&mut lobby, &game_id, 2);
+        let joined = lobby.send("public", json!({"type": "joinGame", "gameId": game_id}));
+        assert_eq!(joined.reply()["code"], "gameFull");
+        let invited = json!({"type": "joinGame", "gameId": game_id, "inviteToken": tokens[0]});
+        assert_eq!(
+            lobby.send("invited", invited.clone()).to("host")[0]["type"],
+            "newClient"
+        );
+        let reused = lobby.send("other", invited);
+        assert_eq!(reused.reply()["code"], "invalidInviteToken");
+    }
+
+    #[test]
+    fn invited_joiners_keep_the_reservation_until_accepted() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"maxPlayers": 2}));
+        let tokens = reserve_slots(&mut lobby, &game_id, 1);
+        let invited = json!({"type": "joinGame", "gameId": game_id, "inviteToken": tokens[0]});
+        // Rejected and disconnected joiners hand the slot back to the invite
+        lobby.send("rejected", invited.clone());
+        lobby.send(
+            "host",
+            json!({"type": "rejectJoin", "gameId": game_id, "clientId": "rejected", "reason": "no"}),
+        );
+        lobby.send("disconnected", invited.clone());
+        lobby.disconnect("disconnected");
+        let joined = lobby.send("public", json!({"type": "joinGame", "gameId": game_id}));
+        assert_eq!(joined.reply()["code"], "gameFull");
+        // Accepted ones use it up
+        lobby.send("accepted", invited.clone());
+        lobby.send(
+            "host",
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": "accepted"}),
+        );
+        lobby.disconnect("accepted");
+        let reused = lobby.send("other", invited);
+        assert_eq!(reused.reply()["code"], "invalidInviteToken");
+    }
+
+    #[test]
+    fn released_slots_are_free_for_public_joins() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"maxPlayers": 2}));
+        let tokens = reserve_slots(&mut lobby, &game_id, 1);
+        let reserved = lobby.send(
+            "host",
+            json!({"type": "reserveSlot", "gameId": game_id, "count": 1}),
+        );
+        assert_eq!(reserved.reply()["code"], "notEnoughSlots");
+        let released = lobby.send(
+            "host",
+            json!({"type": "releaseSlot", "gameId": game_id, "inviteToken": tokens[0]}),
+        );
+        assert!(released.0.self_message.is_none());
+        let joined = lobby.send("public", json!({"type": "joinGame", "gameId": game_id}));
+        assert_eq!(joined.to("host")[0]["type"], "newClient");
+    }
+
+    #[test]
+    fn join_requests_are_published_as_events() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        let joined = lobby.send("a", json!({"type": "joinGame", "gameId": game_id}));
+        let events: Vec<Value> = joined
+            .0
+            .events
+            .iter()
+            .map(|event| serde_json::to_value(event).unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            [json!({"type": "joinRequested", "gameId": game_id, "clientId": "a"})]
+        );
+    }
+
+    // Serves the lobby on a local port like main does
+    async fn serve(config: Config) -> (AppState, SocketAddr) {
+        let app_state = AppState::new(config);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app(&app_state).into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(server);
+        (app_state, addr)
+    }
+
+    type ClientSocket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    // A WebSocket client of a served lobby
+    struct Connection {
+        socket: ClientSocket,
+        socket_id: String,
+    }
+
+    impl Connection {
+        // Connects and reads the welcome messages
+        async fn open(addr: SocketAddr) -> Self {
+            let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+                .await
+                .unwrap();
+            let mut connection = Self {
+                socket,
+                socket_id: String::new(),
+            };
+            let welcome = connection.recv().await;
+            assert_eq!(welcome["type"], "welcome");
+            connection.socket_id = welcome["socketId"].as_str().unwrap().to_string();
+            assert_eq!(connection.recv().await["type"], "serverHello");
+            connection
+        }
+
+        async fn send(&mut self, message: Value) {
+            self.socket
+                .send(ClientMessage::Text(message.to_string()))
+                .await
+                .unwrap();
+        }
+
+        // Next JSON message, skipping control frames
+        async fn recv(&mut self) -> Value {
+            recv_json(&mut self.socket).await
+        }
+
+        // Reads on until the close frame, returning its code
+        async fn closed(&mut self) -> u16 {
+            loop {
+                let message = tokio::time::timeout(Duration::from_secs(5), self.socket.next())
+                    .await
+                    .expect("no close in time")
+                    .expect("socket closed without a close frame")
+                    .unwrap();
+                if let ClientMessage::Close(frame) = message {
+                    return frame.map_or(close_code::STATUS, |frame| frame.code.into());
+                }
+            }
+        }
+    }
+
+    async fn recv_json(socket: &mut ClientSocket) -> Value {
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+                .await
+                .expect("no message in time")
+                .expect("socket closed")
+                .unwrap();
+            if let ClientMessage::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_event_subscribers_see_games_created() {
+        let mut config = Config::from_env();
+        config.admin_token = Some("secret".to_string());
+        let (_, addr) = serve(config).await;
+        let url = format!("ws://{}/admin/events", addr);
+        assert!(tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .is_err());
+        let mut request = url.into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let (mut events, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+        let mut host = Connection::open(addr).await;
+        let connected = recv_json(&mut events).await;
+        assert_eq!(connected["type"], "connected");
+        let host_id = connected["socketId"].clone();
+        host.send(json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4}))
+            .await;
+        let game_id = host.recv().await["gameId"].clone();
+        assert_eq!(
+            recv_json(&mut events).await,
+            json!({"type": "gameCreated", "gameId": game_id, "host": host_id}),
+        );
+    }
+
+    #[test]
+    fn deeply_nested_or_large_signaling_is_rejected() {
+        let mut lobby = Lobby::with_config(|config| {
+            config.max_signaling_depth = 4;
+            config.max_signaling_elements = 8;
+        });
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("client", &game_id);
+        let mut signal = |description: Value| {
+            lobby.send(
+                "client",
+                json!({"type": "webrtcSignaling", "description": description}),
+            )
+        };
+
+        let description = json!({"type": "offer", "sdp": "v=0"});
+        let sent = signal(description.clone());
+        assert_eq!(sent.to("host")[0]["description"], description);
+        let nested = (0..4).fold(json!("deep"), |value, _| json!([value]));
+        assert_eq!(signal(nested).reply()["code"], "signalingTooLarge");
+        let large = json!({"candidates": vec!["candidate"; 8]});
+        assert_eq!(signal(large).reply()["code"], "signalingTooLarge");
+    }
+
+    #[tokio::test]
+    async fn deprecated_messages_are_warned_about_once() {
+        let (_, addr) = serve(Config::from_env()).await;
+        let mut host = Connection::open(addr).await;
+        host.send(json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4}))
+            .await;
+        assert_eq!(host.recv().await["type"], "gameCreated");
+        let update = json!({"type": "updateGameInfo", "serverName": "Renamed", "maxPlayers": 4});
+        host.send(update.clone()).await;
+        assert_eq!(
+            host.recv().await,
+            json!({"type": "deprecation", "messageType": "updateGameInfo", "replacement": "patchGameInfo"}),
+        );
+        host.send(update).await;
+        host.send(json!({"type": "listGames"})).await;
+        assert_eq!(host.recv().await["type"], "gameList");
+    }
+
+    #[test]
+    fn patches_only_change_the_given_fields() {
+        let mut lobby = Lobby::new();
+        lobby.create_game("host", json!({"requiresPassword": true}));
+        lobby.send("host", json!({"type": "patchGameInfo", "maxPlayers": 8}));
+        let game = &list(&mut lobby, json!({}))["games"][0];
+        assert_eq!(game["serverName"], "Test game");
+        assert_eq!(game["maxPlayers"], 8);
+        assert_eq!(game["requiresPassword"], true);
+    }
+
+    #[test]
+    fn advertised_incoming_types_match_the_variants() {
+        // Serde lists every variant when rejecting an unknown one
+        let err = serde_json::from_value::<IncomingMessage>(json!({"type": "noSuchType"}))
+            .unwrap_err()
+            .to_string();
+        let (_, expected) = err.split_once("expected one of ").unwrap();
+        let mut variants: Vec<&str> = expected
+            .split(", ")
+            .map(|variant| variant.trim_matches('`'))
+            .collect();
+        let mut advertised = INCOMING_MESSAGE_TYPES.to_vec();
+        variants.sort_unstable();
+        advertised.sort_unstable();
+        assert_eq!(advertised, variants);
+
+        let game = json!("game");
+        let client = json!("client");
+        let samples = [
+            json!({"type": "webrtcSignaling"}),
+            json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4}),
+            json!({"type": "updateGameInfo", "serverName": "Test game", "maxPlayers": 4}),
+            json!({"type": "patchGameInfo"}),
+            json!({"type": "listGames"}),
+            json!({"type": "getGame", "gameId": game}),
+            json!({"type": "listClients", "gameId": game}),
+            json!({"type": "setName", "name": "Player"}),
+            json!({"type": "joinGame", "gameId": game}),
+            json!({"type": "joinAsSpectator", "gameId": game}),
+            json!({"type": "quickMatch"}),
+            json!({"type": "acceptJoin", "gameId": game, "clientId": client}),
+            json!({"type": "rejectJoin", "gameId": game, "clientId": client, "reason": "full"}),
+            json!({"type": "reserveSlot", "gameId": game, "count": 1}),
+            json!({"type": "releaseSlot", "gameId": game, "inviteToken": "token"}),
+            json!({"type": "leaveGame", "gameId": game}),
+            json!({"type": "kickPlayer", "gameId": game, "clientId": client}),
+            json!({"type": "addCoHost", "gameId": game, "clientId": client}),
+            json!({"type": "subscribeGameList"}),
+            json!({"type": "unsubscribeGameList"}),
+            json!({"type": "setReady", "gameId": game, "ready": true}),
+            json!({"type": "startGame", "gameId": game}),
+            json!({"type": "pingClients", "gameId": game}),
+            json!({"type": "pongHost", "gameId": game}),
+            json!({"type": "chatMessage", "gameId": game, "text": "hello"}),
+            json!({"type": "broadcast", "gameId": game, "payload": {}}),
+            json!({"type": "resume", "token": "token", "lastSeq": 1}),
+            json!({"type": "capabilities"}),
+        ];
+        assert_eq!(samples.len(), INCOMING_MESSAGE_TYPES.len());
+        for sample in samples {
+            let message: IncomingMessage = serde_json::from_value(sample.clone()).unwrap();
+            assert_eq!(message.message_type(), sample["type"]);
+        }
+    }
+
+    #[test]
+    fn advertised_outgoing_types_match_the_variants() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        let client_id = socket("client");
+        let samples = [
+            OutgoingMessage::error(ErrorCode::NotHost),
+            OutgoingMessage::Welcome {
+                socket_id: client_id.clone(),
+                resume_token: None,
+            },
+            OutgoingMessage::ServerHello {
+                version: "1.0.0",
+                max_players_limit: 32,
+                features: Vec::new(),
+            },
+            OutgoingMessage::Resumed {
+                socket_id: client_id.clone(),
+                resume_token: ResumeToken("token".to_string()),
+                seq: Some(1),
+                caught_up: true,
+            },
+            OutgoingMessage::WebrtcSignaling {
+                game_id: game_id.clone(),
+                client_id: None,
+                description: None,
+                candidate: None,
+            },
+            OutgoingMessage::GameCreated {
+                game_id: game_id.clone(),
+                ice_servers: Vec::new(),
+            },
+            OutgoingMessage::GameList {
+                games: lobby.games.snapshot(),
+                total: 1,
+            },
+            OutgoingMessage::GameInfo {
+                game: lobby.games.snapshot()[0].clone(),
+            },
+            OutgoingMessage::ClientList {
+                game_id: game_id.clone(),
+                clients: Vec::new(),
+            },
+            OutgoingMessage::GameListUpdated {
+                games: lobby.games.snapshot(),
+            },
+            OutgoingMessage::GameInfoChanged {
+                game: lobby.games.snapshot()[0].clone(),
+            },
+            OutgoingMessage::GameInfoUpdated {
+                game: lobby.games.snapshot()[0].clone(),
+                seq: 1,
+            },
+            OutgoingMessage::GameRemoved {
+                game_id: game_id.clone(),
+            },
+            OutgoingMessage::NewClient {
+                game_id: game_id.clone(),
+                client_id: client_id.clone(),
+                name: None,
+                password: None,
+                accepted: false,
+                seq: 1,
+            },
+            OutgoingMessage::NewSpectator {
+                game_id: game_id.clone(),
+                client_id: client_id.clone(),
+                seq: 1,
+            },
+            OutgoingMessage::NoMatch,
+            OutgoingMessage::MeshPeers {
+                game_id: game_id.clone(),
+                peers: Vec::new(),
+            },
+            OutgoingMessage::PeerJoined {
+                game_id: game_id.clone(),
+                client_id: client_id.clone(),
+            },
+            OutgoingMessage::AcceptJoin {
+                game_id: game_id.clone(),
+                seq: 1,
+                ice_servers: Vec::new(),
+            },
+            OutgoingMessage::RejectJoin {
+                game_id: game_id.clone(),
+                reason: "full".to_string(),
+                seq: 1,
+            },
+            OutgoingMessage::GameClosed {
+                game_id: game_id.clone(),
+                reason: "evicted".to_string(),
+            },
+            OutgoingMessage::Deprecation {
+                message_type: "updateGameInfo",
+                replacement: "patchGameInfo",
+            },
+            OutgoingMessage::SlotsReserved {
+                game_id: game_id.clone(),
+                invite_tokens: vec![InviteToken::random()],
+            },
+            OutgoingMessage::HostPing {
+                game_id: game_id.clone(),
+            },
+            OutgoingMessage::ClientLiveness {
+                game_id: game_id.clone(),
+                responsive: vec![client_id.clone()],
+                unresponsive: Vec::new(),
+            },
+            OutgoingMessage::NotReady {
+                game_id: game_id.clone(),
+                not_ready: vec![client_id.clone()],
+            },
+            OutgoingMessage::LobbyState {
+                game_id: game_id.clone(),
+                ready: vec![(client_id.clone(), true)],
+            },
+            OutgoingMessage::GameStarting {
+                game_id: game_id.clone(),
+                countdown: 3,
+                seq: 1,
+            },
+            OutgoingMessage::BecameHost {
+                game_id: game_id.clone(),
+                seq: 1,
+            },
+            OutgoingMessage::BecameCoHost {
+                game_id: game_id.clone(),
+                seq: 1,
+            },
+            OutgoingMessage::HostChanged {
+                game_id: game_id.clone(),
+                host_id: client_id.clone(),
+                seq: 1,
+            },
+            OutgoingMessage::ClientLeft {
+                game_id: game_id.clone(),
+                client_id: client_id.clone(),
+                seq: 1,
+            },
+            OutgoingMessage::Kicked {
+                game_id: game_id.clone(),
+                reason: None,
+                seq: 1,
+            },
+            OutgoingMessage::ServerShutdown {
+                reason: "Server restarting".to_string(),
+            },
+            OutgoingMessage::ChatMessage {
+                game_id: game_id.clone(),
+                sender_id: client_id.clone(),
+                sender_name: None,
+                text: "hello".to_string(),
+                seq: 1,
+            },
+            OutgoingMessage::HostBroadcast {
+                game_id: game_id.clone(),
+                payload: json!({}),
+            },
+            OutgoingMessage::Latency { ms: 10 },
+            OutgoingMessage::Capabilities {
+                incoming: INCOMING_MESSAGE_TYPES,
+                outgoing: OUTGOING_MESSAGE_TYPES,
+                protocol_version: 1,
+            },
+        ];
+        let types: Vec<&str> = samples.iter().map(OutgoingMessage::message_type).collect();
+        assert_eq!(types, OUTGOING_MESSAGE_TYPES);
+        for sample in &samples {
+            assert_eq!(to_json(sample)["type"], sample.message_type());
+        }
+    }
+
+    #[test]
+    fn rejoining_tells_pending_and_accepted_apart() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        let join = json!({"type": "joinGame", "gameId": game_id});
+        lobby.send("client", join.clone());
+        assert_eq!(
+            lobby.send("client", join.clone()).reply()["code"],
+            "joinPending"
+        );
+        lobby.send(
+            "host",
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": "client"}),
+        );
+        assert_eq!(lobby.send("client", join).reply()["code"], "alreadyJoined");
+    }
+
+    #[test]
+    fn a_panic_while_locked_doesnt_brick_the_state() {
+        let app_state = AppState::new(Config::from_env());
+        let create_game = json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4});
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            app_state.write_games(|games| {
+                process_incoming_message(
+                    &socket("host"),
+                    &app_state.config,
+                    &app_state.metrics,
+                    None,
+                    games,
+                    serde_json::from_value(create_game).unwrap(),
+                );
+                panic!("handler panicked");
+            })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(app_state.read_games(|games| games.len()), 1);
+        assert!(!app_state.games.is_poisoned());
+
+        let mutex = Mutex::new(1);
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = mutex.lock().unwrap();
+            panic!("handler panicked");
+        });
+        *lock_recovering(&mutex, "test") += 1;
+        assert_eq!(*lock_recovering(&mutex, "test"), 2);
+    }
+
+    fn quick_match(lobby: &mut Lobby, matcher: &str, fields: Value) -> Sent {
+        let mut message = json!({"type": "quickMatch"});
+        message
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        lobby.send(matcher, message)
+    }
+
+    #[test]
+    fn quick_match_skips_games_it_cant_join() {
+        let mut lobby = Lobby::new();
+        let full = lobby.create_game("full host", json!({"maxPlayers": 2}));
+        lobby.send("client", json!({"type": "joinGame", "gameId": full}));
+        let locked = lobby.create_game("locked host", json!({"requiresPassword": true}));
+        let unmatched = quick_match(&mut lobby, "matcher", json!({}));
+        assert_eq!(unmatched.reply()["type"], "noMatch");
+        let matched = quick_match(&mut lobby, "matcher", json!({"password": "secret"}));
+        let requested = matched.to("locked host");
+        assert_eq!(requested[0]["gameId"], json!(locked));
+        assert_eq!(requested[0]["password"], "secret");
+
+        let open = lobby.create_game("open host", json!({"region": "eu-north"}));
+        let matched = quick_match(&mut lobby, "other matcher", json!({}));
+        assert_eq!(matched.to("open host")[0]["gameId"], json!(open));
+        let filtered = json!({"filters": {"region": "eu-west"}});
+        let unmatched = quick_match(&mut lobby, "third matcher", filtered);
+        assert_eq!(unmatched.reply()["type"], "noMatch");
+    }
+
+    #[test]
+    fn game_events_are_numbered_in_order() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        let joined = lobby.send("a", json!({"type": "joinGame", "gameId": game_id}));
+        assert_eq!(joined.to("host")[0]["seq"], 1);
+        let accepted = lobby.send(
+            "host",
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": "a"}),
+        );
+        assert_eq!(accepted.to("a")[0]["seq"], 2);
+        lobby.send("b", json!({"type": "joinGame", "gameId": game_id}));
+        let reject =
+            json!({"type": "rejectJoin", "gameId": game_id, "clientId": "b", "reason": "no"});
+        let rejected = lobby.send("a", reject.clone());
+        assert_eq!(rejected.reply()["code"], "notHost");
+        let rejected = lobby.send("host", reject);
+        assert_eq!(rejected.to("b")[0]["seq"], 4);
+    }
+
+    #[test]
+    fn generated_ids_have_the_configured_format() {
+        let format = || IdFormat {
+            length: 6,
+            alphabet: Some(vec!['A', 'B', 'C']),
+        };
+        let mut lobby = Lobby::with_config(|config| config.game_id_format = format());
+        let game_id = lobby.create_game("host", json!({}));
+        let socket_id = SocketId::random(&format());
+        for id in [game_id.0, socket_id.0] {
+            assert_eq!(id.len(), 6);
+            assert!(id.chars().all(|c| "ABC".contains(c)), "{}", id);
+        }
+        let default_id = SocketId::random(&IdFormat::default()).0;
+        assert_eq!(default_id.len(), 16);
+        assert!(default_id.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[test]
+    fn the_list_snapshot_follows_mutations() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        let snapshot = lobby.games.snapshot();
+        assert!(Arc::ptr_eq(&snapshot, &lobby.games.snapshot()));
+        assert_eq!(snapshot[0].player_amount, 1);
+
+        lobby.join_accepted("client", &game_id);
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["playerAmount"], 2);
+        lobby.send(
+            "host",
+            json!({"type": "patchGameInfo", "serverName": "Renamed"}),
+        );
+        assert_eq!(
+            list(&mut lobby, json!({}))["games"][0]["serverName"],
+            "Renamed"
+        );
+        lobby.disconnect("client");
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["playerAmount"], 1);
+        lobby.disconnect("host");
+        assert_eq!(list(&mut lobby, json!({}))["games"], json!([]));
+    }
+
+    #[test]
+    fn idle_games_are_evicted_at_the_games_cap() {
+        let create = json!({"type": "createGame", "serverName": "New game", "maxPlayers": 4});
+        let mut lobby = Lobby::with_config(|config| config.max_games = Some(1));
+        lobby.create_game("idle host", json!({}));
+        let created = lobby.send("host", create.clone());
+        assert_eq!(created.reply()["code"], "serverFull");
+
+        let mut lobby = Lobby::with_config(|config| {
+            config.max_games = Some(2);
+            config.evict_idle_games = true;
+        });
+        let idle = lobby.create_game("idle host", json!({}));
+        let busy = lobby.create_game("busy host", json!({}));
+        lobby.send("client", json!({"type": "joinGame", "gameId": busy}));
+        let created = lobby.send("host", create.clone());
+        assert_eq!(created.reply()["type"], "gameCreated");
+        assert_eq!(
+            created.to("idle host"),
+            [json!({"type": "gameClosed", "gameId": idle, "reason": "evicted"})],
+        );
+        // Games with members are never evicted
+        lobby.send(
+            "other client",
+            json!({"type": "joinGame", "gameId": created.reply()["gameId"]}),
+        );
+        let created = lobby.send("third host", create);
+        assert_eq!(created.reply()["code"], "serverFull");
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_text_frames_get_an_encoding_error() {
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+        use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+
+        let (_, addr) = serve(Config::from_env()).await;
+        let mut connection = Connection::open(addr).await;
+        let frame = Frame::message(vec![0xff, 0xfe], OpCode::Data(Data::Text), true);
+        connection
+            .socket
+            .send(ClientMessage::Frame(frame))
+            .await
+            .unwrap();
+        assert_eq!(connection.recv().await["code"], "invalidEncoding");
+        assert_eq!(connection.closed().await, close_code::INVALID);
+    }
+
+    #[test]
+    fn join_policies_decide_how_joins_are_let_in() {
+        let mut lobby = Lobby::new();
+        let join = |game_id: &GameId| json!({"type": "joinGame", "gameId": game_id});
+
+        let open = lobby.create_game("open host", json!({"joinPolicy": "open"}));
+        let joined = lobby.send("a", join(&open));
+        assert_eq!(joined.reply()["type"], "acceptJoin");
+        assert_eq!(joined.to("open host")[0]["accepted"], true);
+
+        let approval = lobby.create_game("approval host", json!({}));
+        let joined = lobby.send("b", join(&approval));
+        assert!(joined.0.self_message.is_none());
+        assert_eq!(joined.to("approval host")[0]["accepted"], false);
+
+        let closed = lobby.create_game("host", json!({"joinPolicy": "closed"}));
+        assert_eq!(lobby.send("c", join(&closed)).reply()["code"], "gameClosed");
+        let tokens = reserve_slots(&mut lobby, &closed, 1);
+        let invited = lobby.send(
+            "c",
+            json!({"type": "joinGame", "gameId": closed, "inviteToken": tokens[0]}),
+        );
+        assert_eq!(invited.reply()["type"], "acceptJoin");
+    }
+
+    #[test]
+    fn password_games_keep_requiring_it_after_updates() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game(
+            "host",
+            json!({"joinPolicy": "password", "password": "secret"}),
+        );
+        lobby.send(
+            "host",
+            json!({"type": "updateGameInfo", "serverName": "Renamed", "maxPlayers": 4}),
+        );
+        lobby.send(
+            "host",
+            json!({"type": "patchGameInfo", "requiresPassword": false}),
+        );
+        assert_eq!(
+            list(&mut lobby, json!({}))["games"][0]["requiresPassword"],
+            true
+        );
+        let joined = lobby.send("client", json!({"type": "joinGame", "gameId": game_id}));
+        assert_eq!(joined.reply()["code"], "wrongPassword");
+        let joined = lobby.send(
+            "client",
+            json!({"type": "joinGame", "gameId": game_id, "password": "secret"}),
+        );
+        assert_eq!(joined.reply()["type"], "acceptJoin");
+    }
+
+    #[test]
+    fn updates_keep_requires_password_when_unset() {
+        let mut lobby = Lobby::new();
+        lobby.create_game("host", json!({"requiresPassword": true}));
+        lobby.send(
+            "host",
+            json!({"type": "updateGameInfo", "serverName": "Renamed", "maxPlayers": 4}),
+        );
+        assert_eq!(
+            list(&mut lobby, json!({}))["games"][0]["requiresPassword"],
+            true
+        );
+    }
+
+    fn signal(lobby: &mut Lobby, from: &str, to: &str) -> Sent {
+        lobby.send(
+            from,
+            json!({"type": "webrtcSignaling", "clientId": to, "description": {"sdp": "offer"}}),
+        )
+    }
+
+    #[test]
+    fn signaling_is_relayed_within_a_game() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"mesh": true}));
+        lobby.join_accepted("a", &game_id);
+        lobby.join_accepted("b", &game_id);
+
+        let sent = signal(&mut lobby, "host", "a");
+        assert_eq!(sent.to("a")[0]["type"], "webrtcSignaling");
+        assert_eq!(sent.to("a")[0]["clientId"], Value::Null);
+
+        let sent = signal(&mut lobby, "a", "b");
+        assert_eq!(sent.to("b")[0]["clientId"], "a");
+
+        let sent = lobby.send(
+            "a",
+            json!({"type": "webrtcSignaling", "description": {"sdp": "answer"}}),
+        );
+        assert_eq!(sent.to("host")[0]["clientId"], "a");
+    }
+
+    #[test]
+    fn signaling_is_not_relayed_across_games() {
+        let mut lobby = Lobby::new();
+        let first = lobby.create_game("host1", json!({"mesh": true}));
+        let second = lobby.create_game("host2", json!({"mesh": true}));
+        lobby.join_accepted("a", &first);
+        lobby.join_accepted("b", &second);
+
+        assert!(signal(&mut lobby, "host1", "b").to("b").is_empty());
+        assert!(signal(&mut lobby, "a", "b").to("b").is_empty());
+        assert!(signal(&mut lobby, "a", "host2").to("host2").is_empty());
+    }
+
+    // A half full game against an emptier one, which the fill weight prefers
+    // or avoids per its sign
+    fn quick_match_lobby(fill: f64) -> (Lobby, GameId, GameId) {
+        let mut lobby = Lobby::with_config(|config| {
+            config.quick_match_weights = QuickMatchWeights {
+                fill,
+                freshness: 0.5,
+            }
+        });
+        let half = lobby.create_game("host", json!({"maxPlayers": 4, "joinPolicy": "open"}));
+        lobby.send("a", json!({"type": "joinGame", "gameId": half}));
+        let emptier = lobby.create_game("other", json!({"maxPlayers": 8, "joinPolicy": "open"}));
+        (lobby, half, emptier)
+    }
+
+    #[test]
+    fn quick_match_picks_by_the_configured_weights() {
+        let (mut lobby, half, _) = quick_match_lobby(1.0);
+        let matched = quick_match(&mut lobby, "matcher", json!({}));
+        assert_eq!(matched.reply()["gameId"], json!(half));
+        let (mut lobby, _, emptier) = quick_match_lobby(-1.0);
+        let matched = quick_match(&mut lobby, "matcher", json!({}));
+        assert_eq!(matched.reply()["gameId"], json!(emptier));
+    }
+
+    #[test]
+    fn liveness_checks_report_silent_clients() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("responsive", &game_id);
+        lobby.join_accepted("silent", &game_id);
+        let ping = json!({"type": "pingClients", "gameId": game_id});
+        let pinged = lobby.send("host", ping.clone());
+        assert_eq!(pinged.0.liveness_check.as_ref(), Some(&game_id));
+        for client in ["responsive", "silent"] {
+            assert_eq!(
+                pinged.to(client),
+                [json!({"type": "hostPing", "gameId": game_id})]
+            );
+        }
+        assert_eq!(
+            lobby.send("host", ping).reply()["code"],
+            "livenessCheckRunning"
+        );
+        lobby.send("responsive", json!({"type": "pongHost", "gameId": game_id}));
+
+        let (host, report) = lobby.games.finish_liveness_check(&game_id).unwrap();
+        assert_eq!(host, socket("host"));
+        assert_eq!(
+            to_json(&report),
+            json!({
+                "type": "clientLiveness",
+                "gameId": game_id,
+                "responsive": ["responsive"],
+                "unresponsive": ["silent"],
+            }),
+        );
+        assert!(lobby.games.finish_liveness_check(&game_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_lifetime_byte_limit_are_closed() {
+        let capabilities = json!({"type": "capabilities"});
+        let mut config = Config::from_env();
+        config.max_connection_bytes = Some(2 * capabilities.to_string().len() as u64);
+        let (_, addr) = serve(config).await;
+        let mut connection = Connection::open(addr).await;
+        for _ in 0..2 {
+            connection.send(capabilities.clone()).await;
+            assert_eq!(connection.recv().await["type"], "capabilities");
+        }
+        connection.send(capabilities).await;
+        assert_eq!(connection.closed().await, close_code::POLICY);
+    }
+
+    // A game with two accepted clients, of which only "a" is ready
+    fn half_ready_lobby(start_quorum_percent: u32) -> (Lobby, GameId) {
+        let mut lobby = Lobby::with_config(|config| {
+            config.start_quorum_percent = start_quorum_percent;
+        });
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("a", &game_id);
+        lobby.join_accepted("b", &game_id);
+        lobby.send(
+            "a",
+            json!({"type": "setReady", "gameId": game_id, "ready": true}),
+        );
+        (lobby, game_id)
+    }
+
+    #[test]
+    fn games_start_only_when_everyone_is_ready() {
+        let (mut lobby, game_id) = half_ready_lobby(100);
+        let start = json!({"type": "startGame", "gameId": game_id});
+        assert_eq!(
+            lobby.send("host", start.clone()).reply(),
+            json!({"type": "notReady", "gameId": game_id, "notReady": ["b"]}),
+        );
+        lobby.send(
+            "b",
+            json!({"type": "setReady", "gameId": game_id, "ready": true}),
+        );
+        let started = lobby.send("host", start.clone());
+        assert_eq!(started.reply()["type"], "gameStarting");
+        for client in ["a", "b"] {
+            assert_eq!(started.to(client), [started.reply()]);
+        }
+        assert_eq!(lobby.send("host", start).reply()["code"], "alreadyStarting");
+        let joined = lobby.send("late", json!({"type": "joinGame", "gameId": game_id}));
+        assert_eq!(joined.reply()["code"], "gameStarted");
+    }
+
+    #[test]
+    fn games_start_once_the_quorum_is_ready() {
+        let (mut lobby, game_id) = half_ready_lobby(50);
+        let started = lobby.send("host", json!({"type": "startGame", "gameId": game_id}));
+        assert_eq!(started.reply()["type"], "gameStarting");
+    }
+
+    #[test]
+    fn passwords_are_checked_by_the_server() {
+        let mut lobby = Lobby::new();
+        let locked = lobby.create_game(
+            "locked host",
+            json!({"joinPolicy": "open", "password": "secret"}),
+        );
+        let join =
+            |password: Value| json!({"type": "joinGame", "gameId": locked, "password": password});
+        assert_eq!(
+            lobby.send("a", join(Value::Null)).reply()["code"],
+            "wrongPassword"
+        );
+        assert_eq!(
+            lobby.send("a", join(json!(""))).reply()["code"],
+            "wrongPassword"
+        );
+        assert_eq!(
+            lobby.send("a", join(json!("guess"))).reply()["code"],
+            "wrongPassword"
+        );
+        assert_eq!(
+            lobby.send("a", join(json!("secret"))).reply()["type"],
+            "acceptJoin"
+        );
+
+        let unlocked = lobby.create_game(
+            "unlocked host",
+            json!({"joinPolicy": "open", "password": "secret", "requiresPassword": false}),
+        );
+        let joined = lobby.send(
+            "b",
+            json!({"type": "joinGame", "gameId": unlocked, "password": "guess"}),
+        );
+        assert_eq!(joined.reply()["type"], "acceptJoin");
+    }
+
+    #[test]
+    fn joins_beyond_max_players_are_rejected() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"maxPlayers": 2}));
+        lobby.join_accepted("a", &game_id);
+
+        let joined = lobby.send("b", json!({"type": "joinGame", "gameId": game_id}));
+        assert_eq!(joined.reply()["code"], "gameFull");
+        assert!(joined.to("host").is_empty());
+        assert_eq!(
+            lobby
+                .games
+                .get_game_by_host(&socket("host"))
+                .unwrap()
+                .occupancy()
+                .players,
+            2
+        );
+    }
+
+    #[test]
+    fn player_amounts_follow_joins_and_disconnects() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"joinPolicy": "open"}));
+        let player_amount =
+            |lobby: &mut Lobby| list(lobby, json!({}))["games"][0]["playerAmount"].clone();
+        for client in ["a", "b"] {
+            lobby.send(client, json!({"type": "joinGame", "gameId": game_id}));
+        }
+        assert_eq!(player_amount(&mut lobby), 3);
+        lobby.disconnect("a");
+        assert_eq!(player_amount(&mut lobby), 2);
+        lobby.send(
+            "host",
+            json!({"type": "updateGameInfo", "serverName": "Renamed", "maxPlayers": 8}),
+        );
+        assert_eq!(player_amount(&mut lobby), 2);
+        lobby.send("a", json!({"type": "joinGame", "gameId": game_id}));
+        lobby.disconnect("b");
+        assert_eq!(player_amount(&mut lobby), 2);
+        lobby.disconnect("a");
+        assert_eq!(player_amount(&mut lobby), 1);
+    }
+
+    fn is_registered(app_state: &AppState, connection: &Connection) -> bool {
+        let socket_id = socket(&connection.socket_id);
+        app_state.lock_sockets(|sockets| sockets.get_entry(&socket_id).is_some())
+    }
+
+    #[tokio::test]
+    async fn sockets_not_answering_pings_are_dropped() {
+        let mut config = Config::from_env();
+        config.heartbeat_interval = Duration::from_millis(50);
+        let (app_state, addr) = serve(config).await;
+        let silent = Connection::open(addr).await;
+        let mut responsive = Connection::open(addr).await;
+        // Reading the socket answers the pings
+        let _ = tokio::time::timeout(Duration::from_millis(400), async {
+            while let Some(Ok(_)) = responsive.socket.next().await {}
+        })
+        .await;
+        assert!(!is_registered(&app_state, &silent));
+        assert!(is_registered(&app_state, &responsive));
+    }
+
+    #[tokio::test]
+    async fn idle_sockets_outside_games_are_closed() {
+        let mut config = Config::from_env();
+        config.idle_timeout = Duration::from_millis(200);
+        let (_, addr) = serve(config).await;
+        let mut idle = Connection::open(addr).await;
+        let mut browser = Connection::open(addr).await;
+        let mut host = Connection::open(addr).await;
+        host.send(json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4}))
+            .await;
+        assert_eq!(host.recv().await["type"], "gameCreated");
+
+        for _ in 0..8 {
+            browser.send(json!({"type": "listGames"})).await;
+            assert_eq!(browser.recv().await["type"], "gameList");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(idle.closed().await, close_code::NORMAL);
+        host.send(json!({"type": "capabilities"})).await;
+        assert_eq!(host.recv().await["type"], "capabilities");
+    }
+
+    #[test]
+    fn every_member_is_told_when_the_host_disconnects() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("accepted", &game_id);
+        lobby.send("pending", json!({"type": "joinGame", "gameId": game_id}));
+        lobby.send(
+            "spectator",
+            json!({"type": "joinAsSpectator", "gameId": game_id}),
+        );
+        let sent = lobby.disconnect("host");
+        for member in ["accepted", "pending", "spectator"] {
+            assert_eq!(
+                sent.to(member),
+                [json!({"type": "gameClosed", "gameId": game_id, "reason": "hostLeft"})],
+            );
+        }
+        assert!(lobby.games.get_game_by_host(&socket("host")).is_none());
+    }
+
+    #[test]
+    fn migratable_games_get_a_new_host() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"migratable": true, "serverName": "Kept"}));
+        lobby.join_accepted("a", &game_id);
+        lobby.join_accepted("b", &game_id);
+        let sent = lobby.disconnect("host");
+        let new_host = lobby.games.get_game(&game_id).unwrap().host.0.clone();
+        let other = if new_host == "a" { "b" } else { "a" };
+        assert_eq!(sent.to(&new_host)[0]["type"], "becameHost");
+        let changed = &sent.to(other)[0];
+        assert_eq!(changed["type"], "hostChanged");
+        assert_eq!(changed["hostId"], new_host);
+        let listed = list(&mut lobby, json!({}));
+        assert_eq!(listed["games"][0]["serverName"], "Kept");
+        assert_eq!(listed["games"][0]["playerAmount"], 2);
+
+        // Without clients left to promote the game is closed
+        lobby.disconnect(other);
+        lobby.disconnect(&new_host);
+        assert!(lobby.games.snapshot().is_empty());
+    }
+
+    #[test]
+    fn clients_can_leave_and_join_another_game() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        let other_game_id = lobby.create_game("other host", json!({"joinPolicy": "open"}));
+        lobby.join_accepted("client", &game_id);
+        let leave = json!({"type": "leaveGame", "gameId": game_id});
+        let left = lobby.send("client", leave.clone());
+        let client_left = &left.to("host")[0];
+        assert_eq!(client_left["type"], "clientLeft");
+        assert_eq!(client_left["clientId"], "client");
+        assert_eq!(lobby.send("client", leave).reply()["code"], "notInGame");
+        let joined = lobby.send(
+            "client",
+            json!({"type": "joinGame", "gameId": other_game_id}),
+        );
+        assert_eq!(joined.reply()["type"], "acceptJoin");
+    }
+
+    #[test]
+    fn only_hosts_can_kick_players() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("a", &game_id);
+        lobby.join_accepted("b", &game_id);
+        let kick = |client_id: &str| json!({"type": "kickPlayer", "gameId": game_id, "clientId": client_id, "reason": "cheating"});
+        assert_eq!(lobby.send("a", kick("b")).reply()["code"], "notHost");
+        let kicked = lobby.send("host", kick("b"));
+        let notified = &kicked.to("b")[0];
+        assert_eq!(notified["type"], "kicked");
+        assert_eq!(notified["reason"], "cheating");
+        assert_eq!(
+            lobby.send("host", kick("b")).reply()["code"],
+            "clientNotInGame"
+        );
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["playerAmount"], 2);
+    }
+
+    #[test]
+    fn errors_carry_a_code_and_a_reason() {
+        assert_eq!(
+            to_json(&OutgoingMessage::error(ErrorCode::NotHost)),
+            json!({"type": "error", "code": "notHost", "reason": "You're not a game host"}),
+        );
+        let mut lobby = Lobby::new();
+        let updated = lobby.send(
+            "client",
+            json!({"type": "updateGameInfo", "serverName": "Test game", "maxPlayers": 4}),
+        );
+        assert_eq!(
+            updated.reply(),
+            to_json(&OutgoingMessage::error(ErrorCode::NotHost))
+        );
+    }
+
+    #[tokio::test]
+    async fn unparseable_messages_get_an_invalid_message_error() {
+        let (_, addr) = serve(Config::from_env()).await;
+        let mut connection = Connection::open(addr).await;
+        connection
+            .socket
+            .send(ClientMessage::Text("not json".to_string()))
+            .await
+            .unwrap();
+        let error = connection.recv().await;
+        assert_eq!(error["code"], "invalidMessage");
+        assert!(error["reason"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid message: "));
+    }
+
+    #[test]
+    fn join_errors_render_user_facing_reasons() {
+        let reasons = [
+            (JoinGameError::GameNotFound, "Game not found"),
+            (JoinGameError::OwnGame, "Can't join your own game"),
+            (
+                JoinGameError::InOtherGame,
+                "Already in another game, leave it first",
+            ),
+            (JoinGameError::AlreadyMember, "Already joined"),
+            (JoinGameError::JoinPending, "Join request already pending"),
+            (JoinGameError::GameFull, "Game is full"),
+            (JoinGameError::InvalidInviteToken, "Invalid invite token"),
+            (JoinGameError::GameClosed, "Game is closed"),
+            (JoinGameError::WrongPassword, "Wrong password"),
+            (JoinGameError::GameStarted, "Game already started"),
+        ];
+        for (err, reason) in reasons {
+            assert_eq!(err.to_string(), reason);
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_to_closed_connections_are_dropped() {
+        let (app_state, addr) = serve(Config::from_env()).await;
+        let (host, rx) = register(&app_state);
+        drop(rx);
+        process(
+            &app_state,
+            &host,
+            json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4, "joinPolicy": "open"}),
+        );
+        let game_id = app_state.read_games(|games| games.snapshot()[0].game_id.clone());
+        let mut client = Connection::open(addr).await;
+        // Telling the host about the join fails, the joining socket keeps running
+        client
+            .send(json!({"type": "joinGame", "gameId": game_id}))
+            .await;
+        assert_eq!(client.recv().await["type"], "acceptJoin");
+        client.send(json!({"type": "capabilities"})).await;
+        assert_eq!(client.recv().await["type"], "capabilities");
+    }
+
+    #[test]
+    fn sending_to_a_closed_connection_is_dropped() {
+        let app_state = AppState::new(Config::from_env());
+        let (socket_id, rx) = register(&app_state);
+        drop(rx);
+        app_state.send_to(&socket_id, OutgoingMessage::NoMatch);
+        assert!(app_state
+            .lock_sockets(|sockets| sockets.get(&socket_id))
+            .is_some());
+    }
+
+    #[test]
+    fn messages_to_removed_sockets_are_dropped() {
+        let app_state = AppState::new(Config::from_env());
+        let (host, mut host_rx) = register(&app_state);
+        let (client, _) = register(&app_state);
+        let created = process(
+            &app_state,
+            &host,
+            json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4}),
+        );
+        let game_id = to_json(created.self_message.as_ref().unwrap())["gameId"].clone();
+        process(
+            &app_state,
+            &client,
+            json!({"type": "joinGame", "gameId": game_id}),
+        );
+        app_state.lock_sockets(|sockets| sockets.unregister(&client));
+
+        // The client closed its tab just before the host accepted it
+        let accepted = process(
+            &app_state,
+            &host,
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": client}),
+        );
+        for (socket_id, message) in accepted.other_messages {
+            app_state.send_to(&socket_id, message);
+        }
+        assert!(app_state
+            .lock_sockets(|sockets| sockets.get(&client))
+            .is_none());
+        assert!(host_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_tells_sockets_and_waits_for_them_to_close() {
+        let (app_state, addr) = serve(Config::from_env()).await;
+        let mut connections = [Connection::open(addr).await, Connection::open(addr).await];
+        let shutdown = tokio::spawn({
+            let app_state = app_state.clone();
+            async move { app_state.shutdown().await }
+        });
+        for connection in &mut connections {
+            assert_eq!(connection.recv().await["type"], "serverShutdown");
+            assert_eq!(connection.closed().await, close_code::AWAY);
+        }
+        tokio::time::timeout(Duration::from_secs(5), shutdown)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(app_state.lock_sockets(|sockets| sockets.is_empty()));
+    }
+
+    #[test]
+    fn games_beyond_the_cap_are_refused() {
+        let mut lobby = Lobby::with_config(|config| {
+            config.max_games = Some(1);
+            config.evict_idle_games = false;
+        });
+        lobby.create_game("host", json!({}));
+        let create = json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4});
+        assert_eq!(
+            lobby.send("other", create.clone()).reply()["code"],
+            "serverFull"
+        );
+        lobby.disconnect("host");
+        assert_eq!(lobby.send("other", create).reply()["type"], "gameCreated");
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_limit_are_closed_with_try_again() {
+        let mut config = Config::from_env();
+        config.max_connections = Some(1);
+        let (app_state, addr) = serve(config).await;
+        let connection = Connection::open(addr).await;
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        let mut rejected = Connection {
+            socket,
+            socket_id: String::new(),
+        };
+        assert_eq!(rejected.closed().await, close_code::AGAIN);
+        assert!(is_registered(&app_state, &connection));
+        assert_eq!(app_state.lock_sockets(|sockets| sockets.ids().len()), 1);
+    }
+
+    #[test]
+    fn rate_limiter_allows_a_burst_and_refills_over_time() {
+        let mut rate_limiter = RateLimiter::new(2);
+        assert!(rate_limiter.try_acquire());
+        assert!(rate_limiter.try_acquire());
+        assert!(!rate_limiter.try_acquire());
+        rate_limiter.last_refill -= Duration::from_millis(500);
+        assert!(rate_limiter.try_acquire());
+        assert!(!rate_limiter.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn only_the_first_dropped_message_of_a_burst_is_answered() {
+        let mut config = Config::from_env();
+        config.rate_limit = Some(1);
+        let (_app_state, addr) = serve(config).await;
+        let mut connection = Connection::open(addr).await;
+        for _ in 0..3 {
+            connection.send(json!({"type": "listGames"})).await;
+        }
+        assert_eq!(connection.recv().await["type"], "gameList");
+        assert_eq!(connection.recv().await["code"], "rateLimited");
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        connection.send(json!({"type": "listGames"})).await;
+        assert_eq!(connection.recv().await["type"], "gameList");
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused_before_parsing() {
+        let mut config = Config::from_env();
+        config.max_message_bytes = 64;
+        let (_app_state, addr) = serve(config).await;
+        let mut connection = Connection::open(addr).await;
+        // Not even JSON, so parsing it would have been an invalid message
+        connection
+            .socket
+            .send(ClientMessage::Text("x".repeat(65)))
+            .await
+            .unwrap();
+        assert_eq!(connection.recv().await["code"], "messageTooLarge");
+        connection.send(json!({"type": "listGames"})).await;
+        assert_eq!(connection.recv().await["type"], "gameList");
+    }
+
+    #[test]
+    fn lists_are_paginated_with_a_total() {
+        let mut lobby = Lobby::new();
+        for index in 0..5 {
+            lobby.create_game(
+                &format!("host{}", index),
+                json!({"serverName": format!("Game {}", index)}),
+            );
+        }
+        let all = list(&mut lobby, json!({}));
+        assert_eq!(listed_names(&all).len(), 5);
+        let page = list(&mut lobby, json!({"offset": 1, "limit": 2}));
+        assert_eq!(listed_names(&page), ["Game 1", "Game 2"]);
+        assert_eq!(page["total"], 5);
+        let past_the_end = list(&mut lobby, json!({"offset": 10, "limit": 2}));
+        assert!(listed_names(&past_the_end).is_empty());
+        assert_eq!(past_the_end["total"], 5);
+    }
+
+    #[test]
+    fn filtered_lists_without_a_limit_return_every_match() {
+        let mut lobby = Lobby::with_config(|config| config.max_games = None);
+        for index in 0..150 {
+            lobby.create_game(
+                &format!("host{}", index),
+                json!({"serverName": format!("Game {}", index)}),
+            );
+        }
+        let all = list(&mut lobby, json!({"nameFilter": "game"}));
+        assert_eq!(all["total"], 150);
+        assert_eq!(listed_names(&all).len(), 150);
+        let page = list(&mut lobby, json!({"nameFilter": "game", "limit": 500}));
+        assert_eq!(listed_names(&page).len(), MAX_LIST_LIMIT);
+    }
+
+    #[test]
+    fn lists_are_sorted_by_the_requested_order() {
+        let mut lobby = Lobby::new();
+        for (name, max_players, players) in [("beta", 2, 2), ("Alpha", 4, 1), ("gamma", 4, 3)] {
+            // Apart enough for the creation times to differ
+            std::thread::sleep(Duration::from_millis(2));
+            let game_id = lobby.create_game(
+                name,
+                json!({"serverName": name, "maxPlayers": max_players, "joinPolicy": "open"}),
+            );
+            for player in 1..players {
+                lobby.send(
+                    &format!("{}{}", name, player),
+                    json!({"type": "joinGame", "gameId": game_id}),
+                );
+            }
+        }
+        let sorted = |lobby: &mut Lobby, query: Value| {
+            let list = list(lobby, query);
+            listed_names(&list)
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sorted(&mut lobby, json!({})), ["beta", "Alpha", "gamma"]);
+        assert_eq!(
+            sorted(&mut lobby, json!({"sort": "playersAsc"})),
+            ["Alpha", "beta", "gamma"]
+        );
+        assert_eq!(
+            sorted(&mut lobby, json!({"sort": "playersDesc"})),
+            ["gamma", "beta", "Alpha"]
+        );
+        assert_eq!(
+            sorted(&mut lobby, json!({"sort": "name"})),
+            ["Alpha", "beta", "gamma"]
+        );
+        assert_eq!(
+            sorted(&mut lobby, json!({"sort": "newest"})),
+            ["gamma", "Alpha", "beta"]
+        );
+        assert_eq!(
+            sorted(&mut lobby, json!({"sort": "oldest"})),
+            ["beta", "Alpha", "gamma"]
+        );
+        assert_eq!(
+            sorted(&mut lobby, json!({"sort": "name", "hideFull": true})),
+            ["Alpha", "gamma", "beta"]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribers_are_pushed_list_changes_until_they_leave() {
+        let (app_state, addr) = serve(Config::from_env()).await;
+        let mut subscriber = Connection::open(addr).await;
+        let mut host = Connection::open(addr).await;
+        subscriber.send(json!({"type": "subscribeGameList"})).await;
+        let current = subscriber.recv().await;
+        assert_eq!(current["type"], "gameListUpdated");
+        assert_eq!(current["games"], json!([]));
+
+        let create = json!({"type": "createGame", "serverName": "Pushed", "maxPlayers": 4});
+        host.send(create).await;
+        assert_eq!(host.recv().await["type"], "gameCreated");
+        let pushed = subscriber.recv().await;
+        assert_eq!(pushed["type"], "gameInfoChanged");
+        assert_eq!(pushed["game"]["serverName"], "Pushed");
+
+        subscriber
+            .send(json!({"type": "unsubscribeGameList"}))
+            .await;
+        subscriber.send(json!({"type": "listGames"})).await;
+        assert_eq!(subscriber.recv().await["type"], "gameList");
+        host.send(json!({"type": "updateGameInfo", "serverName": "Renamed", "maxPlayers": 4}))
+            .await;
+        subscriber.send(json!({"type": "listGames"})).await;
+        assert_eq!(subscriber.recv().await["type"], "gameList");
+
+        subscriber.send(json!({"type": "subscribeGameList"})).await;
+        assert_eq!(subscriber.recv().await["type"], "gameListUpdated");
+        subscriber.socket.close(None).await.unwrap();
+        subscriber.closed().await;
+        assert!(app_state.lock_list_subscribers(|subscribers| subscribers.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn subscribers_see_closed_games_removed_before_closing() {
+        let (_, addr) = serve(Config::from_env()).await;
+        let mut host = Connection::open(addr).await;
+        let mut client = Connection::open(addr).await;
+        host.send(json!({"type": "createGame", "serverName": "Closing", "maxPlayers": 4, "joinPolicy": "open"}))
+            .await;
+        let game_id = host.recv().await["gameId"].clone();
+        client
+            .send(json!({"type": "joinGame", "gameId": game_id}))
+            .await;
+        assert_eq!(client.recv().await["type"], "acceptJoin");
+        client.send(json!({"type": "subscribeGameList"})).await;
+        assert_eq!(client.recv().await["type"], "gameListUpdated");
+
+        host.socket.close(None).await.unwrap();
+        let removed = client.recv().await;
+        assert_eq!(removed["type"], "gameRemoved");
+        assert_eq!(removed["gameId"], game_id);
+        assert_eq!(client.recv().await["type"], "gameClosed");
+    }
+
+    #[tokio::test]
+    async fn renaming_a_game_pushes_exactly_one_change() {
+        let (_app_state, addr) = serve(Config::from_env()).await;
+        let mut subscriber = Connection::open(addr).await;
+        let mut host = Connection::open(addr).await;
+        let create = json!({"type": "createGame", "serverName": "Before", "maxPlayers": 4});
+        host.send(create).await;
+        let game_id = host.recv().await["gameId"].clone();
+        subscriber.send(json!({"type": "subscribeGameList"})).await;
+        assert_eq!(subscriber.recv().await["type"], "gameListUpdated");
+
+        host.send(json!({"type": "updateGameInfo", "serverName": "After", "maxPlayers": 4}))
+            .await;
+        let changed = subscriber.recv().await;
+        assert_eq!(changed["type"], "gameInfoChanged");
+        assert_eq!(changed["game"]["gameId"], game_id);
+        assert_eq!(changed["game"]["serverName"], "After");
+        // Anything else pushed would arrive before the list reply
+        subscriber.send(json!({"type": "listGames"})).await;
+        assert_eq!(subscriber.recv().await["type"], "gameList");
+    }
+
+    #[test]
+    fn listing_filters_by_version() {
+        let mut lobby = Lobby::new();
+        lobby.create_game("host1", json!({"serverName": "Old", "version": "1.2"}));
+        lobby.create_game("host2", json!({"serverName": "New", "version": "1.3"}));
+        lobby.create_game("host3", json!({"serverName": "Unversioned"}));
+        let all = list(&mut lobby, json!({}));
+        assert_eq!(listed_names(&all), ["Old", "New", "Unversioned"]);
+        assert_eq!(all["games"][2]["version"], "unknown");
+        let filtered = list(&mut lobby, json!({"versionFilter": "1.2"}));
+        assert_eq!(listed_names(&filtered), ["Old"]);
+    }
+
+    #[test]
+    fn quick_match_only_joins_games_of_the_version() {
+        let mut lobby = Lobby::new();
+        let old = lobby.create_game("host1", json!({"version": "1.2", "joinPolicy": "open"}));
+        let new = lobby.create_game("host2", json!({"version": "1.3", "joinPolicy": "open"}));
+        lobby.send("a", json!({"type": "joinGame", "gameId": new}));
+        let matched = lobby
+            .send(
+                "b",
+                json!({"type": "quickMatch", "filters": {"version": "1.2"}}),
+            )
+            .reply();
+        assert_eq!(matched["gameId"], json!(old));
+        let matched = lobby
+            .send(
+                "c",
+                json!({"type": "quickMatch", "filters": {"version": "2.0"}}),
+            )
+            .reply();
+        assert_eq!(matched["type"], "noMatch");
+    }
+
+    #[test]
+    fn listing_filters_by_any_of_the_regions() {
+        let mut lobby = Lobby::new();
+        for (host, region) in [
+            ("north", "eu-north"),
+            ("central", "eu-central"),
+            ("west", "us-west"),
+        ] {
+            lobby.create_game(host, json!({"serverName": host, "region": region}));
+        }
+        lobby.create_game("nowhere", json!({"serverName": "nowhere"}));
+        let nearby = list(&mut lobby, json!({"regionFilter": "eu-north,eu-central"}));
+        assert_eq!(listed_names(&nearby), ["north", "central"]);
+        assert_eq!(nearby["games"][0]["region"], "eu-north");
+        let all = list(&mut lobby, json!({}));
+        assert_eq!(listed_names(&all), ["north", "central", "west", "nowhere"]);
+    }
+
+    #[test]
+    fn spectators_dont_take_player_slots() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"maxPlayers": 2}));
+        lobby.join_accepted("a", &game_id);
+        let watch = json!({"type": "joinAsSpectator", "gameId": game_id});
+        let watched = lobby.send("watcher", watch);
+        assert_eq!(watched.reply()["type"], "acceptJoin");
+        assert_eq!(watched.to("host")[0]["type"], "newSpectator");
+        let listed = &list(&mut lobby, json!({}))["games"][0];
+        assert_eq!(listed["playerAmount"], 2);
+        assert_eq!(listed["spectatorAmount"], 1);
+
+        let sent = lobby.send(
+            "watcher",
+            json!({"type": "webrtcSignaling", "description": {"sdp": "offer"}}),
+        );
+        assert_eq!(sent.to("host")[0]["clientId"], "watcher");
+
+        lobby.disconnect("watcher");
+        assert_eq!(
+            list(&mut lobby, json!({}))["games"][0]["spectatorAmount"],
+            0
+        );
+    }
+
+    // Applies random membership changes, suspensions, resumes and session
+    // expiries, checking after each that the occupancy adds up and is what's
+    // listed
+    #[tokio::test]
+    async fn occupancy_stays_consistent() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        // Ends the suspension of a socket by resuming its session from a new
+        // connection or by letting it expire
+        async fn end_suspension(
+            app_state: &AppState,
+            suspended: &mut HashMap<SocketId, Instant>,
+            socket_id: &SocketId,
+            resume: bool,
+            step: usize,
+        ) {
+            let disconnected_at = suspended.remove(socket_id).unwrap();
+            if resume {
+                let token = app_state
+                    .lock_sessions(|sessions| sessions.token(socket_id).cloned())
+                    .unwrap();
+                let connection = SocketId(format!("connection{}", step));
+                let (resumed, _) = app_state.resume_session(&token, &connection).unwrap();
+                assert_eq!(resumed, *socket_id);
+            } else {
+                expire_session(app_state.clone(), socket_id.clone(), disconnected_at).await;
+            }
+        }
+
+        let mut config = Config::from_env();
+        config.resume_grace = Some(Duration::ZERO);
+        config.state_file = None;
+        let app_state = AppState::new(config);
+        let create_game = |host: &SocketId| -> GameId {
+            let created = process(
+                &app_state,
+                host,
+                json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4}),
+            );
+            let reply = to_json(created.self_message.as_ref().unwrap());
+            serde_json::from_value(reply["gameId"].clone()).unwrap()
+        };
+        let mut rng = StdRng::seed_from_u64(202);
+        let sockets: Vec<SocketId> = (0..8)
+            .map(|index| SocketId(format!("socket{}", index)))
+            .collect();
+        // Sockets within their resume grace, by time of disconnect
+        let mut suspended: HashMap<SocketId, Instant> = HashMap::new();
+        let mut host = socket("host0");
+        let mut game_id = create_game(&host);
+        for step in 1..2000 {
+            // Connected sockets all have sessions to suspend, expired ones
+            // get new ones as if they had reconnected
+            app_state.lock_sessions(|sessions| {
+                for socket_id in sockets.iter().chain([&host]) {
+                    if sessions.token(socket_id).is_none() {
+                        sessions.issue(socket_id);
+                    }
                 }
-            } else if let Some(game) = games.get_game_by_client(socket_id) {
-                // WebRTC signaling from client -> send to host
-                MessagesToSend::other(
-                    game.host.clone(),
-                    OutgoingMessage::WebrtcSignaling {
-                        game_id: game.game_id.clone(),
-                        client_id: Some(socket_id.clone()),
-                        description,
-                        candidate,
-                    },
+            });
+            let member = &sockets[rng.gen_range(0..sockets.len())];
+            let host_connected = !suspended.contains_key(&host);
+            if suspended.contains_key(member) {
+                // Suspended sockets can't send anything
+                let resume = rng.gen_bool(0.5);
+                end_suspension(&app_state, &mut suspended, member, resume, step).await;
+            } else {
+                let host_message = |message: Value| {
+                    if host_connected {
+                        process(&app_state, &host, message);
+                    }
+                };
+                match rng.gen_range(0..9) {
+                    0 => {
+                        process(
+                            &app_state,
+                            member,
+                            json!({"type": "joinGame", "gameId": game_id}),
+                        );
+                    }
+                    1 => {
+                        process(
+                            &app_state,
+                            member,
+                            json!({"type": "joinAsSpectator", "gameId": game_id}),
+                        );
+                    }
+                    2 => host_message(
+                        json!({"type": "acceptJoin", "gameId": game_id, "clientId": member}),
+                    ),
+                    3 => host_message(
+                        json!({"type": "rejectJoin", "gameId": game_id, "clientId": member, "reason": "no"}),
+                    ),
+                    4 => host_message(
+                        json!({"type": "kickPlayer", "gameId": game_id, "clientId": member}),
+                    ),
+                    5 => {
+                        process(
+                            &app_state,
+                            member,
+                            json!({"type": "leaveGame", "gameId": game_id}),
+                        );
+                    }
+                    6 => app_state.disconnect(member),
+                    7 => {
+                        let disconnected_at = app_state.suspend(member).unwrap();
+                        suspended.insert(member.clone(), disconnected_at);
+                    }
+                    _ => {
+                        host_message(json!({"type": "reserveSlot", "gameId": game_id, "count": 1}))
+                    }
+                }
+            }
+            if !suspended.contains_key(&host) {
+                if rng.gen_ratio(1, 100) {
+                    app_state.disconnect(&host);
+                } else if rng.gen_ratio(1, 50) {
+                    let disconnected_at = app_state.suspend(&host).unwrap();
+                    suspended.insert(host.clone(), disconnected_at);
+                }
+            } else if rng.gen_ratio(1, 10) {
+                let resume = rng.gen_bool(0.5);
+                end_suspension(&app_state, &mut suspended, &host.clone(), resume, step).await;
+            }
+            let current_host = app_state.read_games(|games| {
+                let game = games.get_game(&game_id)?;
+                let occupancy = game.occupancy();
+                let max_players = game.game_info.max_players;
+                let players = std::iter::once(&game.host).chain(&game.clients);
+                let suspended_players = players
+                    .filter(|player| suspended.contains_key(player))
+                    .count() as u32;
+                assert_eq!(occupancy.suspended, suspended_players, "{:?}", occupancy);
+                assert_eq!(
+                    occupancy.players + occupancy.suspended,
+                    game.clients.len() as u32 + 1
+                );
+                assert!(occupancy.players + occupancy.suspended <= max_players);
+                assert!(occupancy.taken_slots() <= max_players, "{:?}", occupancy);
+                assert_eq!(occupancy.spectators, game.spectators.len() as u32);
+                assert_eq!(occupancy.pending, game.pending.len() as u32);
+                // Only current members are marked, and all suspended ones are
+                let marked: HashSet<&SocketId> = std::iter::once(&game.host)
+                    .chain(&game.clients)
+                    .chain(&game.pending)
+                    .chain(&game.spectators)
+                    .filter(|member| suspended.contains_key(member))
+                    .collect();
+                assert_eq!(game.suspended.iter().collect::<HashSet<_>>(), marked);
+                let listed = games
+                    .snapshot()
+                    .iter()
+                    .find(|listed| listed.game_id == game_id)
+                    .cloned()
+                    .unwrap();
+                assert_eq!(listed.player_amount, occupancy.players);
+                assert_eq!(listed.spectator_amount, occupancy.spectators);
+                let members = game.clients.len() + game.pending.len() + game.spectators.len();
+                let disjoint: HashSet<_> = game
+                    .clients
+                    .iter()
+                    .chain(&game.pending)
+                    .chain(&game.spectators)
+                    .collect();
+                assert_eq!(disjoint.len(), members);
+                Some(game.host.clone())
+            });
+            match current_host {
+                // The host may have been migrated
+                Some(current_host) => host = current_host,
+                None => {
+                    host = SocketId(format!("host{}", step));
+                    game_id = create_game(&host);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn evicted_games_are_closed_for_their_spectators() {
+        let mut lobby = Lobby::with_config(|config| {
+            config.max_games = Some(1);
+            config.evict_idle_games = true;
+        });
+        let game_id = lobby.create_game("idle host", json!({}));
+        let spectated = lobby.send(
+            "spectator",
+            json!({"type": "joinAsSpectator", "gameId": game_id}),
+        );
+        assert_eq!(spectated.reply()["type"], "acceptJoin");
+        let created = lobby.send(
+            "host",
+            json!({"type": "createGame", "serverName": "New game", "maxPlayers": 4}),
+        );
+        assert_eq!(created.reply()["type"], "gameCreated");
+        for recipient in ["idle host", "spectator"] {
+            assert_eq!(
+                created.to(recipient),
+                [json!({"type": "gameClosed", "gameId": game_id, "reason": "evicted"})],
+            );
+        }
+    }
+
+    #[test]
+    fn chat_messages_carry_the_game_event_seq() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("client", &game_id);
+        let chat = |lobby: &mut Lobby, sender| {
+            lobby
+                .send(
+                    sender,
+                    json!({"type": "chatMessage", "gameId": game_id, "text": "hi"}),
+                )
+                .reply()["seq"]
+                .as_u64()
+                .unwrap()
+        };
+        let first = chat(&mut lobby, "client");
+        let second = chat(&mut lobby, "host");
+        assert!(second > first);
+        let left = lobby.send("client", json!({"type": "leaveGame", "gameId": game_id}));
+        assert!(left.to("host")[0]["seq"].as_u64().unwrap() > second);
+    }
+
+    #[test]
+    fn info_updates_advance_the_game_event_seq() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("client", &game_id);
+        let chat = |lobby: &mut Lobby| {
+            lobby
+                .send(
+                    "host",
+                    json!({"type": "chatMessage", "gameId": game_id, "text": "hi"}),
                 )
+                .reply()["seq"]
+                .as_u64()
+                .unwrap()
+        };
+        let first = chat(&mut lobby);
+        let updated = lobby.send("host", json!({"type": "patchGameInfo", "maxPlayers": 8}));
+        let [update] = &updated.to("client")[..] else {
+            panic!("expected one update");
+        };
+        assert_eq!(update["type"], "gameInfoUpdated");
+        assert_eq!(update["game"]["maxPlayers"], 8);
+        let update_seq = update["seq"].as_u64().unwrap();
+        assert!(update_seq > first);
+        assert!(updated.to("host").is_empty());
+        assert!(chat(&mut lobby) > update_seq);
+    }
+
+    #[test]
+    fn toggling_ready_broadcasts_the_lobby_state() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("client", &game_id);
+        let lobby_state = |ready: bool| json!({"type": "lobbyState", "gameId": game_id, "ready": [["client", ready]]});
+        let set_ready =
+            |ready: bool| json!({"type": "setReady", "gameId": game_id, "ready": ready});
+
+        let sent = lobby.send("client", set_ready(true));
+        assert_eq!(sent.reply(), lobby_state(true));
+        assert_eq!(sent.to("host"), [lobby_state(true)]);
+        let sent = lobby.send("client", set_ready(true));
+        assert!(sent.0.self_message.is_none() && sent.0.other_messages.is_empty());
+        let sent = lobby.send("client", set_ready(false));
+        assert_eq!(sent.to("host"), [lobby_state(false)]);
+
+        lobby.send("pending", json!({"type": "joinGame", "gameId": game_id}));
+        let sent = lobby.send("pending", set_ready(true));
+        assert_eq!(sent.reply()["code"], "notInGame");
+    }
+
+    #[test]
+    fn started_games_are_listed_as_starting_or_hidden() {
+        for hide_started_games in [false, true] {
+            let mut lobby = Lobby::with_config(|config| {
+                config.start_quorum_percent = 0;
+                config.hide_started_games = hide_started_games;
+            });
+            let game_id = lobby.create_game("host", json!({}));
+            lobby.join_accepted("a", &game_id);
+            let start = json!({"type": "startGame", "gameId": game_id});
+            assert_eq!(lobby.send("a", start.clone()).reply()["code"], "notHost");
+            assert_eq!(list(&mut lobby, json!({}))["games"][0]["status"], "open");
+
+            lobby.send("host", start);
+            let listed = list(&mut lobby, json!({}));
+            if hide_started_games {
+                assert_eq!(listed["games"], json!([]));
             } else {
-                MessagesToSend::none()
+                assert_eq!(listed["games"][0]["status"], "starting");
             }
         }
-        IncomingMessage::CreateGame {
-            game_id,
-            server_name,
-            max_players,
-            requires_password,
-        } => {
-            let game_id = game_id.unwrap_or_else(GameId::random);
-            games.add(Game {
-                game_id: game_id.clone(),
-                host: socket_id.clone(),
-                clients: HashSet::new(),
-                game_info: GameInfo {
-                    server_name,
-                    player_amount: 1,
-                    max_players,
-                    requires_password: requires_password.unwrap_or(false),
-                },
+    }
+
+    #[test]
+    fn explicit_game_ids_must_be_unique() {
+        let mut lobby = Lobby::new();
+        lobby.create_game("host", json!({"gameId": "mine"}));
+        let create = json!({
+            "type": "createGame",
+            "gameId": "mine",
+            "serverName": "Test game",
+            "maxPlayers": 4,
+        });
+        assert_eq!(lobby.send("other", create).reply()["code"], "gameIdTaken");
+        assert_eq!(list(&mut lobby, json!({}))["total"], 1);
+    }
+
+    #[test]
+    fn running_out_of_game_ids_fails_the_create() {
+        let mut lobby = Lobby::with_config(|config| {
+            config.game_id_format = IdFormat {
+                length: 1,
+                alphabet: Some(vec!['a']),
+            };
+        });
+        lobby.create_game("host", json!({"gameId": "a"}));
+        let create = json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4});
+        assert_eq!(lobby.send("other", create).reply()["code"], "serverFull");
+        assert_eq!(list(&mut lobby, json!({}))["total"], 1);
+    }
+
+    #[test]
+    fn hosts_can_only_host_one_game() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"serverName": "First"}));
+        let create = json!({"type": "createGame", "serverName": "Second", "maxPlayers": 4});
+        assert_eq!(
+            lobby.send("host", create.clone()).reply()["code"],
+            "alreadyHosting"
+        );
+        assert_eq!(listed_names(&list(&mut lobby, json!({}))), ["First"]);
+        lobby.disconnect("host");
+        assert!(lobby.games.get_game(&game_id).is_none());
+        assert_eq!(lobby.send("host", create).reply()["type"], "gameCreated");
+    }
+
+    #[test]
+    fn server_names_are_validated_on_create_and_update() {
+        let mut lobby = Lobby::new();
+        let too_long = "x".repeat(MAX_SERVER_NAME_LENGTH + 1);
+        for server_name in ["", "   ", too_long.as_str(), "Bad\u{7}name"] {
+            let create = json!({"type": "createGame", "serverName": server_name, "maxPlayers": 4});
+            assert_eq!(
+                lobby.send("host", create).reply()["code"],
+                "invalidServerName"
+            );
+        }
+        let longest = "ä".repeat(MAX_SERVER_NAME_LENGTH);
+        lobby.create_game("host", json!({"serverName": longest}));
+        lobby.send(
+            "host",
+            json!({"type": "updateGameInfo", "serverName": "  Spaced  ", "maxPlayers": 4}),
+        );
+        assert_eq!(listed_names(&list(&mut lobby, json!({}))), ["Spaced"]);
+        let update = json!({"type": "updateGameInfo", "serverName": "Tab\there", "maxPlayers": 4});
+        assert_eq!(
+            lobby.send("host", update).reply()["code"],
+            "invalidServerName"
+        );
+    }
+
+    #[test]
+    fn max_players_cannot_drop_below_pending_and_reserved_slots() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"maxPlayers": 4}));
+        lobby.send("client", json!({"type": "joinGame", "gameId": game_id}));
+        let reserved = lobby.send(
+            "host",
+            json!({"type": "reserveSlot", "gameId": game_id, "count": 1}),
+        );
+        assert_eq!(
+            reserved.reply()["type"],
+            "slotsReserved",
+            "{}",
+            reserved.reply()
+        );
+        // The host, the pending client and the reservation take three slots
+        let updated = lobby.send(
+            "host",
+            json!({"type": "updateGameInfo", "serverName": "Test game", "maxPlayers": 2}),
+        );
+        assert_eq!(updated.reply()["code"], "invalidMaxPlayers");
+        let updated = lobby.send(
+            "host",
+            json!({"type": "updateGameInfo", "serverName": "Test game", "maxPlayers": 3}),
+        );
+        assert!(updated.0.self_message.is_none());
+    }
+
+    #[test]
+    fn closed_connections_leave_resumed_ones_registered() {
+        let mut sockets = Sockets::new();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let id_format = IdFormat::default();
+        let (closed, _closed_rx, closed_connection) = sockets.register(&id_format, 1, addr);
+        let (resumed, _resumed_rx, resumed_connection) = sockets.register(&id_format, 1, addr);
+        sockets.rename(&resumed, closed.clone());
+
+        sockets.unregister_connection(&closed, &closed_connection);
+        assert!(sockets.get(&closed).is_some());
+        sockets.unregister_connection(&closed, &resumed_connection);
+        assert!(sockets.get(&closed).is_none());
+    }
+
+    #[test]
+    fn resumed_tells_whether_game_events_were_missed() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        let joined = lobby.send("client", json!({"type": "joinGame", "gameId": game_id}));
+        let seq = joined.to("host")[0]["seq"].as_u64().unwrap();
+        let token = ResumeToken("token".to_string());
+        let resumed = |games: &Games, last_seq| {
+            to_json(&resumed_message(
+                games,
+                socket("host"),
+                token.clone(),
+                last_seq,
+            ))
+        };
+        assert_eq!(resumed(&lobby.games, Some(seq))["caughtUp"], true);
+        assert_eq!(resumed(&lobby.games, Some(seq))["seq"], seq);
+
+        lobby.send("client", json!({"type": "leaveGame", "gameId": game_id}));
+        assert_eq!(resumed(&lobby.games, Some(seq))["caughtUp"], false);
+        assert_eq!(resumed(&lobby.games, None)["caughtUp"], false);
+    }
+
+    // The response of a plain HTTP GET, headers included
+    async fn http_get(addr: SocketAddr, path: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics_report_games_sockets_and_messages() {
+        let (_app_state, addr) = serve(Config::from_env()).await;
+        let mut host = Connection::open(addr).await;
+        host.send(json!({"type": "createGame", "serverName": "Counted", "maxPlayers": 4}))
+            .await;
+        assert_eq!(host.recv().await["type"], "gameCreated");
+        let mut client = Connection::open(addr).await;
+        client
+            .send(json!({"type": "joinGame", "gameId": "missing"}))
+            .await;
+        assert_eq!(client.recv().await["type"], "error");
+
+        let response = http_get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        for line in [
+            "lobby_games 1",
+            "lobby_sockets 2",
+            "lobby_messages_total{type=\"createGame\"} 1",
+            "lobby_messages_total{type=\"joinGame\"} 1",
+            "lobby_joins_total{outcome=\"failure\"} 1",
+        ] {
+            assert!(
+                response.lines().any(|reported| reported == line),
+                "{} missing",
+                line
+            );
+        }
+    }
+
+    // Log output collected in memory
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_logged_as_warnings_of_the_socket() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_env_filter("debug")
+            .with_ansi(false)
+            .finish();
+        // The test runtime is single threaded, so this covers the server too
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let (_app_state, addr) = serve(Config::from_env()).await;
+        let mut connection = Connection::open(addr).await;
+        connection.send(json!({"type": "noSuchType"})).await;
+        assert_eq!(connection.recv().await["type"], "error");
+
+        let invalid = logs
+            .lines()
+            .into_iter()
+            .find(|line| line.contains("Invalid message"))
+            .expect("invalid message not logged");
+        assert!(invalid.contains("WARN"), "{}", invalid);
+        assert!(
+            invalid.contains(&format!("socket_id={}", connection.socket_id)),
+            "{}",
+            invalid
+        );
+    }
+
+    #[test]
+    fn log_formats_default_to_text() {
+        assert_eq!(log_format(None), Some(LogFormat::Text));
+        assert_eq!(log_format(Some("text")), Some(LogFormat::Text));
+        assert_eq!(log_format(Some("pretty")), Some(LogFormat::Pretty));
+        assert_eq!(log_format(Some("json")), Some(LogFormat::Json));
+        assert_eq!(log_format(Some("JSON")), None);
+        assert_eq!(log_format(Some("")), None);
+    }
+
+    // Status of a WebSocket upgrade with extra headers, and the subprotocol the
+    // server picked if it was accepted
+    async fn upgrade(addr: SocketAddr, headers: &[(&'static str, &str)]) -> (u16, Option<String>) {
+        let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+        for (name, value) in headers {
+            request.headers_mut().insert(*name, value.parse().unwrap());
+        }
+        match tokio_tungstenite::connect_async(request).await {
+            Ok((_, response)) => (
+                response.status().as_u16(),
+                response
+                    .headers()
+                    .get(header::SEC_WEBSOCKET_PROTOCOL)
+                    .map(|protocol| protocol.to_str().unwrap().to_string()),
+            ),
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                (response.status().as_u16(), None)
+            }
+            Err(err) => panic!("upgrade failed: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrades_are_refused_from_disallowed_origins() {
+        let mut config = Config::from_env();
+        config.allowed_origins = Some(vec!["https://suomipelit.fi".to_string()]);
+        let (_app_state, addr) = serve(config).await;
+        assert_eq!(
+            upgrade(addr, &[("origin", "https://suomipelit.fi")])
+                .await
+                .0,
+            101
+        );
+        assert_eq!(
+            upgrade(addr, &[("origin", "HTTPS://SUOMIPELIT.FI")])
+                .await
+                .0,
+            101
+        );
+        assert_eq!(
+            upgrade(addr, &[("origin", "https://evil.example")]).await.0,
+            403
+        );
+        // Not from a browser
+        assert_eq!(upgrade(addr, &[]).await.0, 101);
+    }
+
+    #[tokio::test]
+    async fn upgrades_negotiate_a_known_subprotocol() {
+        let (_app_state, addr) = serve(Config::from_env()).await;
+        let offer = |protocols| [("sec-websocket-protocol", protocols)];
+        assert_eq!(
+            upgrade(addr, &offer("suomipelit.v2, suomipelit.v1")).await,
+            (101, Some("suomipelit.v1".to_string()))
+        );
+        assert_eq!(
+            upgrade(addr, &offer("suomipelit.v1.msgpack")).await,
+            (101, Some("suomipelit.v1.msgpack".to_string()))
+        );
+        assert_eq!(upgrade(addr, &offer("suomipelit.v2")).await, (400, None));
+        assert_eq!(upgrade(addr, &[]).await, (101, None));
+    }
+
+    // Next binary frame, decoded from MessagePack
+    async fn recv_message_pack(socket: &mut ClientSocket) -> Value {
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+                .await
+                .expect("no message in time")
+                .expect("socket closed")
+                .unwrap();
+            match message {
+                ClientMessage::Binary(data) => return rmp_serde::from_slice(&data).unwrap(),
+                ClientMessage::Text(text) => panic!("expected MessagePack, got {}", text),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn message_pack_connections_are_answered_in_kind() {
+        let (_app_state, addr) = serve(Config::from_env()).await;
+        let mut connection = Connection::open(addr).await;
+        let list_games = rmp_serde::to_vec_named(&json!({"type": "listGames"})).unwrap();
+        connection
+            .socket
+            .send(ClientMessage::Binary(list_games))
+            .await
+            .unwrap();
+        assert_eq!(
+            recv_message_pack(&mut connection.socket).await["type"],
+            "gameList"
+        );
+        // The format sticks once chosen
+        connection.send(json!({"type": "listGames"})).await;
+        assert_eq!(
+            recv_message_pack(&mut connection.socket).await["code"],
+            "invalidMessage"
+        );
+    }
+
+    // permessage-deflate isn't supported by the WebSocket stack, so an offer
+    // of it is declined and the connection falls back to uncompressed frames
+    #[tokio::test]
+    async fn compression_offers_are_declined() {
+        let (_app_state, addr) = serve(Config::from_env()).await;
+        let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+        request.headers_mut().insert(
+            header::SEC_WEBSOCKET_EXTENSIONS,
+            "permessage-deflate".parse().unwrap(),
+        );
+        let (mut socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert!(response
+            .headers()
+            .get(header::SEC_WEBSOCKET_EXTENSIONS)
+            .is_none());
+        assert_eq!(recv_json(&mut socket).await["type"], "welcome");
+    }
+
+    #[tokio::test]
+    async fn upgrades_are_refused_from_banned_addresses() {
+        let mut config = Config::from_env();
+        config.banned_networks = vec!["127.0.0.0/8".parse().unwrap()];
+        let (app_state, addr) = serve(config).await;
+        assert_eq!(upgrade(addr, &[]).await.0, 403);
+        assert!(app_state.lock_sockets(|sockets| sockets.is_empty()));
+    }
+
+    #[test]
+    fn hosts_and_accepted_clients_get_ice_servers() {
+        let mut lobby = Lobby::with_config(|config| {
+            config.turn = Some(config::TurnConfig {
+                urls: vec!["turn:turn.example.com:3478".to_string()],
+                secret: "secret".to_string(),
+                credential_ttl: Duration::from_secs(3600),
             });
-            MessagesToSend::self_(OutgoingMessage::GameCreated { game_id })
+        });
+        let create = json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4});
+        let created = lobby.send("host", create).reply();
+        assert_eq!(
+            created["iceServers"][0]["urls"][0],
+            "turn:turn.example.com:3478"
+        );
+        assert!(created["iceServers"][0]["username"]
+            .as_str()
+            .unwrap()
+            .ends_with(":host"));
+        let game_id = created["gameId"].clone();
+        lobby.send("client", json!({"type": "joinGame", "gameId": game_id}));
+        let accepted = lobby.send(
+            "host",
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": "client"}),
+        );
+        let ice_servers = &accepted.to("client")[0]["iceServers"];
+        assert!(ice_servers[0]["username"]
+            .as_str()
+            .unwrap()
+            .ends_with(":client"));
+    }
+
+    #[tokio::test]
+    async fn connections_are_welcomed_with_their_socket_id() {
+        let (app_state, addr) = serve(Config::from_env()).await;
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        let welcome = recv_json(&mut socket).await;
+        assert_eq!(welcome["type"], "welcome");
+        let socket_id = welcome["socketId"].as_str().unwrap();
+        assert!(!socket_id.is_empty());
+        assert_eq!(
+            app_state.lock_sockets(|sockets| sockets.ids()),
+            [SocketId(socket_id.to_string())]
+        );
+    }
+
+    #[test]
+    fn server_hello_advertises_the_version_and_configured_features() {
+        let mut config = Config::from_env();
+        config.max_players_range = 2..=16;
+        config.resume_grace = None;
+        config.state_file = None;
+        config.turn = None;
+        let hello = to_json(&server_hello(&config));
+        assert_eq!(hello["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(hello["maxPlayersLimit"], 16);
+        let features = hello["features"].as_array().unwrap();
+        assert!(features.contains(&json!("spectators")));
+        assert!(!features.contains(&json!("resume")));
+
+        config.resume_grace = Some(Duration::from_secs(30));
+        let hello = to_json(&server_hello(&config));
+        assert!(hello["features"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("resume")));
+    }
+
+    #[tokio::test]
+    async fn answered_pings_are_reported_as_latency() {
+        let mut config = Config::from_env();
+        config.heartbeat_interval = Duration::from_millis(50);
+        let (_app_state, addr) = serve(config).await;
+        let mut connection = Connection::open(addr).await;
+        // Reading the socket answers the ping
+        let latency = connection.recv().await;
+        assert_eq!(latency["type"], "latency");
+        assert!(latency["ms"].as_u64().unwrap() < 1000);
+    }
+
+    #[tokio::test]
+    async fn pongs_not_answering_a_ping_are_not_reported() {
+        let (_app_state, addr) = serve(Config::from_env()).await;
+        let mut connection = Connection::open(addr).await;
+        connection
+            .socket
+            .send(ClientMessage::Pong(b"unsolicited".to_vec()))
+            .await
+            .unwrap();
+        connection.send(json!({"type": "listGames"})).await;
+        assert_eq!(connection.recv().await["type"], "gameList");
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_and_close_frames_clean_up() {
+        let (app_state, addr) = serve(Config::from_env()).await;
+        let mut host = Connection::open(addr).await;
+        host.socket
+            .send(ClientMessage::Ping(b"ping".to_vec()))
+            .await
+            .unwrap();
+        let pong = tokio::time::timeout(Duration::from_secs(5), host.socket.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(pong, ClientMessage::Pong(b"ping".to_vec()));
+
+        host.send(json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4}))
+            .await;
+        assert_eq!(host.recv().await["type"], "gameCreated");
+        host.socket
+            .close(Some(tokio_tungstenite::tungstenite::protocol::CloseFrame {
+                code: close_code::NORMAL.into(),
+                reason: "Bye".into(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(host.closed().await, close_code::NORMAL);
+        assert_eq!(app_state.read_games(|games| games.len()), 0);
+        assert!(!is_registered(&app_state, &host));
+    }
+
+    #[test]
+    fn private_games_are_unlisted_but_joinable_by_id() {
+        let mut lobby = Lobby::new();
+        lobby.create_game("public", json!({"serverName": "Public"}));
+        let private = lobby.create_game(
+            "private",
+            json!({"serverName": "Private", "private": true, "requiresPassword": false}),
+        );
+        assert_eq!(listed_names(&list(&mut lobby, json!({}))), ["Public"]);
+        assert!(listed_names(&list(&mut lobby, json!({"nameFilter": "private"}))).is_empty());
+        lobby.join_accepted("friend", &private);
+        assert!(lobby
+            .games
+            .get_game(&private)
+            .unwrap()
+            .clients
+            .contains(&socket("friend")));
+    }
+
+    #[test]
+    fn hosts_cant_join_their_own_game() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"joinPolicy": "open"}));
+        let joined = lobby.send("host", json!({"type": "joinGame", "gameId": game_id}));
+        assert_eq!(joined.reply()["code"], "cannotJoinOwnGame");
+        let game = lobby.games.get_game(&game_id).unwrap();
+        assert!(game.clients.is_empty());
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["playerAmount"], 1);
+    }
+
+    #[test]
+    fn clients_are_in_one_game_at_a_time() {
+        let mut lobby = Lobby::new();
+        let first = lobby.create_game("host1", json!({"joinPolicy": "open"}));
+        let second = lobby.create_game("host2", json!({"joinPolicy": "open"}));
+        let third = lobby.create_game("host3", json!({}));
+        lobby.send("client", json!({"type": "joinGame", "gameId": first}));
+        for message in [
+            json!({"type": "joinGame", "gameId": second}),
+            json!({"type": "joinAsSpectator", "gameId": second}),
+        ] {
+            assert_eq!(
+                lobby.send("client", message).reply()["code"],
+                "alreadyInGame"
+            );
         }
-        IncomingMessage::UpdateGameInfo {
-            max_players,
-            player_amount,
-            server_name,
-            requires_password,
-        } => {
-            if games.update_info(
-                socket_id,
-                GameInfo {
-                    max_players,
-                    player_amount,
-                    server_name,
-                    requires_password: requires_password.unwrap_or(false),
-                },
-            ) {
-                MessagesToSend::none()
-            } else {
-                MessagesToSend::self_(OutgoingMessage::Error {
-                    reason: "You're not a game host".to_string(),
-                })
+        // Pending joins count as membership too
+        lobby.send("pending", json!({"type": "joinGame", "gameId": third}));
+        let joined = lobby.send("pending", json!({"type": "joinGame", "gameId": first}));
+        assert_eq!(joined.reply()["code"], "alreadyInGame");
+        // Hosts can't join games either
+        let joined = lobby.send("host1", json!({"type": "joinGame", "gameId": second}));
+        assert_eq!(joined.reply()["code"], "alreadyInGame");
+        // Neither can members host a game of their own
+        lobby.send(
+            "spectator",
+            json!({"type": "joinAsSpectator", "gameId": second}),
+        );
+        for member in ["client", "pending", "spectator"] {
+            let created = lobby.send(
+                member,
+                json!({"type": "createGame", "serverName": member, "maxPlayers": 4}),
+            );
+            assert_eq!(created.reply()["code"], "alreadyInGame");
+            assert!(lobby.games.get_game_by_host(&socket(member)).is_none());
+        }
+        assert!(!lobby
+            .games
+            .get_game(&second)
+            .unwrap()
+            .clients
+            .contains(&socket("client")));
+    }
+
+    #[test]
+    fn games_are_looked_up_by_id_and_keep_creation_order() {
+        let mut lobby = Lobby::new();
+        let game_ids: Vec<GameId> = ["first", "second", "third"]
+            .into_iter()
+            .map(|name| lobby.create_game(name, json!({"serverName": name})))
+            .collect();
+        for (game_id, host) in game_ids.iter().zip(["first", "second", "third"]) {
+            assert_eq!(lobby.games.get_game(game_id).unwrap().host, socket(host));
+            assert_eq!(
+                lobby.games.get_game_by_host(&socket(host)).unwrap().game_id,
+                *game_id
+            );
+        }
+        lobby.games.remove(&game_ids[1]);
+        assert!(lobby.games.get_game(&game_ids[1]).is_none());
+        assert!(lobby.games.get_game_by_host(&socket("second")).is_none());
+        lobby.create_game("fourth", json!({"serverName": "fourth"}));
+        assert_eq!(
+            listed_names(&list(&mut lobby, json!({}))),
+            ["first", "third", "fourth"]
+        );
+    }
+
+    // The indexes as rebuilt from scratch must match the maintained ones
+    fn assert_indexes_in_sync(games: &Games) {
+        let mut hosts = HashMap::new();
+        let mut members = HashMap::new();
+        for game in games.iter() {
+            hosts.insert(game.host.clone(), game.game_id.clone());
+            for member in game
+                .clients
+                .iter()
+                .chain(&game.pending)
+                .chain(&game.spectators)
+            {
+                members.insert(member.clone(), game.game_id.clone());
             }
         }
-        IncomingMessage::ListGames => MessagesToSend::self_(OutgoingMessage::GameList {
-            games: games.list(),
-        }),
-        IncomingMessage::JoinGame { game_id, password } => {
-            match games.join_game(&game_id, socket_id) {
-                Err(err) => MessagesToSend::self_(OutgoingMessage::Error {
-                    reason: match err {
-                        JoinGameError::GameNotFound => "Game not found".to_string(),
-                        JoinGameError::AlreadyJoined => "Already joined".to_string(),
-                    },
-                }),
-                Ok(host) => MessagesToSend::other(
-                    host,
-                    OutgoingMessage::NewClient {
-                        game_id,
-                        client_id: socket_id.clone(),
-                        password,
-                    },
+        assert_eq!(games.hosts, hosts);
+        assert_eq!(games.members, members);
+    }
+
+    #[test]
+    fn indexes_stay_in_sync_with_the_games() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(307);
+        let mut lobby = Lobby::new();
+        let sockets: Vec<String> = (0..8).map(|index| format!("socket{}", index)).collect();
+        for _ in 0..2000 {
+            let actor = &sockets[rng.gen_range(0..sockets.len())];
+            let other = &sockets[rng.gen_range(0..sockets.len())];
+            let game_id = lobby
+                .games
+                .iter()
+                .nth(rng.gen_range(0..lobby.games.len().max(1)))
+                .map(|game| game.game_id.clone());
+            match (rng.gen_range(0..9), game_id) {
+                (0, _) => lobby.send(
+                    actor,
+                    json!({"type": "createGame", "serverName": "Game", "maxPlayers": 4, "migratable": rng.gen_bool(0.5)}),
                 ),
-            }
+                (1, Some(game_id)) => lobby.send(actor, json!({"type": "joinGame", "gameId": game_id})),
+                (2, Some(game_id)) => {
+                    lobby.send(actor, json!({"type": "joinAsSpectator", "gameId": game_id}))
+                }
+                (3, Some(game_id)) => lobby.send(
+                    actor,
+                    json!({"type": "acceptJoin", "gameId": game_id, "clientId": other}),
+                ),
+                (4, Some(game_id)) => lobby.send(
+                    actor,
+                    json!({"type": "rejectJoin", "gameId": game_id, "clientId": other, "reason": "no"}),
+                ),
+                (5, Some(game_id)) => lobby.send(
+                    actor,
+                    json!({"type": "kickPlayer", "gameId": game_id, "clientId": other}),
+                ),
+                (6, Some(game_id)) => lobby.send(actor, json!({"type": "leaveGame", "gameId": game_id})),
+                (7, _) => lobby.disconnect(actor),
+                _ => Sent(MessagesToSend::none()),
+            };
+            assert_indexes_in_sync(&lobby.games);
         }
-        IncomingMessage::AcceptJoin {
-            game_id,
-            client_id: accepted_socket_id,
-        } => MessagesToSend::other(accepted_socket_id, OutgoingMessage::AcceptJoin { game_id }),
-        IncomingMessage::RejectJoin {
-            game_id,
-            client_id: rejected_socket_id,
-            reason,
-        } => {
-            games.remove_client(&rejected_socket_id);
-            MessagesToSend::other(
-                rejected_socket_id,
-                OutgoingMessage::RejectJoin { game_id, reason },
-            )
+    }
+
+    #[test]
+    fn readers_of_the_games_dont_wait_for_each_other() {
+        let app_state = AppState::new(Config::from_env());
+        app_state.read_games(|_| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let other = app_state.clone();
+            std::thread::spawn(move || tx.send(other.read_games(|games| games.len())).unwrap());
+            // An exclusive lock would keep the other reader waiting until
+            // this one is done
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(0));
+        });
+    }
+
+    #[test]
+    fn sockets_buffer_up_to_the_configured_capacity() {
+        let mut sockets = Sockets::new();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (socket_id, mut rx, _) = sockets.register(&IdFormat::default(), 3, addr);
+        let tx = sockets.get(&socket_id).unwrap();
+        for _ in 0..3 {
+            tx.try_send(OutgoingMessage::NoMatch).unwrap();
         }
+        assert!(matches!(
+            tx.try_send(OutgoingMessage::NoMatch),
+            Err(TrySendError::Full(_))
+        ));
+        rx.try_recv().unwrap();
+        tx.try_send(OutgoingMessage::NoMatch).unwrap();
     }
-}
 
-fn process_disconnect(socket_id: &SocketId, games: &mut Games) {
-    if games.remove_game(socket_id) {
-        return;
+    fn received(rx: &mut mpsc::Receiver<OutgoingMessage>) -> Vec<Value> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|message| to_json(&message))
+            .collect()
     }
-    games.remove_client(socket_id);
-}
 
-fn random_string() -> String {
-    Alphanumeric.sample_string(&mut thread_rng(), 16)
-}
+    #[test]
+    fn full_sockets_drop_updates_and_are_disconnected_for_the_rest() {
+        let app_state = AppState::new(Config::from_env());
+        let (socket_id, mut rx) = register(&app_state);
+        let removed = || OutgoingMessage::GameRemoved {
+            game_id: GameId("game".to_string()),
+        };
+        // The helper registers with a buffer of 10
+        for _ in 0..10 {
+            app_state.send_to(&socket_id, removed());
+        }
+        app_state.send_to(&socket_id, removed());
+        assert!(app_state.lock_sockets(|sockets| sockets.get(&socket_id).is_some()));
+        assert_eq!(received(&mut rx).len(), 10);
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
-struct SocketId(String);
+        for _ in 0..10 {
+            app_state.send_to(&socket_id, removed());
+        }
+        app_state.send_to(&socket_id, OutgoingMessage::NoMatch);
+        assert!(app_state.lock_sockets(|sockets| sockets.get(&socket_id).is_none()));
+    }
 
-impl SocketId {
-    fn random() -> Self {
-        Self(random_string())
+    #[test]
+    fn listings_carry_the_age_of_games() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["ageSeconds"], 0);
+        lobby.games.games_mut()[&game_id].created_at -= Duration::from_secs(300);
+        assert_eq!(list(&mut lobby, json!({}))["games"][0]["ageSeconds"], 300);
     }
-}
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
-struct GameId(String); // String for backwards compat
+    #[tokio::test]
+    async fn games_left_idle_are_closed() {
+        let app_state = AppState::new(Config::from_env());
+        let (active_host, _active_rx) = register(&app_state);
+        let (idle_host, mut idle_rx) = register(&app_state);
+        let (client, mut client_rx) = register(&app_state);
+        let create = json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4, "joinPolicy": "open"});
+        let active = process(&app_state, &active_host, create.clone());
+        let active = to_json(active.self_message.as_ref().unwrap())["gameId"].clone();
+        let idle = process(&app_state, &idle_host, create);
+        let idle = to_json(idle.self_message.as_ref().unwrap())["gameId"].clone();
+        process(
+            &app_state,
+            &client,
+            json!({"type": "joinGame", "gameId": idle}),
+        );
 
-impl GameId {
-    fn random() -> Self {
-        Self(random_string())
+        let sweeper = tokio::spawn(close_stale_games(
+            app_state.clone(),
+            Duration::from_millis(100),
+        ));
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            process(&app_state, &active_host, json!({"type": "listGames"}));
+        }
+        sweeper.abort();
+
+        let game_ids: Vec<Value> =
+            app_state.read_games(|games| games.iter().map(|game| json!(game.game_id)).collect());
+        assert_eq!(game_ids, [active]);
+        for rx in [&mut idle_rx, &mut client_rx] {
+            let closed = received(rx).pop().unwrap();
+            assert_eq!(closed["type"], "gameClosed");
+            assert_eq!(closed["reason"], "idle");
+        }
     }
-}
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type", rename_all = "camelCase")]
-enum IncomingMessage {
-    #[serde(rename_all = "camelCase")]
-    WebrtcSignaling {
-        client_id: Option<SocketId>,
-        description: Option<serde_json::Value>,
-        candidate: Option<serde_json::Value>,
-    },
-    #[serde(rename_all = "camelCase")]
-    CreateGame {
-        server_name: String,
-        max_players: u32,
-        game_id: Option<GameId>,
-        requires_password: Option<bool>,
-    },
-    #[serde(rename_all = "camelCase")]
-    UpdateGameInfo {
-        server_name: String,
-        player_amount: u32,
-        max_players: u32,
-        requires_password: Option<bool>,
-    },
-    ListGames,
-    #[serde(rename_all = "camelCase")]
-    JoinGame {
-        game_id: GameId,
-        password: Option<String>,
-    },
-    #[serde(rename_all = "camelCase")]
-    AcceptJoin {
-        game_id: GameId,
-        client_id: SocketId,
-    },
-    #[serde(rename_all = "camelCase")]
-    RejectJoin {
-        game_id: GameId,
-        client_id: SocketId,
-        reason: String,
-    },
-}
 
-#[derive(Debug, Serialize)]
-#[serde(tag = "type", rename_all = "camelCase")]
-enum OutgoingMessage {
-    #[serde(rename_all = "camelCase")]
-    Error { reason: String },
+    #[test]
+    fn hosts_are_told_when_a_client_disconnects() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("client", &game_id);
+        let disconnected = lobby.disconnect("client");
+        let client_left = &disconnected.to("host")[0];
+        assert_eq!(client_left["type"], "clientLeft");
+        assert_eq!(client_left["gameId"], json!(game_id));
+        assert_eq!(client_left["clientId"], "client");
+        assert!(lobby.games.get_game(&game_id).unwrap().clients.is_empty());
+    }
 
-    #[serde(rename_all = "camelCase")]
-    WebrtcSignaling {
-        game_id: GameId,
-        client_id: Option<SocketId>,
-        description: Option<serde_json::Value>,
-        candidate: Option<serde_json::Value>,
-    },
+    #[test]
+    fn mesh_clients_are_introduced_to_each_other() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({"mesh": true}));
+        lobby.join_accepted("a", &game_id);
+        lobby.send("b", json!({"type": "joinGame", "gameId": game_id}));
+        let accepted = lobby.send(
+            "host",
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": "b"}),
+        );
+        let to_b = accepted.to("b");
+        assert_eq!(to_b[0]["type"], "acceptJoin");
+        assert_eq!(to_b[1]["type"], "meshPeers");
+        assert_eq!(to_b[1]["peers"], json!(["a"]));
+        assert_eq!(
+            accepted.to("a"),
+            [json!({"type": "peerJoined", "gameId": game_id, "clientId": "b"})]
+        );
 
-    #[serde(rename_all = "camelCase")]
-    GameCreated { game_id: GameId },
+        // Without mesh clients only talk to the host
+        let star = lobby.create_game("other", json!({}));
+        lobby.join_accepted("c", &star);
+        lobby.send("d", json!({"type": "joinGame", "gameId": star}));
+        let accepted = lobby.send(
+            "other",
+            json!({"type": "acceptJoin", "gameId": star, "clientId": "d"}),
+        );
+        assert!(accepted.to("c").is_empty());
+        assert_eq!(accepted.to("d").len(), 1);
+        assert!(signal(&mut lobby, "c", "d").to("d").is_empty());
+    }
 
-    #[serde(rename_all = "camelCase")]
-    GameList { games: Vec<OutgoingGameInfo> },
+    #[test]
+    fn co_hosts_manage_the_game_with_the_host() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("co", &game_id);
+        lobby.join_accepted("player", &game_id);
+        let promote =
+            |client: &str| json!({"type": "addCoHost", "gameId": game_id, "clientId": client});
+        assert_eq!(
+            lobby.send("player", promote("co")).reply()["code"],
+            "notHost"
+        );
+        assert_eq!(
+            lobby.send("host", promote("outsider")).reply()["code"],
+            "clientNotInGame"
+        );
+        let promoted = lobby.send("host", promote("co"));
+        assert_eq!(promoted.to("co")[0]["type"], "becameCoHost");
 
-    #[serde(rename_all = "camelCase")]
-    NewClient {
-        game_id: GameId,
-        client_id: SocketId,
-        password: Option<String>,
-    },
+        lobby.send("joiner", json!({"type": "joinGame", "gameId": game_id}));
+        let accepted = lobby.send(
+            "co",
+            json!({"type": "acceptJoin", "gameId": game_id, "clientId": "joiner"}),
+        );
+        assert_eq!(accepted.to("joiner")[0]["type"], "acceptJoin");
+        lobby.send(
+            "co",
+            json!({"type": "kickPlayer", "gameId": game_id, "clientId": "player"}),
+        );
+        lobby.send(
+            "co",
+            json!({"type": "updateGameInfo", "serverName": "Renamed", "maxPlayers": 4}),
+        );
+        let game = lobby.games.get_game(&game_id).unwrap();
+        assert!(!game.clients.contains(&socket("player")));
+        assert_eq!(game.game_info.server_name, "Renamed");
 
-    #[serde(rename_all = "camelCase")]
-    AcceptJoin { game_id: GameId },
+        lobby.disconnect("co");
+        let game = lobby.games.get_game(&game_id).unwrap();
+        assert!(game.co_hosts.is_empty());
+        assert_eq!(game.host, socket("host"));
+    }
 
-    #[serde(rename_all = "camelCase")]
-    RejectJoin { game_id: GameId, reason: String },
-}
+    #[test]
+    fn metadata_is_listed_as_given_within_the_caps() {
+        let mut lobby = Lobby::new();
+        let metadata = json!({"map": "Saimaa", "mode": "ctf"});
+        lobby.create_game("host", json!({"metadata": metadata}));
+        assert_eq!(
+            list(&mut lobby, json!({}))["games"][0]["metadata"],
+            metadata
+        );
+        lobby.send(
+            "host",
+            json!({"type": "updateGameInfo", "serverName": "Test game", "maxPlayers": 4, "metadata": {"map": "Päijänne"}}),
+        );
+        assert_eq!(
+            list(&mut lobby, json!({}))["games"][0]["metadata"],
+            json!({"map": "Päijänne"})
+        );
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct OutgoingGameInfo {
-    game_id: GameId,
-    server_name: String,
-    player_amount: u32,
-    max_players: u32,
-    requires_password: bool,
+        let too_many: serde_json::Map<String, Value> = (0..=MAX_METADATA_KEYS)
+            .map(|index| (format!("key{}", index), json!("value")))
+            .collect();
+        let too_large = json!({"map": "x".repeat(MAX_METADATA_BYTES)});
+        for metadata in [Value::Object(too_many), too_large] {
+            let create = json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4, "metadata": metadata});
+            assert_eq!(
+                lobby.send("other", create).reply()["code"],
+                "invalidMetadata"
+            );
+        }
+    }
+
+    #[test]
+    fn single_games_are_fetched_by_id_even_when_private() {
+        let mut lobby = Lobby::new();
+        let public = lobby.create_game("host", json!({"serverName": "Public"}));
+        let private =
+            lobby.create_game("friend", json!({"serverName": "Private", "private": true}));
+        for (game_id, server_name) in [(&public, "Public"), (&private, "Private")] {
+            let reply = lobby
+                .send("invited", json!({"type": "getGame", "gameId": game_id}))
+                .reply();
+            assert_eq!(reply["type"], "gameInfo");
+            assert_eq!(reply["game"]["gameId"], json!(game_id));
+            assert_eq!(reply["game"]["serverName"], server_name);
+        }
+        let missing = lobby.send("invited", json!({"type": "getGame", "gameId": "missing"}));
+        assert_eq!(missing.reply()["code"], "gameNotFound");
+    }
+
+    #[test]
+    fn hosts_can_list_their_clients() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("a", &game_id);
+        lobby.join_accepted("b", &game_id);
+        let list_clients = json!({"type": "listClients", "gameId": game_id});
+        let reply = lobby.send("host", list_clients.clone()).reply();
+        assert_eq!(reply["type"], "clientList");
+        let mut clients: Vec<&str> = reply["clients"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|client| client["clientId"].as_str().unwrap())
+            .collect();
+        clients.sort();
+        assert_eq!(clients, ["a", "b"]);
+        assert_eq!(lobby.send("a", list_clients).reply()["code"], "notHost");
+    }
+
+    #[tokio::test]
+    async fn display_names_follow_the_socket_until_it_disconnects() {
+        let (app_state, addr) = serve(Config::from_env()).await;
+        let mut host = Connection::open(addr).await;
+        let mut client = Connection::open(addr).await;
+        host.send(json!({"type": "createGame", "serverName": "Test game", "maxPlayers": 4, "joinPolicy": "open"}))
+            .await;
+        let game_id = host.recv().await["gameId"].clone();
+
+        client
+            .send(json!({"type": "setName", "name": "\u{7}"}))
+            .await;
+        assert_eq!(client.recv().await["code"], "invalidName");
+        client
+            .send(json!({"type": "setName", "name": " Matti "}))
+            .await;
+        client
+            .send(json!({"type": "joinGame", "gameId": game_id}))
+            .await;
+        let new_client = host.recv().await;
+        assert_eq!(new_client["type"], "newClient");
+        assert_eq!(new_client["name"], "Matti");
+        assert_eq!(client.recv().await["type"], "acceptJoin");
+        client
+            .send(json!({"type": "chatMessage", "gameId": game_id, "text": "Moi"}))
+            .await;
+        let chat = host.recv().await;
+        assert_eq!(chat["type"], "chatMessage");
+        assert_eq!(chat["senderName"], "Matti");
+
+        client.socket.close(None).await.unwrap();
+        client.closed().await;
+        assert!(app_state.read_games(|games| games.names.is_empty()));
+    }
+
+    #[test]
+    fn listings_show_the_host_name_when_set() {
+        let mut lobby = Lobby::new();
+        lobby.send("named", json!({"type": "setName", "name": "Maija"}));
+        lobby.create_game("named", json!({"serverName": "Named"}));
+        lobby.create_game("anonymous", json!({"serverName": "Anonymous"}));
+        let listed = list(&mut lobby, json!({}));
+        assert_eq!(listed["games"][0]["hostName"], "Maija");
+        assert_eq!(listed["games"][1]["hostName"], Value::Null);
+        // Names set after creating the game show up too
+        lobby.send("anonymous", json!({"type": "setName", "name": "Pekka"}));
+        let listed = list(&mut lobby, json!({"nameFilter": "anon"}));
+        assert_eq!(listed["games"][0]["hostName"], "Pekka");
+        // Names are part of the cached list, which only renaming a host rebuilds
+        let snapshot = lobby.games.snapshot();
+        lobby.send("client", json!({"type": "setName", "name": "Liisa"}));
+        assert!(Arc::ptr_eq(&snapshot, &lobby.games.snapshot()));
+        let (listed, _) = lobby.games.list(&ListQuery::default());
+        assert!(Arc::ptr_eq(&snapshot, &listed));
+        lobby.send("named", json!({"type": "setName", "name": "Maija-Liisa"}));
+        assert_eq!(
+            lobby.games.snapshot()[0].host_name.as_deref(),
+            Some("Maija-Liisa")
+        );
+        lobby.disconnect("anonymous");
+        lobby.create_game("anonymous", json!({"serverName": "Anonymous"}));
+        assert_eq!(lobby.games.snapshot()[1].host_name, None);
+    }
+
+    #[test]
+    fn host_broadcasts_reach_every_client() {
+        let mut lobby = Lobby::new();
+        let game_id = lobby.create_game("host", json!({}));
+        lobby.join_accepted("a", &game_id);
+        lobby.join_accepted("b", &game_id);
+        lobby.send("pending", json!({"type": "joinGame", "gameId": game_id}));
+        let payload = json!({"countdown": 10, "rules": ["no rush"]});
+        let broadcast =
+            |payload: &Value| json!({"type": "broadcast", "gameId": game_id, "payload": payload});
+        let sent = lobby.send("host", broadcast(&payload));
+        for client in ["a", "b"] {
+            assert_eq!(
+                sent.to(client),
+                [json!({"type": "hostBroadcast", "gameId": game_id, "payload": payload})]
+            );
+        }
+        assert!(sent.to("pending").is_empty());
+
+        assert_eq!(
+            lobby.send("a", broadcast(&payload)).reply()["code"],
+            "notHost"
+        );
+        let too_large = json!("x".repeat(MAX_BROADCAST_BYTES));
+        assert_eq!(
+            lobby.send("host", broadcast(&too_large)).reply()["code"],
+            "payloadTooLarge"
+        );
+    }
 }
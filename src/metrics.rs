@@ -0,0 +1,108 @@
+use crate::{AppState, INCOMING_MESSAGE_TYPES};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Counters updated while processing messages. Gauges are read from the
+// current state when scraped.
+pub struct Metrics {
+    // Indexed like INCOMING_MESSAGE_TYPES
+    messages: Vec<AtomicU64>,
+    joins_succeeded: AtomicU64,
+    joins_failed: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            messages: INCOMING_MESSAGE_TYPES
+                .iter()
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            joins_succeeded: AtomicU64::new(0),
+            joins_failed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_message(&self, message_type: &str) {
+        if let Some(index) = INCOMING_MESSAGE_TYPES
+            .iter()
+            .position(|known| *known == message_type)
+        {
+            self.messages[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_join(&self, succeeded: bool) {
+        let counter = if succeeded {
+            &self.joins_succeeded
+        } else {
+            &self.joins_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Processed messages by type, in the order of INCOMING_MESSAGE_TYPES
+    pub fn message_counts(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        INCOMING_MESSAGE_TYPES.iter().copied().zip(
+            self.messages
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed)),
+        )
+    }
+
+    pub fn joins_succeeded(&self) -> u64 {
+        self.joins_succeeded.load(Ordering::Relaxed)
+    }
+
+    pub fn joins_failed(&self) -> u64 {
+        self.joins_failed.load(Ordering::Relaxed)
+    }
+}
+
+// Prometheus text exposition format
+pub async fn metrics(State(app_state): State<AppState>) -> impl IntoResponse {
+    let games = app_state.read_games(|games| games.len());
+    let sockets = app_state.lock_sockets(|sockets| sockets.len());
+    let metrics = &app_state.metrics;
+
+    let mut body = String::new();
+    writeln!(body, "# HELP lobby_games Active games").unwrap();
+    writeln!(body, "# TYPE lobby_games gauge").unwrap();
+    writeln!(body, "lobby_games {}", games).unwrap();
+    writeln!(body, "# HELP lobby_sockets Connected sockets").unwrap();
+    writeln!(body, "# TYPE lobby_sockets gauge").unwrap();
+    writeln!(body, "lobby_sockets {}", sockets).unwrap();
+    writeln!(
+        body,
+        "# HELP lobby_messages_total Processed messages by type"
+    )
+    .unwrap();
+    writeln!(body, "# TYPE lobby_messages_total counter").unwrap();
+    for (message_type, count) in metrics.message_counts() {
+        writeln!(
+            body,
+            "lobby_messages_total{{type=\"{}\"}} {}",
+            message_type, count
+        )
+        .unwrap();
+    }
+    writeln!(body, "# HELP lobby_joins_total Game joins by outcome").unwrap();
+    writeln!(body, "# TYPE lobby_joins_total counter").unwrap();
+    writeln!(
+        body,
+        "lobby_joins_total{{outcome=\"success\"}} {}",
+        metrics.joins_succeeded()
+    )
+    .unwrap();
+    writeln!(
+        body,
+        "lobby_joins_total{{outcome=\"failure\"}} {}",
+        metrics.joins_failed()
+    )
+    .unwrap();
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
@@ -0,0 +1,243 @@
+use crate::{
+    expire_session, AppState, Game, GameId, GameInfo, GameStatus, InviteToken, ResumeToken,
+    SocketId,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::error;
+
+// What survives a restart: the games and the resume tokens their members need
+// to reclaim them. Pending joins and game history are dropped.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedState {
+    games: Vec<SavedGame>,
+    sessions: Vec<SavedSession>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SavedGame {
+    game_id: GameId,
+    host: SocketId,
+    clients: Vec<SocketId>,
+    spectators: Vec<SocketId>,
+    #[serde(default)]
+    co_hosts: Vec<SocketId>,
+    reservations: Vec<InviteToken>,
+    game_info: GameInfo,
+    event_seq: u64,
+    status: GameStatus,
+    // Missing from files saved by older versions
+    #[serde(default = "SystemTime::now")]
+    created_at: SystemTime,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SavedSession {
+    socket_id: SocketId,
+    token: ResumeToken,
+}
+
+impl SavedState {
+    pub fn collect(app_state: &AppState) -> Self {
+        let games: Vec<SavedGame> = app_state.read_games(|games| {
+            games
+                .iter()
+                .map(|game| SavedGame {
+                    game_id: game.game_id.clone(),
+                    host: game.host.clone(),
+                    clients: game.clients.iter().cloned().collect(),
+                    spectators: game.spectators.iter().cloned().collect(),
+                    co_hosts: game.co_hosts.iter().cloned().collect(),
+                    // Pending joiners aren't saved, so their invites are
+                    // reserved again
+                    reservations: game
+                        .reservations
+                        .iter()
+                        .chain(game.pending_invites.values())
+                        .cloned()
+                        .collect(),
+                    game_info: game.game_info.clone(),
+                    event_seq: game.event_seq,
+                    status: game.status,
+                    created_at: game.created_at,
+                })
+                .collect()
+        });
+        let sessions = app_state.lock_sessions(|sessions| {
+            games
+                .iter()
+                .flat_map(|game| {
+                    std::iter::once(&game.host)
+                        .chain(&game.clients)
+                        .chain(&game.spectators)
+                })
+                .filter_map(|socket_id| {
+                    sessions.token(socket_id).map(|token| SavedSession {
+                        socket_id: socket_id.clone(),
+                        token: token.clone(),
+                    })
+                })
+                .collect()
+        });
+        Self { games, sessions }
+    }
+
+    // The restored sockets count as disconnected from now on, so their games
+    // are cleaned up unless they resume within the grace period
+    pub fn restore(self, app_state: &AppState) {
+        app_state.write_games(|games| {
+            for game in self.games {
+                games.add(Game {
+                    game_id: game.game_id,
+                    host: game.host,
+                    clients: game.clients.into_iter().collect(),
+                    pending: HashSet::new(),
+                    spectators: game.spectators.into_iter().collect(),
+                    co_hosts: game.co_hosts.into_iter().collect(),
+                    reservations: game.reservations.into_iter().collect(),
+                    pending_invites: HashMap::new(),
+                    game_info: game.game_info,
+                    event_seq: game.event_seq,
+                    created_at: game.created_at,
+                    last_activity: Mutex::new(Instant::now()),
+                    history: VecDeque::new(),
+                    liveness_check: None,
+                    ready: HashSet::new(),
+                    suspended: HashSet::new(),
+                    status: game.status,
+                });
+            }
+        });
+        for session in self.sessions {
+            let disconnected_at = app_state
+                .lock_sessions(|sessions| sessions.restore(&session.socket_id, session.token));
+            app_state.write_games(|games| games.set_suspended(&session.socket_id, true));
+            tokio::spawn(expire_session(
+                app_state.clone(),
+                session.socket_id,
+                disconnected_at,
+            ));
+        }
+    }
+}
+
+// Returns None if there's no saved state yet
+pub fn load(path: &Path) -> Option<SavedState> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(err) => panic!("Failed to read {}: {}", path.display(), err),
+    };
+    Some(
+        serde_json::from_slice(&data)
+            .unwrap_or_else(|err| panic!("Invalid state file {}: {}", path.display(), err)),
+    )
+}
+
+// Writes to a temporary file first so a crash mid-write can't corrupt the
+// previous state
+pub fn save(app_state: &AppState, path: &Path) {
+    let data = serde_json::to_vec(&SavedState::collect(app_state)).unwrap();
+    let tmp_path = path.with_extension("tmp");
+    let result = std::fs::write(&tmp_path, data).and_then(|()| std::fs::rename(&tmp_path, path));
+    if let Err(err) = result {
+        error!(path = %path.display(), %err, "Failed to save state");
+    }
+}
+
+pub async fn save_periodically(app_state: AppState, path: &Path, interval: Duration) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        interval.tick().await;
+        save(&app_state, path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::process;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    // A state file of its own for each test
+    fn state_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lobby-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn config(state_file: &Path, resume_grace: Duration) -> Config {
+        let mut config = Config::from_env();
+        config.state_file = Some(state_file.to_path_buf());
+        config.resume_grace = Some(resume_grace);
+        config
+    }
+
+    // Saves a game hosted by "host" with "client" in it, both with sessions
+    fn save_game(path: &Path, resume_grace: Duration) -> (ResumeToken, ResumeToken) {
+        let app_state = AppState::new(config(path, resume_grace));
+        let host = SocketId("host".to_string());
+        let client = SocketId("client".to_string());
+        process(
+            &app_state,
+            &host,
+            json!({"type": "createGame", "gameId": "saved", "serverName": "Saved", "maxPlayers": 4, "joinPolicy": "open"}),
+        );
+        process(
+            &app_state,
+            &client,
+            json!({"type": "joinGame", "gameId": "saved"}),
+        );
+        let tokens =
+            app_state.lock_sessions(|sessions| (sessions.issue(&host), sessions.issue(&client)));
+        save(&app_state, path);
+        tokens
+    }
+
+    #[tokio::test]
+    async fn saved_games_are_restored_with_their_sessions() {
+        let path = state_file("restored");
+        let (host_token, client_token) = save_game(&path, Duration::from_secs(60));
+
+        let app_state = AppState::new(config(&path, Duration::from_secs(60)));
+        let host = SocketId("host".to_string());
+        let client = SocketId("client".to_string());
+        app_state.read_games(|games| {
+            let game = games.get_game(&GameId("saved".to_string())).unwrap();
+            assert_eq!(game.host, host);
+            assert!(game.clients.contains(&client));
+            assert_eq!(game.game_info.server_name, "Saved");
+        });
+        app_state.lock_sessions(|sessions| {
+            assert_eq!(sessions.token(&host), Some(&host_token));
+            assert_eq!(sessions.token(&client), Some(&client_token));
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restored_games_are_closed_unless_the_host_resumes() {
+        let path = state_file("expired");
+        save_game(&path, Duration::from_millis(50));
+
+        let app_state = AppState::new(config(&path, Duration::from_millis(50)));
+        let game_id = GameId("saved".to_string());
+        assert!(app_state.read_games(|games| games.get_game(&game_id).is_some()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(app_state.read_games(|games| games.get_game(&game_id).is_none()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn nothing_is_restored_without_a_state_file() {
+        assert!(load(&state_file("missing")).is_none());
+    }
+}
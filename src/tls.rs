@@ -0,0 +1,150 @@
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+// Builds a rustls config from PEM files. When `client_ca` is given, clients
+// must present a certificate signed by it.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> ServerConfig {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca) {
+                roots.add(&cert).unwrap_or_else(|err| {
+                    panic!("Invalid CA certificate in {}: {}", client_ca.display(), err)
+                });
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(load_certs(cert), load_key(key))
+        .unwrap_or_else(|err| panic!("Invalid TLS certificate or key: {}", err));
+    // WebSocket upgrades need HTTP/1.1
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    config
+}
+
+fn read_pem(path: &Path) -> Vec<Item> {
+    let file =
+        File::open(path).unwrap_or_else(|err| panic!("Failed to open {}: {}", path.display(), err));
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err))
+}
+
+fn load_certs(path: &Path) -> Vec<Certificate> {
+    let certs: Vec<Certificate> = read_pem(path)
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        panic!("No certificates found in {}", path.display());
+    }
+    certs
+}
+
+fn load_key(path: &Path) -> PrivateKey {
+    read_pem(path)
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .unwrap_or_else(|| panic!("No private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, ClientConnection, Connection, ServerConnection};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    // A test CA with a server and a client certificate it signed, and a
+    // self-signed client certificate, all generated with openssl
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    fn transfer(from: &mut Connection, to: &mut Connection) -> Result<(), rustls::Error> {
+        let mut data = Vec::new();
+        from.write_tls(&mut data).unwrap();
+        let mut data = data.as_slice();
+        while !data.is_empty() {
+            to.read_tls(&mut data).unwrap();
+            to.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    // Runs a handshake in memory against a server config requiring client
+    // certificates signed by `client_ca` if set, with the client presenting
+    // `client_cert` and its key if set
+    fn handshake(
+        client_ca: Option<&str>,
+        client_cert: Option<(&str, &str)>,
+    ) -> Result<(), rustls::Error> {
+        let client_ca = client_ca.map(testdata);
+        let server_config = server_config(
+            &testdata("server.pem"),
+            &testdata("server-key.pem"),
+            client_ca.as_deref(),
+        );
+        let mut roots = RootCertStore::empty();
+        roots.add(&load_certs(&testdata("ca.pem"))[0]).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let client_config = match client_cert {
+            Some((cert, key)) => client_config
+                .with_client_auth_cert(load_certs(&testdata(cert)), load_key(&testdata(key)))
+                .unwrap(),
+            None => client_config.with_no_client_auth(),
+        };
+        let mut client = Connection::from(
+            ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+                .unwrap(),
+        );
+        let mut server = Connection::from(ServerConnection::new(Arc::new(server_config)).unwrap());
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server)?;
+            transfer(&mut server, &mut client)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn client_certs_signed_by_the_ca_are_accepted() {
+        assert_eq!(
+            handshake(Some("ca.pem"), Some(("client.pem", "client-key.pem"))),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn client_certs_not_signed_by_the_ca_are_rejected() {
+        assert!(matches!(
+            handshake(Some("ca.pem"), Some(("untrusted.pem", "untrusted-key.pem"))),
+            Err(rustls::Error::InvalidCertificate(_))
+        ));
+    }
+
+    #[test]
+    fn servers_without_a_client_ca_serve_any_client() {
+        assert_eq!(handshake(None, None), Ok(()));
+    }
+
+    #[test]
+    fn servers_with_a_client_ca_require_a_client_cert() {
+        assert!(handshake(Some("ca.pem"), None).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::config::TurnConfig;
+use crate::SocketId;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+}
+
+// Ephemeral credentials as in the TURN REST API: the username is the expiry
+// time and the socket id, the credential an HMAC of the username keyed with the
+// secret shared with the TURN server. Empty when TURN isn't configured.
+pub fn ice_servers(turn: Option<&TurnConfig>, socket_id: &SocketId) -> Vec<IceServer> {
+    let Some(turn) = turn else {
+        return Vec::new();
+    };
+    let expires_at = (SystemTime::now() + turn.credential_ttl)
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let username = format!("{}:{}", expires_at, socket_id.0);
+    vec![IceServer {
+        urls: turn.urls.clone(),
+        credential: credential(&turn.secret, &username),
+        username,
+    }]
+}
+
+fn credential(secret: &str, username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(username.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn turn() -> TurnConfig {
+        TurnConfig {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            secret: "secret".to_string(),
+            credential_ttl: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn credentials_are_an_hmac_of_the_username() {
+        // RFC 2202 test case 2
+        assert_eq!(
+            credential("Jefe", "what do ya want for nothing?"),
+            "7/zfauXrL6LSdBbV8YTfnCWafHk="
+        );
+    }
+
+    #[test]
+    fn ice_servers_expire_after_the_ttl() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let socket_id = SocketId("socket".to_string());
+        let ice_servers = ice_servers(Some(&turn()), &socket_id);
+        assert_eq!(ice_servers.len(), 1);
+        let ice_server = &ice_servers[0];
+        assert_eq!(ice_server.urls, turn().urls);
+        let (expires_at, user) = ice_server.username.split_once(':').unwrap();
+        assert_eq!(user, "socket");
+        let expires_in = expires_at.parse::<u64>().unwrap() - now.as_secs();
+        assert!((3599..=3601).contains(&expires_in), "{}", expires_in);
+        assert_eq!(
+            ice_server.credential,
+            credential("secret", &ice_server.username)
+        );
+    }
+
+    #[test]
+    fn no_ice_servers_without_turn() {
+        assert!(ice_servers(None, &SocketId("socket".to_string())).is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{ErrorCode, IncomingMessage, OutgoingMessage};
+use axum::extract::ws::Message;
+use serde::Serialize;
+use tracing::error;
+
+// Encoding of the messages on a connection. Browsers use JSON text frames,
+// native clients may use MessagePack binary frames instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    MessagePack,
+}
+
+impl WireFormat {
+    pub fn decode(self, data: &[u8]) -> Result<IncomingMessage, String> {
+        match self {
+            WireFormat::Json => serde_json::from_slice(data).map_err(|err| err.to_string()),
+            WireFormat::MessagePack => rmp_serde::from_slice(data).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn encode(self, message: &OutgoingMessage) -> Message {
+        self.encode_or_error(message, message.message_type())
+    }
+
+    // Falls back to an internal error message if `message` can't be
+    // serialized
+    fn encode_or_error<T: Serialize>(self, message: &T, message_type: &str) -> Message {
+        self.try_encode(message).unwrap_or_else(|err| {
+            error!(message_type, %err, "Failed to serialize message");
+            self.try_encode(&OutgoingMessage::error(ErrorCode::InternalError))
+                .unwrap()
+        })
+    }
+
+    fn try_encode<T: Serialize>(self, message: &T) -> Result<Message, String> {
+        match self {
+            WireFormat::Json => serde_json::to_string(message)
+                .map(Message::Text)
+                .map_err(|err| err.to_string()),
+            // Named fields keep the message shape identical to the JSON one
+            WireFormat::MessagePack => rmp_serde::to_vec_named(message)
+                .map(Message::Binary)
+                .map_err(|err| err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GameId;
+    use serde::ser::{Error, Serializer};
+    use serde_json::{json, Value};
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(S::Error::custom("unserializable"))
+        }
+    }
+
+    fn decoded(message: Message) -> Value {
+        match message {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            Message::Binary(data) => rmp_serde::from_slice(&data).unwrap(),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn unserializable_messages_are_replaced_by_an_error() {
+        for format in [WireFormat::Json, WireFormat::MessagePack] {
+            let message = format.encode_or_error(&Unserializable, "unserializable");
+            assert_eq!(
+                decoded(message),
+                json!({"type": "error", "code": "internalError", "reason": ErrorCode::InternalError.reason()}),
+            );
+        }
+    }
+
+    #[test]
+    fn message_pack_keeps_the_json_shape() {
+        let message = OutgoingMessage::GameRemoved {
+            game_id: GameId("game".to_string()),
+        };
+        let binary = WireFormat::MessagePack.encode(&message);
+        assert!(matches!(binary, Message::Binary(_)));
+        assert_eq!(decoded(binary), decoded(WireFormat::Json.encode(&message)));
+    }
+
+    #[test]
+    fn message_pack_decodes_like_json() {
+        let message = json!({"type": "joinGame", "gameId": "game"});
+        let data = rmp_serde::to_vec_named(&message).unwrap();
+        let decoded = WireFormat::MessagePack.decode(&data).unwrap();
+        assert!(matches!(
+            decoded,
+            IncomingMessage::JoinGame { game_id, .. } if game_id == GameId("game".to_string())
+        ));
+        assert!(WireFormat::MessagePack
+            .decode(message.to_string().as_bytes())
+            .is_err());
+    }
+}